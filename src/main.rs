@@ -4,21 +4,143 @@ use tcod::colors::Color;
 use tcod::console::*;
 use tcod::input::Event;
 use tcod::input::Key;
+use tcod::input::KeyCode;
 use tcod::input::Mouse;
+use tcod::line::Line;
 use tcod::map::{FovAlgorithm, Map as FovMap};
 use tcod::{colors, input};
 
 extern crate rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng, StdRng};
 
 use std::cmp::max;
 use std::cmp::min;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
 
 const PLAYER: usize = 0;
+// One slot per letter
+const INVENTORY_SIZE: usize = 26;
+// How much the player can carry, going up with their attack
+const CARRY_BASE: i32 = 30;
+const CARRY_PER_ATTACK: i32 = 4;
+// Better than common equipment gets another +1 attack every few levels down
+const RARITY_DEPTH_STEP: i32 = 3;
+const KEEN_ATTACK: i32 = 2;
+const FLAME_DAMAGE: i32 = 3;
+const VAMPIRIC_HEAL: i32 = 2;
+const MAX_MANA: i32 = 20;
+const MANA_REGEN: i32 = 1;
+const FIREBALL_RANGE: i32 = 8;
+const FIREBALL_RADIUS: i32 = 2;
+const FIREBALL_DAMAGE: i32 = 12;
+// Spells are weaker than the one-shot scrolls, but can be cast again and again
+const SPELL_LIGHTNING_DAMAGE: i32 = 15;
+const SPELL_HEAL: i32 = 8;
+const BEAR_HP: i32 = 10;
+const TURTLE_DEFENSE: i32 = 2;
+const PLAYER_REGEN_RATE: u32 = 20;
+// Extra torch radius from each affix of the Sun
+const SUN_LIGHT: i32 = 3;
+// A troll weapon divides the wielder's regeneration interval by this
+const TROLL_REGEN_FACTOR: u32 = 2;
 const POTION_HEAL: i32 = 4;
 const LIGHTNING_DAMAGE: i32 = 40;
 const LIGHTNING_RANGE: i32 = 5;
 const CONFUSION_TURNS: i32 = 5;
+const HASTE_TURNS: i32 = 20;
+const SLOW_TURNS: i32 = 10;
+// Energy everyone gains each round at normal speed, and spends on each action.
+// Haste doubles the speed and slowness halves it.
+const NORMAL_SPEED: i32 = 100;
+const ACTION_COST: i32 = 100;
+// How far hasted and slowed monsters' colors move towards these
+const HASTE_COLOR: Color = colors::LIGHT_YELLOW;
+const SLOW_COLOR: Color = colors::LIGHT_BLUE;
+const SPEED_TINT: f32 = 0.5;
+const ACID_DAMAGE: i32 = 6;
+const THROW_RANGE: i32 = 6;
+// Tunnels dug by a wand always run this far, whatever tile was aimed at
+const DIG_RANGE: i32 = 8;
+const DIG_CHARGES: i32 = 3;
+// Thrown potions shatter and splash everything this close to where they land
+const SPLASH_RADIUS: i32 = 1;
+// Lightens the tiles a target can be picked from
+const TARGET_RANGE_TINT: Color = Color { r: 25, g: 25, b: 0 };
+// Necromancers raise the dead every few turns while the player can see them
+const SUMMON_COOLDOWN: i32 = 5;
+const MAX_SUMMONS: i32 = 3;
+const REANIMATE_RANGE: i32 = 3;
+// Beyond this many corpses on a level, the oldest ones crumble away
+const MAX_CORPSES: usize = 30;
+// Allies with nothing to fight stay about this close to the player
+const ALLY_FOLLOW_DISTANCE: i32 = 2;
+// Chance of any attack missing completely, and of the player dropping their
+// weapon when theirs does
+const FUMBLE_CHANCE: f32 = 0.03;
+const FUMBLE_DROP_CHANCE: f32 = 0.25;
+const SHIELD_DEFENSE: i32 = 1;
+const SHIELD_BLOCK_CHANCE: f32 = 0.15;
+// Most scrolls of enchantment one item can take
+const MAX_ENCHANTMENT: i32 = 3;
+const CHARM_RANGE: i32 = 5;
+const CHARM_TURNS: i32 = 15;
+const BLESSING_TURNS: i32 = 30;
+const CRACKED_WALL_HP: i32 = 10;
+const CRACKED_WALL_CHANCE: f32 = 0.1;
+const BARREL_HP: i32 = 3;
+const BARREL_CHANCE: f32 = 0.15;
+const BARREL_LOOT_CHANCE: f32 = 0.5;
+const BLESSING_ATTACK: i32 = 2;
+// A sneaking player goes unnoticed by unaware monsters until this close, and
+// even then only gets spotted some of the time
+const SNEAK_DETECTION_RADIUS: i32 = 3;
+const SNEAK_NOTICE_CHANCE: f32 = 0.3;
+const SNEAK_ATTACK_MULTIPLIER: i32 = 2;
+// Extra damage for hitting something from behind, see Direction::is_behind
+const BACK_ATTACK_BONUS: i32 = 2;
+// The player leaves a scent trail which fades a little every turn
+const SCENT_MAX: u16 = 100;
+const SCENT_DECAY: u16 = 4;
+// Monster spells
+const SHAMAN_HEAL: i32 = 5;
+const SHAMAN_HEAL_RANGE: i32 = 5;
+const BOLT_DAMAGE: i32 = 4;
+const BOLT_RANGE: i32 = 6;
+const WATER_SLOWDOWN: i32 = 1;
+const LAVA_DAMAGE: i32 = 3;
+const SPIKE_TRAP_DAMAGE: i32 = 6;
+const SPIKE_TRAP_REARM_TURNS: u32 = 10;
+const DART_TRAP_DAMAGE: i32 = 4;
+const DROWN_CHANCE: f32 = 0.05;
+// Until items have a weight, each one carried makes swimming this much riskier
+const DROWN_CHANCE_PER_ITEM: f32 = 0.02;
+const DROWN_DAMAGE: i32 = 4;
+// How much of the fight to remember for the death screen
+const RECENT_COMBAT_LENGTH: usize = 5;
+const RECAP_TURNS: usize = 5;
+// Share of their gold a player loses each time they die in practice mode
+const PRACTICE_GOLD_PENALTY_PERCENT: i32 = 50;
+// Kills of a monster type it takes to learn its HP, then its attack and defense
+const BESTIARY_HP_KILLS: u32 = 1;
+const BESTIARY_STATS_KILLS: u32 = 3;
+// Longest the player will rest for in one go
+const REST_TURNS: u32 = 20;
+
+// Things the player does over several turns without pressing a key for each,
+// see auto_step
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AutoAction {
+    Rest { turns_left: u32 },
+    // Walk to a known spot by way of explored, safe tiles.  With `clear` set
+    // the level has no monsters left, so the walk doesn't stop for things
+    // the player sees on the way, but is called off if anything turns up.
+    Travel { to: (i32, i32), clear: bool },
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum PlayerAction {
@@ -29,6 +151,7 @@ enum PlayerAction {
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum UseResult {
     UsedUp,
+    UsedAndKept,
     Cancelled,
 }
 
@@ -38,6 +161,10 @@ struct Fighter {
     hp: i32,
     defense: i32,
     attack: i32,
+    // Turns it takes to heal 1 HP on its own; 0 means it never does
+    regen_rate: u32,
+    // Turns since it last healed that way
+    regen_counter: u32,
     on_death: DeathCallback,
 }
 
@@ -45,6 +172,7 @@ struct Fighter {
 enum DeathCallback {
     Player,
     Monster,
+    Boss,
 }
 
 impl DeathCallback {
@@ -53,972 +181,9727 @@ impl DeathCallback {
         let callback: fn(&mut Object, &mut Game) = match self {
             Player => player_death,
             Monster => monster_death,
+            Boss => boss_death,
         };
         callback(object, game);
     }
 }
 
 fn player_death(player: &mut Object, game: &mut Game) {
-    game.messages.add("You died!", colors::RED);
+    game.messages.add_cat("You died!", colors::RED, MsgCategory::Combat);
+
+    // In practice mode the player gets straight back up, see respawn_player
+    if game.mode == GameMode::Permadeath {
+        player.char = '%';
+        player.color = colors::DARK_RED;
+    }
+}
+
+// Whether dying ends the game
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GameMode {
+    Permadeath,
+    // The player comes back at the start of the level, a little poorer
+    Practice,
+}
 
-    player.char = '%';
-    player.color = colors::DARK_RED;
+impl GameMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            GameMode::Permadeath => "Permadeath",
+            GameMode::Practice => "Practice",
+        }
+    }
 }
 
 fn monster_death(monster: &mut Object, game: &mut Game) {
-    game.messages.add(format!("{} is dead!", monster.name), colors::ORANGE);
+    if is_in_bestiary(&monster.name) {
+        *game.bestiary.entry(monster.name.clone()).or_insert(0) += 1;
+    }
+    game.messages.add_cat(
+        format!("{} is dead!", capitalized(&monster.the_name())),
+        colors::ORANGE,
+        MsgCategory::Combat,
+    );
     monster.char = '%';
     monster.color = colors::DARK_RED;
     monster.is_walkable = true;
     monster.fighter = None;
     monster.ai = None;
+    monster.status_effects.clear();
     monster.name = format!("remains of {}", monster.name);
 }
 
+// The boss goes the way of any other monster, but leaves the amulet behind
+fn boss_death(monster: &mut Object, game: &mut Game) {
+    let (x, y) = monster.pos();
+    monster_death(monster, game);
+    game.messages.add_cat(
+        "The Amulet of the Kings falls from the bones!",
+        colors::GOLD,
+        MsgCategory::Lore,
+    );
+    game.pending_spawns.push(make_item(Item::Amulet, x, y));
+}
+
 #[derive(Clone, Debug, PartialEq)]
 enum Ai {
     Basic,
     Confused { previous: Box<Ai>, turns: i32 },
+    Summoner { cooldown: i32, summons_left: i32 },
+    // phase is how many of BOSS_PHASES have started
+    Boss { phase: usize },
 }
 
+// A stage of the boss fight, which starts once the boss is down to
+// hp_percent of its health
+struct BossPhase {
+    hp_percent: i32,
+    message: &'static str,
+    // Raised skeletons called up as the phase starts
+    summons: i32,
+    attack_bonus: i32,
+    // Replaces whatever the boss could cast before
+    abilities: &'static [Ability],
+}
+
+const BOSS_PHASES: &[BossPhase] = &[
+    BossPhase {
+        hp_percent: 75,
+        message: "The Ancient King calls his guard up from their graves!",
+        summons: 3,
+        attack_bonus: 0,
+        abilities: &[Ability::Bolt],
+    },
+    BossPhase {
+        hp_percent: 40,
+        message: "The Ancient King roars, and his blade burns with cold fire!",
+        summons: 0,
+        attack_bonus: 3,
+        abilities: &[Ability::Bolt],
+    },
+    BossPhase {
+        hp_percent: 15,
+        message: "The Ancient King pours the last of his strength into his servants!",
+        summons: 2,
+        attack_bonus: 0,
+        abilities: &[Ability::HealAlly],
+    },
+];
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Item {
-    Heal,      // Potion of healing
-    Lightning, // Scroll of lightning bolt
-    Confusion, // Scroll of confusion
+    Heal,         // Potion of healing
+    Acid,         // Potion of acid
+    Lightning,    // Scroll of lightning bolt
+    Confusion,    // Scroll of confusion
+    Charm,        // Scroll of charm monster
+    Haste,        // Potion of speed
+    Slow,         // Scroll of slowness
+    Enchant,      // Scroll of enchantment, makes a weapon hit harder
+    Spear,        // Weapon which reaches past the first enemy
+    Axe,          // Weapon which cleaves the enemies either side
+    Pick,         // Weapon which digs through walls
+    Shield,       // Worn in the off hand, for defense
+    Ring,         // Worn on either hand, for its affixes
+    Pendant,      // Worn around the neck, for its affixes
+    Digging(i32), // Wand of digging, with the charges it has left
+    Gold(i32),    // Pile of gold, goes straight into the purse
+    Amulet,       // Amulet of the Kings, picking it up wins the game
 }
 
-fn normalize(delta: i32) -> i32 {
-    match delta {
-        0 => 0,
-        1.. => 1,
-        _ => -1,
-    }
+// How good a piece of loot is.  Rarer equipment hits harder and rarer wands
+// hold more charges.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Rarity {
+    Common,
+    Uncommon,
+    Rare,
+    Epic,
 }
 
-fn move_towards(id: usize, target_x: i32, target_y: i32, map: &MapSlice, objects: &mut [Object]) {
-    let dx = normalize(target_x - objects[id].x);
-    let dy = normalize(target_y - objects[id].y);
-    if move_by(id, dx, dy, map, objects) == PlayerAction::DidntTakeTurn
-        && move_by(id, dx, 0, map, objects) == PlayerAction::DidntTakeTurn
-    {
-        move_by(id, 0, dy, map, objects);
+impl Rarity {
+    pub fn color(self) -> Color {
+        match self {
+            Rarity::Common => colors::WHITE,
+            Rarity::Uncommon => colors::LIGHT_GREEN,
+            Rarity::Rare => colors::LIGHT_AZURE,
+            Rarity::Epic => colors::LIGHT_MAGENTA,
+        }
     }
-}
 
-fn attack_player(id: usize, game: &mut Game, objects: &mut [Object]) {
-    if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
-        // TODO: if objects[PLAYER].fighter.hp > 0 {
-        let (player_slice, ai_slice) = objects.split_at_mut(id);
-        ai_slice[0].attack(&mut player_slice[0], game);
+    // Goes in front of the item's name
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Rarity::Common => "",
+            Rarity::Uncommon => "fine ",
+            Rarity::Rare => "rare ",
+            Rarity::Epic => "epic ",
+        }
     }
-}
 
-fn ai_take_turn(id: usize, game: &mut Game, objects: &mut [Object]) {
-    assert_ne!(id, PLAYER);
-    match &mut objects[id].ai {
-        Some(Ai::Basic) => {
-            if objects[id].grid_distance_to(&objects[PLAYER]) > 1 {
-                let (player_x, player_y) = objects[PLAYER].pos();
-                move_towards(id, player_x, player_y, &game.map, objects);
-            } else {
-                attack_player(id, game, objects);
-            }
-        }
-        Some(Ai::Confused { previous, turns }) => {
-            if *turns > 0 {
-                *turns -= 1;
-            }
-            if *turns == 0 {
-                objects[id].ai = Some(*previous.clone());
-            }
+    pub fn bonus(self) -> i32 {
+        self as i32
+    }
 
-            let dx: i32 = (rand::random::<f32>() * 3.0) as i32 - 1;
-            let dy: i32 = (rand::random::<f32>() * 3.0) as i32 - 1;
-            println!("dx, dy = {}, {}", dx, dy);
-            let (tx, ty) = (objects[id].x + dx, objects[id].y + dy);
-            if objects[PLAYER].pos() == (tx, ty) {
-                attack_player(id, game, objects);
-            } else {
-                move_by(id, dx, dy, &game.map, objects);
-            }
-        }
-        None => {
-            println!("No AI!!!");
+    // Fewest and most affixes equipment of this rarity rolls
+    pub fn affixes(self) -> (usize, usize) {
+        match self {
+            Rarity::Common => (0, 0),
+            Rarity::Uncommon => (0, 1),
+            Rarity::Rare => (1, 1),
+            Rarity::Epic => (1, 2),
         }
     }
 }
 
-#[derive(Debug)]
-struct Object {
-    // Properties
-    x: i32,
-    y: i32,
-    char: char,
-    color: Color,
-    name: String,
-
-    // Components
-    fighter: Option<Fighter>,
-    ai: Option<Ai>,
-    item: Option<Item>,
-
-    // Flags (which are also kind of properties)
-    is_walkable: bool,
-    is_alive: bool,
-    was_seen: bool,
+// Enchantments rolled onto better equipment.  Their effects only apply while
+// the equipment is wielded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Affix {
+    Keen,     // More attack
+    Flaming,  // Burns whatever it hits
+    Vampiric, // Heals the wielder with each hit
+    Bear,     // More max HP
+    Turtle,   // More defense
+    Troll,    // Faster regeneration
+    Sun,      // Brighter torch, only found on accessories
 }
 
-impl Object {
-    pub fn new(x: i32, y: i32, char: char, name: &str, color: Color) -> Self {
-        Object {
-            // Properties
-            x,
-            y,
-            char,
-            color,
-            name: name.to_string(),
-
-            // Components
-            fighter: None,
-            ai: None,
-            item: None,
+// Equipment gets at most one of each kind, so the name stays readable
+const PREFIX_AFFIXES: [Affix; 3] = [Affix::Keen, Affix::Flaming, Affix::Vampiric];
+const SUFFIX_AFFIXES: [Affix; 3] = [Affix::Bear, Affix::Turtle, Affix::Troll];
+// Rings and pendants are all about their affixes, so they have a pool of their own
+const ACCESSORY_AFFIXES: [Affix; 7] = [
+    Affix::Keen,
+    Affix::Flaming,
+    Affix::Vampiric,
+    Affix::Bear,
+    Affix::Turtle,
+    Affix::Troll,
+    Affix::Sun,
+];
 
-            // Flags (which are also kind of properties)
-            is_walkable: false,
-            is_alive: true,
-            was_seen: false,
+impl Affix {
+    pub fn name(self) -> &'static str {
+        match self {
+            Affix::Keen => "keen",
+            Affix::Flaming => "flaming",
+            Affix::Vampiric => "vampiric",
+            Affix::Bear => "of the Bear",
+            Affix::Turtle => "of the Turtle",
+            Affix::Troll => "of the Troll",
+            Affix::Sun => "of the Sun",
         }
     }
 
-    pub fn pos(&self) -> (i32, i32) {
-        (self.x, self.y)
+    pub fn is_prefix(self) -> bool {
+        PREFIX_AFFIXES.contains(&self)
     }
+}
 
-    pub fn set_pos(&mut self, x: i32, y: i32) {
-        self.x = x;
-        self.y = y;
+fn roll_affixes<R: Rng>(rarity: Rarity, rng: &mut R) -> Vec<Affix> {
+    let (least, most) = rarity.affixes();
+    let count = rng.gen_range(least, most + 1);
+    let prefix = *rng.choose(&PREFIX_AFFIXES).unwrap_or(&Affix::Keen);
+    let suffix = *rng.choose(&SUFFIX_AFFIXES).unwrap_or(&Affix::Bear);
+    match count {
+        0 => vec![],
+        1 if rng.gen() => vec![prefix],
+        1 => vec![suffix],
+        _ => vec![prefix, suffix],
     }
+}
 
-    pub fn take_damage(&mut self, damage: i32, game: &mut Game) {
-        if damage <= 0 {
-            return;
-        }
-        if let Some(fighter) = self.fighter.as_mut() {
-            if damage >= fighter.hp {
-                fighter.hp = 0;
-                self.is_alive = false;
-                fighter.on_death.callback(self, game);
-            } else {
-                fighter.hp -= damage;
-            }
+// Accessories always have at least one affix, and rarer ones have more
+fn roll_accessory_affixes<R: Rng>(rarity: Rarity, rng: &mut R) -> Vec<Affix> {
+    let (_, most) = rarity.affixes();
+    let count = rng.gen_range(1, most + 2);
+    let mut affixes = vec![];
+    while affixes.len() < count {
+        let affix = *rng.choose(&ACCESSORY_AFFIXES).unwrap_or(&Affix::Sun);
+        if !affixes.contains(&affix) {
+            affixes.push(affix);
         }
     }
+    // Prefixes first, so they read in the order they're put in the name
+    affixes.sort_by_key(|affix| !affix.is_prefix());
+    affixes
+}
 
-    pub fn heal(&mut self, healing: i32) {
-        if healing <= 0 {
-            return;
+// Deeper levels turn up better loot
+fn roll_rarity<R: Rng>(level: u32, rng: &mut R) -> Rarity {
+    let table = [
+        (Rarity::Common, 100),
+        (Rarity::Uncommon, 20 + 5 * level),
+        (Rarity::Rare, 5 + 3 * level),
+        (Rarity::Epic, level),
+    ];
+    choose_weighted(&table, rng)
+}
+
+// Make a freshly generated item the given rarity, with bonuses to match.  Only
+// equipment and wands have anything to improve, so other items stay common.
+fn apply_rarity<R: Rng>(item: &mut Object, rarity: Rarity, level: u32, rng: &mut R) {
+    let bonus = rarity.bonus();
+    let mut affixes = vec![];
+    match (item.item, item.equipment.as_mut()) {
+        (_, Some(equipment)) => {
+            // Rare finds further down are better still
+            let depth_bonus = if bonus > 0 { level as i32 / RARITY_DEPTH_STEP } else { 0 };
+            // Shields have no affixes, see equipped_affixes
+            match equipment.slot {
+                Slot::MainHand => {
+                    equipment.attack_bonus += bonus + depth_bonus;
+                    equipment.affixes = roll_affixes(rarity, rng);
+                }
+                Slot::OffHand => equipment.defense_bonus += bonus + depth_bonus,
+                Slot::LeftRing | Slot::RightRing | Slot::Neck => {
+                    equipment.affixes = roll_accessory_affixes(rarity, rng);
+                }
+            }
+            affixes = equipment.affixes.clone();
         }
-        if let Some(fighter) = self.fighter.as_mut() {
-            fighter.hp = min(fighter.max_hp, fighter.hp + healing);
+        (Some(Item::Digging(charges)), None) => {
+            item.item = Some(Item::Digging(charges + bonus));
+            item.name = format!("wand of digging ({})", charges + bonus);
         }
+        _ => return,
     }
+    item.rarity = rarity;
+    // Enchanted equipment is named for its affixes, the color shows the rarity
+    item.name = if affixes.is_empty() {
+        format!("{}{}", rarity.prefix(), item.name)
+    } else {
+        let mut name = item.name.clone();
+        for affix in affixes {
+            name = if affix.is_prefix() {
+                format!("{} {}", affix.name(), name)
+            } else {
+                format!("{} {}", name, affix.name())
+            };
+        }
+        name
+    };
+}
 
-    pub fn attack(&self, other: &mut Object, game: &mut Game) {
-        let damage = self.fighter.map_or(0, |f| f.attack) - other.fighter.map_or(0, |f| f.defense);
-        if damage > 0 {
-            game.messages.add(
-                format!("{} attacks {} for {} damage", self.name, other.name, damage),
-                colors::WHITE,
-            );
-            other.take_damage(damage, game);
-        } else {
-            game.messages.add(
-                format!("{} attacks {} but it has no effect!", self.name, other.name),
-                colors::WHITE,
-            );
+impl Item {
+    pub fn weight(self) -> i32 {
+        use Item::*;
+        match self {
+            Heal | Acid | Lightning | Confusion | Charm | Haste | Slow | Enchant | Amulet => 1,
+            Digging(_) => 2,
+            Spear => 8,
+            Axe => 10,
+            Pick => 12,
+            Shield => 6,
+            Ring | Pendant => 1,
+            Gold(_) => 0,
         }
     }
 
-    pub fn draw(&self, con: &mut dyn Console) {
-        con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+    // What examining one tells the player, once they know what it is
+    pub fn description(self) -> &'static str {
+        use Item::*;
+        match self {
+            Heal => "Heals your wounds.",
+            Acid => "Burns whoever drinks it, or whatever it splashes over.",
+            Lightning => "Strikes the closest enemy with a bolt of lightning.",
+            Confusion => "Leaves an enemy stumbling about at random for a while.",
+            Charm => "Turns an enemy into an ally for a while.",
+            Haste => "Lets you act twice as often for a while.",
+            Slow => "Makes an enemy act half as often for a while.",
+            Enchant => "Makes a piece of your equipment a little better.",
+            Spear => "A weapon which also hits the enemy behind the one you strike.",
+            Axe => "A two-handed weapon which also hits the enemies either side.",
+            Pick => "A two-handed weapon which digs through walls.",
+            Shield => "Held in the off hand, it can stop a blow outright.",
+            Ring => "Worn on either hand, for whatever magic it holds.",
+            Pendant => "Worn around the neck, for whatever magic it holds.",
+            Digging(_) => "Blasts a tunnel through the rock.",
+            Gold(_) => "Gold for the shops.",
+            Amulet => "The Amulet of the Kings. Take it and the tombs are yours.",
+        }
     }
+}
 
-    fn grid_distance_to(&self, other: &Object) -> i32 {
-        let dx = (other.x - self.x).abs();
-        let dy = (other.y - self.y).abs();
-        max(dx, dy)
+// For names at the start of a message
+fn capitalized(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
     }
+}
 
-    pub fn clear(&self, con: &mut dyn Console) {
-        con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
+fn normalize(delta: i32) -> i32 {
+    match delta {
+        0 => 0,
+        1.. => 1,
+        _ => -1,
     }
 }
 
-//
-// map-related stuff
-//
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
-
-const MAX_ROOMS: i32 = 30;
-const MAX_ROOM_WIDTH: i32 = 15;
-const MIN_ROOM_WIDTH: i32 = 6;
-const MAX_ROOM_HEIGHT: i32 = 10;
-const MIN_ROOM_HEIGHT: i32 = 5;
-const MAX_ROOM_MONSTERS: i32 = 3;
-const MAX_ROOM_ITEMS: i32 = 1;
-
-// sizes and coordinates relevant for the GUI
-const BAR_WIDTH: i32 = 20;
-const PANEL_HEIGHT: i32 = 7;
-const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
-
-const MSG_X: i32 = BAR_WIDTH + 2;
-const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
-const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
-
-const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
-const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
-const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
-const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
-
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
-const FOV_LIGHT_WALLS: bool = true;
-const TORCH_RADIUS: i32 = 10;
-
-fn light_blend(
-    x1: i32,
-    y1: i32,
-    x2: i32,
-    y2: i32,
-    close: Color,
-    far: Color,
-    max_radius: f32,
-) -> Color {
-    let dx = (max(x1, x2) - min(x1, x2)) as f32;
-    let dy = (max(y1, y2) - min(y1, y2)) as f32;
+// Turns a monster waits behind an ally before trying to swap places with it
+const MAX_QUEUE_TURNS: u32 = 3;
 
-    let f = (dx * dx + dy * dy) / (max_radius * max_radius);
-    // adjacent squares (f ~= 0) should be the close color
-    // squares at maximum visible distance (f ~= 1) should be the far color
-    close * (1. - f) + far * f
+fn move_towards(id: usize, target_x: i32, target_y: i32, game: &mut Game, objects: &mut [Object]) {
+    let dx = normalize(target_x - objects[id].x);
+    let dy = normalize(target_y - objects[id].y);
+    // Monsters of a kind don't jostle each other, see wait_behind.  The
+    // player's allies make way for each other in step instead.
+    let ahead = (objects[id].x + dx, objects[id].y + dy);
+    let faction = objects[id].faction;
+    let blocker = objects.iter().enumerate().position(|(other, ob)| {
+        other != id
+            && ob.pos() == ahead
+            && ob.is_alive
+            && ob.fighter.is_some()
+            && !faction.is_hostile_to(ob.faction)
+    });
+    if let (Some(blocker), true) = (blocker, faction.is_monster()) {
+        wait_behind(id, blocker, (target_x, target_y), game, objects);
+        return;
+    }
+    objects[id].queued_turns = 0;
+    let moved = move_by(id, dx, dy, game, objects) == PlayerAction::TookTurn
+        || move_by(id, dx, 0, game, objects) == PlayerAction::TookTurn
+        || move_by(id, 0, dy, game, objects) == PlayerAction::TookTurn;
+    // Rather than wait at the water's edge, go round it
+    if !moved && !objects[id].can_swim {
+        route_around_water(id, (target_x, target_y), game, objects);
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Rect {
-    x1: i32,
-    y1: i32,
-    x2: i32,
-    y2: i32,
+// One step along the shortest way to `target` which keeps out of deep water and
+// hazards, for monsters which can't swim
+fn route_around_water(id: usize, target: (i32, i32), game: &mut Game, objects: &mut [Object]) {
+    let movement = game.settings.movement;
+    let distances = dijkstra_map_over(&game.map, &[target], movement, |tile| {
+        tile.is_walkable && !tile.kind.is_hazard() && tile.kind != (TileKind::Water { deep: true })
+    });
+    let (x, y) = objects[id].pos();
+    let distance = |(nx, ny): (i32, i32)| distances[nx as usize][ny as usize];
+    let step = DIRECTIONS
+        .iter()
+        .copied()
+        .filter(|&(dx, dy)| movement.allows(dx, dy) && in_map(x + dx, y + dy))
+        .filter(|&(dx, dy)| distance((x + dx, y + dy)) < distance((x, y)))
+        .min_by_key(|&(dx, dy)| distance((x + dx, y + dy)));
+    if let Some((dx, dy)) = step {
+        move_by(id, dx, dy, game, objects);
+    }
 }
 
-impl Rect {
-    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
-        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
+// Stuck behind an ally on the way to `target`.  Go round it if some other step
+// still gets closer, otherwise wait for it to move on rather than sidestepping
+// back and forth.  An ally which isn't fighting at the front of the queue gets
+// swapped with after a while, so two monsters waiting on each other (say, in
+// a corridor with enemies at both ends) never wait forever.
+fn wait_behind(
+    id: usize,
+    blocker: usize,
+    target: (i32, i32),
+    game: &mut Game,
+    objects: &mut [Object],
+) {
+    let movement = game.settings.movement;
+    let metric = movement.metric();
+    let (x, y) = objects[id].pos();
+    let closer = |(nx, ny): (i32, i32)| metric.distance(target.0 - nx, target.1 - ny);
+    let distance = closer((x, y));
+    let mut detours: Vec<(i32, i32)> = DIRECTIONS
+        .iter()
+        .copied()
+        .filter(|&(dx, dy)| movement.allows(dx, dy))
+        .filter(|&(dx, dy)| closer((x + dx, y + dy)) < distance)
+        .filter(|&(dx, dy)| !is_blocked(&game.map, x + dx, y + dy, objects))
+        .collect();
+    detours.sort_by(|&a, &b| closer((x + a.0, y + a.1)).total_cmp(&closer((x + b.0, y + b.1))));
+    let detoured = detours
+        .into_iter()
+        .any(|(dx, dy)| move_by(id, dx, dy, game, objects) == PlayerAction::TookTurn);
+    if detoured {
+        objects[id].queued_turns = 0;
+        return;
     }
 
-    pub fn center(&self) -> (i32, i32) {
-        ((self.x1 + self.x2) / 2, (self.y1 + self.y2) / 2)
+    let fighting = objects[blocker].grid_distance(target.0, target.1, movement) <= 1;
+    if objects[id].queued_turns >= MAX_QUEUE_TURNS
+        && !fighting
+        && swap_positions(id, blocker, &game.map, objects)
+    {
+        objects[id].queued_turns = 0;
+    } else {
+        objects[id].queued_turns += 1;
     }
+}
 
-    pub fn intersects_with(&self, other: &Rect) -> bool {
-        self.x1 <= other.x2 && self.x2 >= other.x1 && self.y1 <= other.y2 && self.y2 >= other.y1
+// Mutably borrow two different objects at once
+fn mut_two(first: usize, second: usize, objects: &mut [Object]) -> (&mut Object, &mut Object) {
+    assert_ne!(first, second);
+    if first < second {
+        let (first_slice, second_slice) = objects.split_at_mut(second);
+        (&mut first_slice[first], &mut second_slice[0])
+    } else {
+        let (second_slice, first_slice) = objects.split_at_mut(first);
+        (&mut first_slice[0], &mut second_slice[second])
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Tile {
-    is_walkable: bool,
-    is_transparent: bool,
-    explored: bool,
+fn attack_target(id: usize, target: usize, game: &mut Game, objects: &mut [Object]) {
+    if objects[target].fighter.is_some_and(|f| f.hp > 0) {
+        if fumbles(id, game, objects) {
+            return;
+        }
+        let (attacker, defender) = mut_two(id, target, objects);
+        attacker.attack(defender, game);
+        if id == PLAYER {
+            affixes_on_hit(target, game, objects);
+        }
+    }
 }
 
-impl Tile {
-    pub fn empty() -> Self {
-        Tile::new(true, true)
+// The odd attack goes nowhere, and the player might even lose their grip on
+// their weapon.  It still takes the turn.
+fn fumbles(id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
+    if !game.settings.fumbles {
+        return false;
     }
-
-    pub fn wall() -> Self {
-        Tile::new(false, false)
+    let roll = game.rngs.combat.gen::<f32>();
+    let name = &objects[id].name;
+    game.trace.record(|| format!("roll fumble {} {:.3} against {:.3}", name, roll, FUMBLE_CHANCE));
+    if roll >= FUMBLE_CHANCE {
+        return false;
     }
+    game.messages.add_cat(format!("{} fumbles!", name), colors::LIGHT_GREY, MsgCategory::Combat);
 
-    pub fn new(is_walkable: bool, is_transparent: bool) -> Self {
-        Tile { is_walkable, is_transparent, explored: false }
+    let weapon = game.inventory.iter().position(|item| {
+        item.equipment.as_ref().is_some_and(|e| e.equipped && e.slot == Slot::MainHand)
+    });
+    let drops = id == PLAYER && game.rngs.combat.gen::<f32>() < FUMBLE_DROP_CHANCE;
+    if let (Some(weapon), true) = (weapon, drops) {
+        let mut dropped = game.inventory.remove(weapon);
+        if let Some(equipment) = dropped.equipment.as_mut() {
+            equipment.equipped = false;
+        }
+        let (x, y) = objects[PLAYER].pos();
+        dropped.set_pos(x, y);
+        game.messages.add_cat(
+            format!("You drop your {}!", dropped.name),
+            colors::RED,
+            MsgCategory::Combat,
+        );
+        // It turns up on the floor once everyone has moved, see flush_pending
+        game.pending_spawns.push(dropped);
+        objects[PLAYER].clamp_hp(game);
     }
+    true
 }
 
-type Map = Vec<Vec<Tile>>;
-type MapSlice = [Vec<Tile>];
-
-struct Messages {
-    messages: Vec<(String, Color)>,
-}
-
-impl Messages {
-    pub fn new() -> Self {
-        Self { messages: vec![] }
-    }
-
-    pub fn add<T: Into<String>>(&mut self, message: T, color: Color) {
-        self.messages.push((message.into(), color));
+// Effects of the player's equipment affixes, after each hit
+fn affixes_on_hit(target: usize, game: &mut Game, objects: &mut [Object]) {
+    for _ in 0..equipped_affixes(Affix::Flaming, game) {
+        if !objects[target].is_alive {
+            break;
+        }
+        game.messages.add_cat(
+            format!("The {} is burned by the flames!", objects[target].name),
+            colors::FLAME,
+            MsgCategory::Combat,
+        );
+        objects[target].take_damage(FLAME_DAMAGE, game);
     }
-
-    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &(String, Color)> {
-        self.messages.iter()
+    let vampiric = equipped_affixes(Affix::Vampiric, game) as i32;
+    if vampiric > 0 {
+        objects[PLAYER].heal(VAMPIRIC_HEAL * vampiric, game);
     }
 }
 
-// Structure to hold game "global" data
-// (Why is the Object list not in here?)
-struct Game {
-    map: Map,
-    messages: Messages,
-    inventory: Vec<Object>,
+// The closest living fighter that is hostile to objects[id].  Like every
+// "nearest" or "most" pick monsters make, ties go to the lowest object index, so
+// a given seed always plays out the same way.
+fn nearest_enemy(id: usize, movement: MovementMode, objects: &[Object]) -> Option<usize> {
+    let faction = objects[id].faction;
+    objects
+        .iter()
+        .enumerate()
+        .filter(|&(_, ob)| ob.is_alive && ob.fighter.is_some() && faction.is_hostile_to(ob.faction))
+        .min_by_key(|&(_, ob)| ob.grid_distance_to(&objects[id], movement))
+        .map(|(target, _)| target)
 }
 
-fn is_blocked_by_object(x: i32, y: i32, objects: &[Object]) -> bool {
-    objects.iter().any(|object| !object.is_walkable && object.pos() == (x, y))
+// Go after the nearest enemy.  Allies with no enemies left keep up with the player.
+fn chase_and_attack(id: usize, fov: &Vision, game: &mut Game, objects: &mut [Object]) {
+    let movement = game.settings.movement;
+    match nearest_enemy(id, movement, objects) {
+        Some(target) if objects[id].grid_distance_to(&objects[target], movement) > 1 => {
+            // Out of sight of the player, follow their trail around corners
+            let (x, y) = objects[id].pos();
+            if target == PLAYER && !fov.is_in_fov(x, y) && follow_scent(id, game, objects) {
+                return;
+            }
+            let (target_x, target_y) = objects[target].pos();
+            move_towards(id, target_x, target_y, game, objects);
+        }
+        Some(target) => attack_target(id, target, game, objects),
+        None => {
+            if objects[id].faction == Faction::Player
+                && objects[id].grid_distance_to(&objects[PLAYER], movement) > ALLY_FOLLOW_DISTANCE
+            {
+                let (player_x, player_y) = objects[PLAYER].pos();
+                move_towards(id, player_x, player_y, game, objects);
+            }
+        }
+    }
 }
 
-fn is_blocked(map: &MapSlice, x: i32, y: i32, objects: &[Object]) -> bool {
-    if !map[x as usize][y as usize].is_walkable {
-        return true;
+// Wake every monster in the groups alerted this turn, so they all come for the
+// player.  One pass over the objects, and waking doesn't alert anyone else.
+fn broadcast_alerts(game: &mut Game, objects: &mut [Object]) {
+    if game.alerted_groups.is_empty() {
+        return;
+    }
+    let groups = std::mem::take(&mut game.alerted_groups);
+    let mut woken = 0;
+    for ob in objects.iter_mut() {
+        let in_group = ob.group_id.is_some_and(|group| groups.contains(&group));
+        if !in_group || !ob.is_alive || !ob.faction.is_monster() {
+            continue;
+        }
+        ob.was_seen = true;
+        if !matches!(ob.alert_state, AlertState::Noticed | AlertState::Hunting) {
+            ob.alert_state = AlertState::Noticed;
+            woken += 1;
+        }
+    }
+    if woken > 0 {
+        game.messages.add("You hear the rest of the hive stir!", colors::ORANGE);
     }
-    is_blocked_by_object(x, y, objects)
 }
 
-fn move_by(id: usize, dx: i32, dy: i32, map: &MapSlice, objects: &mut [Object]) -> PlayerAction {
+// Step onto the neighbouring tile with the strongest scent of the player, if
+// it's stronger than here.  Returns false if there was nowhere to go.
+fn follow_scent(id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
     let (x, y) = objects[id].pos();
-    // move by the given amount
-    let next_x = x + dx;
-    let next_y = y + dy;
-    if (0..MAP_WIDTH).contains(&next_x)
-        && (0..MAP_HEIGHT).contains(&next_y)
-        && !is_blocked(map, next_x, next_y, objects)
-    {
-        objects[id].set_pos(next_x, next_y);
-        return PlayerAction::TookTurn;
+    let here = game.scent[x as usize][y as usize];
+    let mut steps = vec![];
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (nx, ny) = (x + dx, y + dy);
+            if in_map(nx, ny) && game.settings.movement.allows(dx, dy) {
+                let scent = game.scent[nx as usize][ny as usize];
+                if scent > here {
+                    steps.push((scent, dx, dy));
+                }
+            }
+        }
     }
-    PlayerAction::DidntTakeTurn
+    steps.sort_by_key(|&(scent, _, _)| std::cmp::Reverse(scent));
+    steps
+        .into_iter()
+        .any(|(_, dx, dy)| move_by(id, dx, dy, game, objects) == PlayerAction::TookTurn)
 }
 
-fn player_move_or_attack(
-    dx: i32,
-    dy: i32,
-    game: &mut Game,
-    objects: &mut [Object],
-) -> PlayerAction {
-    let (x, y) = objects[PLAYER].pos();
-    let next_x = x + dx;
-    let next_y = y + dy;
-
-    let target_id = objects
-        .iter()
-        .position(|object| object.pos() == (next_x, next_y) && object.fighter != None);
-
-    match target_id {
-        Some(target_id) => {
-            let (player_slice, target_slice) = objects.split_at_mut(target_id);
-            player_slice[0].attack(&mut target_slice[0], game);
-            PlayerAction::TookTurn
+// Fade the whole scent map, then mark the player's current tile
+fn update_scent(game: &mut Game, objects: &[Object]) {
+    for column in game.scent.iter_mut() {
+        for scent in column.iter_mut() {
+            *scent = scent.saturating_sub(SCENT_DECAY);
         }
-        None => move_by(PLAYER, dx, dy, &game.map, objects),
     }
+    let (x, y) = objects[PLAYER].pos();
+    // Sneaking leaves a fainter trail
+    game.scent[x as usize][y as usize] = if game.sneaking { SCENT_MAX / 2 } else { SCENT_MAX };
 }
 
-fn player_pick_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    if game.inventory.len() >= 26 {
-        game.messages.add("Inventory is full", colors::RED);
-        return;
-    }
-    let item = objects.swap_remove(object_id);
-    game.messages.add(format!("You picked up a {}", item.name), colors::WHITE);
-    game.inventory.push(item);
+fn empty_scent() -> Vec<Vec<u16>> {
+    vec![vec![0; MAP_HEIGHT as usize]; MAP_WIDTH as usize]
 }
 
-fn player_pick_up_here(game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
-    let player_pos = objects[PLAYER].pos();
+// Whether nothing blocks the view between two tiles
+fn clear_line(map: &MapSlice, from: (i32, i32), to: (i32, i32)) -> bool {
+    Line::new(from, to)
+        .take_while(|&pos| pos != to)
+        .all(|(x, y)| map[x as usize][y as usize].is_transparent)
+}
 
-    match objects.iter().position(|ob| ob.pos() == player_pos && ob.item.is_some()) {
-        Some(id) => {
-            player_pick_up(id, game, objects);
-            PlayerAction::TookTurn
+// Use the first of the monster's abilities which is ready and has a target.
+// Returns false if it didn't use any, so it can fight normally instead.
+fn ai_cast(id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
+    for ability in objects[id].abilities.clone() {
+        if objects[id].cooldowns.get(&ability).is_some_and(|&turns| turns > 0) {
+            continue;
         }
-        _ => {
-            game.messages.add("There is nothing here", colors::WHITE);
-            PlayerAction::DidntTakeTurn
+        let used = match ability {
+            Ability::HealAlly => cast_heal_ally(id, game, objects),
+            Ability::Bolt => cast_bolt(id, game, objects),
+        };
+        if used {
+            objects[id].cooldowns.insert(ability, ability.cooldown());
+            return true;
         }
     }
+    false
 }
 
-fn make_room(room: Rect, map: &mut Map) {
-    for x in (room.x1 + 1)..room.x2 {
-        for y in (room.y1 + 1)..room.y2 {
-            map[x as usize][y as usize] = Tile::empty();
+fn cast_heal_ally(id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
+    let faction = objects[id].faction;
+    let patient = objects
+        .iter()
+        .enumerate()
+        .filter(|&(_, ob)| {
+            ob.is_alive
+                && ob.faction == faction
+                && ob.distance_to(&objects[id], Metric::Manhattan) <= SHAMAN_HEAL_RANGE as f32
+                && ob.fighter.is_some_and(|f| f.hp < f.max_hp)
+        })
+        // min_by_key keeps the first of equals, where max_by_key would keep the last
+        .min_by_key(|&(_, ob)| std::cmp::Reverse(ob.fighter.map_or(0, |f| f.max_hp - f.hp)))
+        .map(|(patient, _)| patient);
+    match patient {
+        Some(patient) => {
+            game.messages.add_cat(
+                format!("The {} heals the {}", objects[id].name, objects[patient].name),
+                colors::LIGHT_GREEN,
+                MsgCategory::Combat,
+            );
+            objects[patient].heal(SHAMAN_HEAL, game);
+            true
         }
+        None => false,
     }
 }
 
-fn make_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    for x in min(x1, x2)..=(max(x1, x2)) {
-        map[x as usize][y as usize] = Tile::empty();
+fn cast_bolt(id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
+    let movement = game.settings.movement;
+    let target = match nearest_enemy(id, movement, objects) {
+        Some(target) => target,
+        None => return false,
+    };
+    let distance = objects[id].grid_distance_to(&objects[target], movement);
+    // Not worth a spell when it could just hit them
+    if distance <= 1
+        || distance > BOLT_RANGE
+        || !clear_line(&game.map, objects[id].pos(), objects[target].pos())
+    {
+        return false;
+    }
+    game.messages.add_cat(
+        format!(
+            "The {} hurls a bolt of magic at {}!",
+            objects[id].name,
+            objects[target].the_name()
+        ),
+        colors::LIGHT_FLAME,
+        MsgCategory::Combat,
+    );
+    if target != PLAYER || !player_blocks(game) {
+        objects[target].take_damage(BOLT_DAMAGE, game);
     }
+    if target == PLAYER && !objects[PLAYER].is_alive {
+        game.killed_by = Some(objects[id].name.clone());
+    }
+    true
 }
 
-fn make_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    for y in min(y1, y2)..=(max(y1, y2)) {
-        map[x as usize][y as usize] = Tile::empty();
+// Abilities recharge every turn, whether or not their owner gets to act
+fn tick_cooldowns(objects: &mut [Object]) {
+    for object in objects.iter_mut().filter(|ob| ob.is_alive) {
+        for turns in object.cooldowns.values_mut() {
+            *turns = turns.saturating_sub(1);
+        }
     }
 }
 
-fn place_item(x: i32, y: i32, objects: &mut Vec<Object>) {
-    let r = rand::random::<f32>();
-    let item: Object = if r < 0.7 {
-        let mut potion = Object::new(x, y, '!', "health potion", colors::ORANGE);
-        potion.is_walkable = true;
-        potion.item = Some(Item::Heal);
-        potion
-    } else if r < 0.8 {
-        let mut scroll = Object::new(x, y, '#', "scroll of lightning bolt", colors::LIGHT_YELLOW);
-        scroll.is_walkable = true;
-        scroll.item = Some(Item::Lightning);
-        scroll
-    } else {
-        let mut scroll = Object::new(x, y, '#', "scroll of confusion", colors::LIGHT_BLUE);
-        scroll.is_walkable = true;
-        scroll.item = Some(Item::Confusion);
-        scroll
-    };
-    objects.push(item);
-}
-
-fn place_monster(x: i32, y: i32, objects: &mut Vec<Object>) {
-    let monster = if rand::random::<f32>() < 0.8 {
-        // Create an orc
-        let mut orc = Object::new(x, y, 'o', "orc", colors::DESATURATED_GREEN);
-        orc.fighter = Some(Fighter {
-            max_hp: 10,
-            hp: 10,
-            defense: 0,
-            attack: 3,
-            on_death: DeathCallback::Monster,
-        });
-        orc.ai = Some(Ai::Basic);
-        orc
-    } else {
-        let mut troll = Object::new(x, y, 'T', "troll", colors::DARKER_RED);
-        troll.fighter = Some(Fighter {
-            max_hp: 16,
-            hp: 16,
-            defense: 1,
-            attack: 4,
-            on_death: DeathCallback::Monster,
-        });
-        troll.ai = Some(Ai::Basic);
-        troll
-    };
+// The player's spells recharge every turn
+fn tick_spells(game: &mut Game) {
+    for turns in game.spell_cooldowns.values_mut() {
+        *turns = turns.saturating_sub(1);
+    }
+}
 
-    objects.push(monster);
+fn regenerate_mana(objects: &[Object], game: &mut Game) {
+    if objects[PLAYER].is_alive {
+        game.mana = min(game.max_mana, game.mana + MANA_REGEN);
+    }
 }
 
-fn place_objects(room: Rect, objects: &mut Vec<Object>) {
-    let mut rng = rand::thread_rng();
-    let num_monsters = rng.gen_range(0, MAX_ROOM_MONSTERS + 1);
-    for _ in 0..num_monsters {
-        let x = rng.gen_range(room.x1 + 1, room.x2);
-        let y = rng.gen_range(room.y1 + 1, room.y2);
-        if is_blocked_by_object(x, y, objects) {
+// Every living fighter heals 1 HP each time its counter reaches its rate
+fn regenerate(game: &Game, objects: &mut [Object]) {
+    for object in objects.iter_mut() {
+        let (rate, max_hp) = (object.regen_rate(game), object.max_hp(game));
+        if !object.is_alive || rate == 0 {
             continue;
         }
-        place_monster(x, y, objects);
+        if let Some(fighter) = object.fighter.as_mut() {
+            if fighter.hp >= max_hp {
+                fighter.regen_counter = 0;
+                continue;
+            }
+            fighter.regen_counter += 1;
+            if fighter.regen_counter >= rate {
+                fighter.regen_counter = 0;
+                fighter.hp += 1;
+            }
+        }
     }
+}
 
-    let num_items = rng.gen_range(0, MAX_ROOM_ITEMS + 1);
-    for _ in 0..num_items {
-        let x = rng.gen_range(room.x1 + 1, room.x2);
-        let y = rng.gen_range(room.y1 + 1, room.y2);
-        if !is_blocked_by_object(x, y, objects) {
-            place_item(x, y, objects);
+// Raise a nearby corpse, or failing that call up a fresh skeleton next to the
+// summoner.  Returns false if there was no room for either.
+fn summon(id: usize, game: &mut Game, objects: &mut [Object]) -> bool {
+    let corpse = (0..objects.len()).find(|&i| {
+        let ob = &objects[i];
+        i != PLAYER
+            && !ob.is_alive
+            && ob.fighter.is_none()
+            && ob.grid_distance_to(&objects[id], game.settings.movement) <= REANIMATE_RANGE
+            && !is_blocked_by_object(ob.x, ob.y, objects)
+    });
+    if let Some(corpse) = corpse {
+        game.messages.add_cat(
+            format!("The {} raises the {}!", objects[id].name, objects[corpse].name),
+            colors::LIGHT_VIOLET,
+            MsgCategory::Combat,
+        );
+        // Reuse the corpse's slot, so the object list doesn't grow
+        let (x, y) = objects[corpse].pos();
+        objects[corpse] = make_monster(&RAISED_SKELETON, x, y);
+        objects[corpse].was_seen = true;
+        objects[corpse].faction = objects[id].faction;
+        objects[corpse].status_effects = objects[id].status_effects.clone();
+        return true;
+    }
+    let (x, y) = objects[id].pos();
+    if spawn_monster_near(x, y, &RAISED_SKELETON, game, objects) {
+        if let Some(summoned) = game.pending_spawns.last_mut() {
+            summoned.faction = objects[id].faction;
+            summoned.status_effects = objects[id].status_effects.clone();
         }
+        game.messages.add_cat(
+            format!("The {} summons a {}!", objects[id].name, RAISED_SKELETON.name),
+            colors::LIGHT_VIOLET,
+            MsgCategory::Combat,
+        );
+        true
+    } else {
+        false
     }
 }
 
-fn make_map(objects: &mut Vec<Object>) -> Map {
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
-    let mut rng = rand::thread_rng();
-    let mut rooms = vec![];
-    let mut prev_x = 0;
-    let mut prev_y = 0;
+fn start_boss_phase(id: usize, phase: &BossPhase, game: &mut Game, objects: &mut [Object]) {
+    game.messages.add_cat(phase.message, colors::LIGHT_VIOLET, MsgCategory::Combat);
+    if let Some(fighter) = objects[id].fighter.as_mut() {
+        fighter.attack += phase.attack_bonus;
+    }
+    objects[id].abilities = phase.abilities.to_vec();
+    let (x, y) = objects[id].pos();
+    for _ in 0..phase.summons {
+        if !spawn_monster_near(x, y, &RAISED_SKELETON, game, objects) {
+            break;
+        }
+        if let Some(summoned) = game.pending_spawns.last_mut() {
+            summoned.faction = objects[id].faction;
+            summoned.was_seen = true;
+        }
+    }
+}
 
-    for _ in 0..MAX_ROOMS {
-        let w = rng.gen_range(MIN_ROOM_WIDTH, MAX_ROOM_WIDTH);
-        let h = rng.gen_range(MIN_ROOM_HEIGHT, MAX_ROOM_HEIGHT);
-        let room_rect =
-            Rect::new(rng.gen_range(0, MAP_WIDTH - w), rng.gen_range(0, MAP_HEIGHT - h), w, h);
+fn ai_take_turn(id: usize, fov: &Vision, game: &mut Game, objects: &mut [Object]) {
+    assert_ne!(id, PLAYER);
+    if objects[id].lost_turns > 0 {
+        objects[id].lost_turns -= 1;
+        return;
+    }
+    match &mut objects[id].ai {
+        Some(Ai::Basic) => {
+            if !ai_cast(id, game, objects) {
+                chase_and_attack(id, fov, game, objects);
+            }
+        }
+        Some(Ai::Summoner { cooldown, summons_left }) => {
+            if *cooldown > 0 {
+                *cooldown -= 1;
+            }
+            let (ready, summons_left) = (*cooldown == 0, *summons_left);
+            let (x, y) = objects[id].pos();
+            if ready && summons_left > 0 && fov.is_in_fov(x, y) && summon(id, game, objects) {
+                objects[id].ai = Some(Ai::Summoner {
+                    cooldown: SUMMON_COOLDOWN,
+                    summons_left: summons_left - 1,
+                });
+            } else if !ai_cast(id, game, objects) {
+                chase_and_attack(id, fov, game, objects);
+            }
+        }
+        Some(Ai::Boss { phase }) => {
+            let phase = *phase;
+            let next = BOSS_PHASES.get(phase).filter(|next| {
+                objects[id].fighter.is_some_and(|f| f.hp * 100 <= f.max_hp * next.hp_percent)
+            });
+            match next {
+                // Starting a phase takes the boss's whole turn
+                Some(next) => {
+                    objects[id].ai = Some(Ai::Boss { phase: phase + 1 });
+                    start_boss_phase(id, next, game, objects);
+                }
+                None => {
+                    if !ai_cast(id, game, objects) {
+                        chase_and_attack(id, fov, game, objects);
+                    }
+                }
+            }
+        }
+        Some(Ai::Confused { previous, turns }) => {
+            if *turns > 0 {
+                *turns -= 1;
+            }
+            if *turns == 0 {
+                objects[id].ai = Some(*previous.clone());
+            }
 
-        let blocked = rooms.iter().any(|other_room| room_rect.intersects_with(other_room));
-        if !blocked {
-            make_room(room_rect, &mut map);
-            place_objects(room_rect, objects);
-            let (new_x, new_y) = room_rect.center();
-            if rooms.is_empty() {
-                objects[PLAYER].set_pos(new_x, new_y);
-            } else if rand::random() {
-                make_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                make_v_tunnel(prev_y, new_y, new_x, &mut map);
-            } else {
-                make_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                make_h_tunnel(prev_x, new_x, new_y, &mut map);
+            let dx: i32 = game.rngs.combat.gen_range(-1, 2);
+            let dy: i32 = game.rngs.combat.gen_range(-1, 2);
+            let name = &objects[id].name;
+            game.trace.record(|| format!("roll confused {} steps {} {}", name, dx, dy));
+            println!("dx, dy = {}, {}", dx, dy);
+            let (tx, ty) = (objects[id].x + dx, objects[id].y + dy);
+            // Confused monsters lash out at anyone, friend or foe
+            let reachable = game.settings.movement.allows(dx, dy);
+            let target = (0..objects.len()).find(|&target| {
+                target != id
+                    && reachable
+                    && objects[target].pos() == (tx, ty)
+                    && objects[target].fighter.is_some()
+            });
+            match target {
+                Some(target) => attack_target(id, target, game, objects),
+                None => {
+                    move_by(id, dx, dy, game, objects);
+                }
             }
-            prev_x = new_x;
-            prev_y = new_y;
-            rooms.push(room_rect)
+        }
+        None => {
+            println!("No AI!!!");
         }
     }
-
-    map
 }
 
-//
-// primary game stuff
-//
-const SCREEN_WIDTH: i32 = 80;
-const SCREEN_HEIGHT: i32 = 50;
-const LIMIT_FPS: i32 = 20;
-
-fn show_list<U: AsRef<str>>(tcod: &mut Tcod, items: &[U]) -> Option<usize> {
-    let (w, h) = (SCREEN_WIDTH / 2, SCREEN_HEIGHT / 2);
-    let mut dialog = Offscreen::new(w, h);
+// Temporary conditions on an object, counted down once per turn
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StatusEffect {
+    // Fighting for the player until the charm wears off, then for `faction` again
+    Charmed { turns: i32, faction: Faction },
+    // Hits harder, from praying at a shrine
+    Blessed { turns: i32 },
+    // Twice as fast, or half as fast, see Object::speed
+    Hasted { turns: i32 },
+    Slowed { turns: i32 },
+}
 
-    dialog.print_frame(0, 0, w, h, false, BackgroundFlag::Screen, Some("Inventory"));
-    for i in 0..items.len() {
-        let key = (b'a' + i as u8) as char;
-        dialog.print(1, i as i32 + 1, format!("{}: {}", key, items[i].as_ref()));
-    }
+// Spells and other special moves monsters can use every so often
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Ability {
+    // Heal the most wounded friend nearby
+    HealAlly,
+    // Ranged magic attack on the nearest visible enemy
+    Bolt,
+}
 
-    blit(&dialog, (0, 0), (w, h), &mut tcod.root, (w / 2, h / 2), 1.0, 1.0);
-    tcod.root.flush();
-    let key = tcod.root.wait_for_keypress(true);
-    if key.printable >= 'a' {
-        let id = key.printable as usize - b'a' as usize;
-        if id < items.len() {
-            return Some(id);
+impl Ability {
+    // Turns to wait between uses
+    pub fn cooldown(self) -> u32 {
+        match self {
+            Ability::HealAlly => 6,
+            Ability::Bolt => 4,
         }
     }
-    None
 }
 
-fn cast_heal(
-    _inventory_id: usize,
-    _tcod: &mut Tcod,
-    _game: &mut Game,
-    objects: &mut [Object],
-) -> UseResult {
-    objects[PLAYER].heal(POTION_HEAL);
-    UseResult::UsedUp
+// The player's reusable spells, cast from the 'z' menu
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Spell {
+    // Blast everything around a chosen tile
+    Fireball,
+    // Strike the closest monster
+    Lightning,
+    Heal,
 }
 
-fn closest_monster(range: i32, objects: &[Object]) -> Option<usize> {
-    let mut closest_id = None;
-    let mut closest_distance = range + 1;
-    for (id, ob) in objects.iter().enumerate() {
-        // Reference code has ai.is_some, but I'm not sure why.
-        // Possibly, it's to guarantee that the confusion spell can
-        // safely dereferen
-        if id != PLAYER && ob.fighter.is_some() {
-            let dist = ob.grid_distance_to(&objects[PLAYER]);
-            if dist < closest_distance {
-                closest_distance = dist;
-                closest_id = Some(id);
-            }
+impl Spell {
+    pub fn name(self) -> &'static str {
+        match self {
+            Spell::Fireball => "fireball",
+            Spell::Lightning => "lightning",
+            Spell::Heal => "heal",
         }
     }
-    closest_id
-}
 
-fn cast_lightning(
-    _inventory_id: usize,
-    _tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut [Object],
-) -> UseResult {
-    let monster_id = closest_monster(LIGHTNING_RANGE, objects);
-    if let Some(id) = monster_id {
-        game.messages
-            .add(format!("A lightning bolt strikes the {}!", objects[id].name), colors::LIGHT_BLUE);
-        objects[id].take_damage(LIGHTNING_DAMAGE, game);
-        UseResult::UsedUp
-    } else {
-        game.messages.add("No monsters in range", colors::RED);
-        UseResult::Cancelled
+    pub fn mana_cost(self) -> i32 {
+        match self {
+            Spell::Fireball => 12,
+            Spell::Lightning => 8,
+            Spell::Heal => 6,
+        }
     }
-}
 
-fn cast_confusion(
-    _inventory_id: usize,
-    _tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut [Object],
-) -> UseResult {
-    let monster_id = closest_monster(LIGHTNING_RANGE, objects);
-    if let Some(id) = monster_id {
-        game.messages
-            .add(format!("The {} begins acting strangely!", objects[id].name), colors::LIGHT_BLUE);
-        objects[id].ai = Some(Ai::Confused {
-            previous: Box::new(objects[id].ai.clone().unwrap()),
-            turns: CONFUSION_TURNS,
-        });
-        UseResult::UsedUp
-    } else {
-        game.messages.add("No monsters in range", colors::RED);
-        UseResult::Cancelled
+    // Turns to wait between casts
+    pub fn cooldown(self) -> u32 {
+        match self {
+            Spell::Fireball => 8,
+            Spell::Lightning => 5,
+            Spell::Heal => 6,
+        }
     }
 }
 
-fn use_item(
-    inventory_id: usize,
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut [Object],
-) -> UseResult {
-    if let Some(item) = game.inventory[inventory_id].item {
-        let on_use = match item {
-            Item::Heal => cast_heal,
-            Item::Lightning => cast_lightning,
-            Item::Confusion => cast_confusion,
-        };
-        match on_use(inventory_id, tcod, game, objects) {
-            UseResult::UsedUp => {
-                // swap_remove is more efficient, but would re-order things,
-                // which might surprise the player
-                game.inventory.remove(inventory_id);
-                UseResult::UsedUp
-            }
-            UseResult::Cancelled => UseResult::Cancelled,
+// How aware a monster is of the player.  Noticed and LostTrack only last a
+// turn, and are shown as a '!' or '?' above the monster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AlertState {
+    Unaware,
+    Noticed,
+    Hunting,
+    LostTrack,
+    Searching,
+}
+
+impl AlertState {
+    // Whether a monster in this state spots the player this turn.  Monsters
+    // which already know about the player keep seeing them whenever they're in
+    // view, but an unaware one might miss a sneaking player.
+    pub fn notices_player<R: Rng>(
+        self,
+        in_view: bool,
+        distance: i32,
+        sneaking: bool,
+        rng: &mut R,
+    ) -> bool {
+        if !in_view {
+            return false;
         }
-    } else {
-        game.messages.add("Not sure what to do with this", colors::YELLOW);
-        UseResult::Cancelled
+        if !sneaking || self != AlertState::Unaware {
+            return true;
+        }
+        distance <= SNEAK_DETECTION_RADIUS && rng.gen::<f32>() < SNEAK_NOTICE_CHANCE
     }
-}
 
-fn show_inventory(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
-    let inv_names: Vec<String> = game.inventory.iter().map(|ob| ob.name.clone()).collect();
-    match show_list(tcod, &inv_names) {
-        Some(id) => match use_item(id, tcod, game, objects) {
-            UseResult::UsedUp => PlayerAction::TookTurn,
-            UseResult::Cancelled => PlayerAction::DidntTakeTurn,
-        },
-        None => {
-            game.messages.add("cancelled", colors::WHITE);
-            PlayerAction::DidntTakeTurn
+    pub fn next(self, can_see_player: bool) -> AlertState {
+        use AlertState::*;
+        match (self, can_see_player) {
+            (Unaware, false) => Unaware,
+            (Unaware, true) | (LostTrack, true) | (Searching, true) => Noticed,
+            (Noticed, true) | (Hunting, true) => Hunting,
+            (Noticed, false) | (Hunting, false) => LostTrack,
+            (LostTrack, false) | (Searching, false) => Searching,
         }
     }
-}
+
+    pub fn indicator(self) -> Option<char> {
+        match self {
+            AlertState::Noticed => Some('!'),
+            AlertState::LostTrack => Some('?'),
+            _ => None,
+        }
+    }
+}
+
+// What happens when the player bumps into something that isn't an enemy
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InteractKind {
+    // Show an entry from LORE
+    Lore(usize),
+    // Say one of these lines
+    Talk(&'static [&'static str]),
+    // Heal or bless the player, once per game
+    Shrine { used: bool },
+    // Sell the player things
+    Shop,
+}
+
+// Something the player can wield.  Only one weapon can be equipped at a time.
+#[derive(Clone, Debug, PartialEq)]
+struct Equipment {
+    equipped: bool,
+    slot: Slot,
+    // Takes the off hand too, so there's no holding a shield with it
+    two_handed: bool,
+    attack_bonus: i32,
+    defense_bonus: i32,
+    // Chance of stopping a blow outright
+    block_chance: f32,
+    // Scrolls of enchantment read on it, already counted in attack_bonus
+    enchantment: i32,
+    // Also hits an enemy on the tile beyond the target
+    reach: bool,
+    // Also hits enemies on the tiles either side of the target
+    cleave: bool,
+    // Breaks through any wall in one blow
+    digs: bool,
+    affixes: Vec<Affix>,
+}
+
+impl Equipment {
+    pub fn has(&self, affix: Affix) -> bool {
+        self.affixes.contains(&affix)
+    }
+
+    // Whether it stops `other` being used alongside it.  Rings fit under
+    // anything held, two-handed or not.
+    pub fn conflicts_with(&self, other: &Equipment) -> bool {
+        let in_hands = |e: &Equipment| e.slot == Slot::MainHand || e.slot == Slot::OffHand;
+        self.slot == other.slot
+            || (in_hands(self) && in_hands(other) && (self.two_handed || other.two_handed))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Slot {
+    // Weapons, whose affixes are the ones that count
+    MainHand,
+    // Shields
+    OffHand,
+    // Rings, one on each hand.  A ring goes on whichever hand is free, see
+    // toggle_equipment.
+    LeftRing,
+    RightRing,
+    // Pendants
+    Neck,
+}
+
+impl Slot {
+    pub fn is_ring(self) -> bool {
+        self == Slot::LeftRing || self == Slot::RightRing
+    }
+
+    // Shown after the name of whatever is equipped there
+    pub fn worn_label(self) -> &'static str {
+        match self {
+            Slot::MainHand | Slot::OffHand => "in hand",
+            Slot::LeftRing => "on left hand",
+            Slot::RightRing => "on right hand",
+            Slot::Neck => "around neck",
+        }
+    }
+}
+
+// Ways of measuring range.  Movement is Chebyshev (diagonal steps are as long as
+// straight ones), which makes a range a square.  Euclidean makes it a circle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Metric {
+    Chebyshev,
+    Manhattan,
+    Euclidean,
+}
+
+impl Metric {
+    pub fn distance(self, dx: i32, dy: i32) -> f32 {
+        let (dx, dy) = (dx.abs(), dy.abs());
+        match self {
+            Metric::Chebyshev => max(dx, dy) as f32,
+            Metric::Manhattan => (dx + dy) as f32,
+            Metric::Euclidean => ((dx * dx + dy * dy) as f32).sqrt(),
+        }
+    }
+}
+
+// Whether anything can step diagonally.  With FourWay, every range measured
+// with grid_distance becomes a diamond rather than a square: reach, bolt and
+// reanimation range, splash radius, sneak detection and how close allies keep.
+// Field of view and thrown or cast ranges stay round.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MovementMode {
+    EightWay,
+    FourWay,
+}
+
+impl MovementMode {
+    pub fn metric(self) -> Metric {
+        match self {
+            MovementMode::EightWay => Metric::Chebyshev,
+            MovementMode::FourWay => Metric::Manhattan,
+        }
+    }
+
+    pub fn allows(self, dx: i32, dy: i32) -> bool {
+        self == MovementMode::EightWay || dx == 0 || dy == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Faction {
+    // The player and their allies
+    Player,
+    // Orcs, trolls and the rest of the living
+    Monster,
+    // Skeletons and zombies, and those who raise them
+    Undead,
+    Neutral,
+}
+
+impl Faction {
+    // Every side but the neutral one is at war with the other two, so monsters
+    // of different factions fight each other as well as the player
+    pub fn is_hostile_to(self, other: Faction) -> bool {
+        self != other && self != Faction::Neutral && other != Faction::Neutral
+    }
+
+    pub fn is_monster(self) -> bool {
+        self == Faction::Monster || self == Faction::Undead
+    }
+}
+
+#[derive(Debug)]
+struct Object {
+    // Properties
+    x: i32,
+    y: i32,
+    char: char,
+    color: Color,
+    name: String,
+
+    // Turns to skip, e.g. while wading through water
+    lost_turns: i32,
+    faction: Faction,
+    status_effects: Vec<StatusEffect>,
+    abilities: Vec<Ability>,
+    // Turns left before each ability can be used again
+    cooldowns: HashMap<Ability, u32>,
+    alert_state: AlertState,
+    // Turns spent waiting behind an ally, see move_towards
+    queued_turns: u32,
+    // Monsters with the same group all wake together, see broadcast_alerts
+    group_id: Option<u32>,
+    // The way it last moved or attacked
+    facing: Direction,
+    // Saved up towards its next action, see Object::speed
+    energy: i32,
+    // Can't be struck from behind
+    watchful: bool,
+
+    // Components
+    fighter: Option<Fighter>,
+    ai: Option<Ai>,
+    item: Option<Item>,
+    equipment: Option<Equipment>,
+    on_interact: Option<InteractKind>,
+    // HP left for things like barrels, which break rather than die
+    destructible: Option<i32>,
+    rarity: Rarity,
+    // Where the player last saw this, for things that stay drawn out of sight
+    remembered_at: Option<(i32, i32)>,
+
+    // Flags (which are also kind of properties)
+    is_walkable: bool,
+    is_alive: bool,
+    was_seen: bool,
+    stairs: Option<Stairs>,
+    can_swim: bool,
+}
+
+impl Object {
+    pub fn new(x: i32, y: i32, char: char, name: &str, color: Color) -> Self {
+        Object {
+            // Properties
+            x,
+            y,
+            char,
+            color,
+            name: name.to_string(),
+            lost_turns: 0,
+            faction: Faction::Neutral,
+            status_effects: vec![],
+            abilities: vec![],
+            cooldowns: HashMap::new(),
+            alert_state: AlertState::Unaware,
+            queued_turns: 0,
+            group_id: None,
+            facing: Direction::South,
+            energy: 0,
+            watchful: false,
+
+            // Components
+            fighter: None,
+            ai: None,
+            item: None,
+            equipment: None,
+            on_interact: None,
+            destructible: None,
+            rarity: Rarity::Common,
+            remembered_at: None,
+
+            // Flags (which are also kind of properties)
+            is_walkable: false,
+            is_alive: true,
+            was_seen: false,
+            stairs: None,
+            can_swim: false,
+        }
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    pub fn set_pos(&mut self, x: i32, y: i32) {
+        self.x = x;
+        self.y = y;
+    }
+
+    pub fn take_damage(&mut self, damage: i32, game: &mut Game) {
+        if damage <= 0 || (self.is_player() && game.invulnerable()) {
+            return;
+        }
+        if let Some(fighter) = self.fighter.as_mut() {
+            if damage >= fighter.hp {
+                fighter.hp = 0;
+                self.is_alive = false;
+                fighter.on_death.callback(self, game);
+            } else {
+                fighter.hp -= damage;
+            }
+            // The rest of the group comes to see what's going on
+            if let Some(group) = self.group_id.filter(|_| self.faction.is_monster()) {
+                game.alerted_groups.push(group);
+            }
+        }
+    }
+
+    pub fn heal(&mut self, healing: i32, game: &Game) {
+        if healing <= 0 {
+            return;
+        }
+        let max_hp = self.max_hp(game);
+        if let Some(fighter) = self.fighter.as_mut() {
+            fighter.hp = min(max_hp, fighter.hp + healing);
+        }
+    }
+
+    // After losing a max HP bonus
+    pub fn clamp_hp(&mut self, game: &Game) {
+        let max_hp = self.max_hp(game);
+        if let Some(fighter) = self.fighter.as_mut() {
+            fighter.hp = min(max_hp, fighter.hp);
+        }
+    }
+
+    // How messages refer to it, e.g. "the orc", or "you" for the player
+    pub fn the_name(&self) -> String {
+        if self.is_player() {
+            "you".to_string()
+        } else {
+            format!("the {}", self.name)
+        }
+    }
+
+    pub fn is_player(&self) -> bool {
+        self.fighter.is_some_and(|f| f.on_death == DeathCallback::Player)
+    }
+
+    // Heaviest load the object can carry without slowing down
+    pub fn max_weight(&self) -> i32 {
+        CARRY_BASE + CARRY_PER_ATTACK * self.fighter.map_or(0, |f| f.attack)
+    }
+
+    // Attack including the bonus from the player's weapon and any blessing
+    pub fn power(&self, game: &Game) -> i32 {
+        let mut base = self.fighter.map_or(0, |f| f.attack);
+        if self.status_effects.iter().any(|effect| matches!(effect, StatusEffect::Blessed { .. })) {
+            base += BLESSING_ATTACK;
+        }
+        if self.is_player() {
+            let bonus: i32 = equipped(game).map(|equipment| equipment.attack_bonus).sum();
+            base + bonus + KEEN_ATTACK * equipped_affixes(Affix::Keen, game) as i32
+        } else {
+            base
+        }
+    }
+
+    // Defense including the player's equipment and its affixes
+    pub fn defense(&self, game: &Game) -> i32 {
+        let base = self.fighter.map_or(0, |f| f.defense);
+        if self.is_player() {
+            let bonus: i32 = equipped(game).map(|equipment| equipment.defense_bonus).sum();
+            base + bonus + TURTLE_DEFENSE * equipped_affixes(Affix::Turtle, game) as i32
+        } else {
+            base
+        }
+    }
+
+    // Max HP including the player's equipment affixes
+    pub fn max_hp(&self, game: &Game) -> i32 {
+        let base = self.fighter.map_or(0, |f| f.max_hp);
+        if self.is_player() {
+            base + BEAR_HP * equipped_affixes(Affix::Bear, game) as i32
+        } else {
+            base
+        }
+    }
+
+    // Turns per point of natural healing, including the player's equipment
+    // affixes.  Each troll affix divides it again.
+    pub fn regen_rate(&self, game: &Game) -> u32 {
+        let base = self.fighter.map_or(0, |f| f.regen_rate);
+        let trolls = if self.is_player() { equipped_affixes(Affix::Troll, game) } else { 0 };
+        if base == 0 || trolls == 0 {
+            base
+        } else {
+            max(1, base / TROLL_REGEN_FACTOR.pow(trolls as u32))
+        }
+    }
+
+    pub fn attack(&mut self, other: &mut Object, game: &mut Game) {
+        let (power, defense) = (self.power(game), other.defense(game));
+        let mut damage = power - defense;
+        let mut multiplier = 1;
+        let mut bonus = 0;
+        let (dx, dy) = (self.x - other.x, self.y - other.y);
+        if let Some(facing) = Direction::towards(-dx, -dy) {
+            self.facing = facing;
+        }
+        // Striking at something which never saw the player coming.  Monsters
+        // which have lost track of the player are still on their guard.
+        if damage > 0
+            && self.fighter.is_some_and(|f| f.on_death == DeathCallback::Player)
+            && other.alert_state == AlertState::Unaware
+        {
+            multiplier = game.settings.sneak_attack_multiplier;
+            damage *= multiplier;
+            game.messages.add_cat("Sneak attack!", colors::LIGHT_BLUE, MsgCategory::Combat);
+        } else if damage > 0 && !other.watchful && other.facing.is_behind(dx, dy) {
+            bonus = game.settings.back_attack_bonus;
+            damage += bonus;
+            let message = format!(
+                "{} {} struck from behind!",
+                capitalized(&other.the_name()),
+                if other.is_player() { "are" } else { "is" }
+            );
+            game.messages.add_cat(message, colors::LIGHT_BLUE, MsgCategory::Combat);
+        }
+        if damage > 0 && other.is_player() && player_blocks(game) {
+            // Which the combat trace shows as a multiplier of 0
+            multiplier = 0;
+            bonus = 0;
+            damage = 0;
+        }
+        if self.is_player() && game.kills_in_one_hit() {
+            damage = max(damage, other.fighter.map_or(0, |f| f.hp));
+        }
+        let verb = if self.is_player() { "attack" } else { "attacks" };
+        let (attacker, target) = (capitalized(&self.the_name()), other.the_name());
+        let message = if damage > 0 {
+            format!("{} {} {} for {} damage", attacker, verb, target, damage)
+        } else {
+            format!("{} {} {} but it has no effect!", attacker, verb, target)
+        };
+        game.messages.add_cat(message.clone(), colors::WHITE, MsgCategory::Combat);
+        game.add_recent_combat(message);
+        let hp_before = other.fighter.map_or(0, |f| f.hp);
+        other.take_damage(damage, game);
+        let hp_after = other.fighter.map_or(0, |f| f.hp);
+        game.trace.record(|| {
+            format!(
+                "attack {} -> {}: power {} defense {} multiplier {} bonus {} damage {} hp {} -> {}",
+                self.name,
+                other.name,
+                power,
+                defense,
+                multiplier,
+                bonus,
+                damage,
+                hp_before,
+                hp_after
+            )
+        });
+        // Nobody sleeps through being hit
+        if other.alert_state == AlertState::Unaware {
+            other.alert_state = AlertState::Noticed;
+            other.was_seen = true;
+        }
+        if !other.is_alive && other.fighter.is_some_and(|f| f.on_death == DeathCallback::Player) {
+            game.killed_by = Some(self.name.clone());
+        }
+    }
+
+    // Energy gained each round.  Haste and slowness together cancel out.
+    pub fn speed(&self) -> i32 {
+        let has = |matches: fn(&StatusEffect) -> bool| self.status_effects.iter().any(matches);
+        let hasted = has(|effect| matches!(effect, StatusEffect::Hasted { .. }));
+        let slowed = has(|effect| matches!(effect, StatusEffect::Slowed { .. }));
+        match (hasted, slowed) {
+            (true, false) => NORMAL_SPEED * 2,
+            (false, true) => NORMAL_SPEED / 2,
+            _ => NORMAL_SPEED,
+        }
+    }
+
+    // "hasted" or "slowed", for anything not at normal speed
+    pub fn speed_label(&self) -> Option<&'static str> {
+        match self.speed().cmp(&NORMAL_SPEED) {
+            Ordering::Greater => Some("hasted"),
+            Ordering::Less => Some("slowed"),
+            Ordering::Equal => None,
+        }
+    }
+
+    // Haste or slow it, replacing any haste or slowness of the same kind
+    pub fn set_speed_effect(&mut self, effect: StatusEffect) {
+        self.status_effects
+            .retain(|other| std::mem::discriminant(other) != std::mem::discriminant(&effect));
+        self.status_effects.push(effect);
+    }
+
+    pub fn draw(&self, con: &mut dyn Console) {
+        let color = match self.speed().cmp(&NORMAL_SPEED) {
+            Ordering::Greater => colors::lerp(self.display_color(), HASTE_COLOR, SPEED_TINT),
+            Ordering::Less => colors::lerp(self.display_color(), SLOW_COLOR, SPEED_TINT),
+            Ordering::Equal => self.display_color(),
+        };
+        con.set_default_foreground(color);
+        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+    }
+
+    // Anything better than common shows its rarity
+    pub fn display_color(&self) -> Color {
+        if self.rarity == Rarity::Common {
+            self.color
+        } else {
+            self.rarity.color()
+        }
+    }
+
+    // Chebyshev distance, i.e. the number of steps it takes to get there
+    fn grid_distance_to(&self, other: &Object, movement: MovementMode) -> i32 {
+        self.grid_distance(other.x, other.y, movement)
+    }
+
+    fn distance_to(&self, other: &Object, metric: Metric) -> f32 {
+        self.distance(other.x, other.y, metric)
+    }
+
+    fn distance(&self, x: i32, y: i32, metric: Metric) -> f32 {
+        metric.distance(x - self.x, y - self.y)
+    }
+
+    // Number of steps to (x, y), ignoring anything in the way
+    fn grid_distance(&self, x: i32, y: i32, movement: MovementMode) -> i32 {
+        self.distance(x, y, movement.metric()) as i32
+    }
+
+    pub fn clear(&self, con: &mut dyn Console) {
+        con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
+    }
+}
+
+//
+// map-related stuff
+//
+const MAP_WIDTH: i32 = 80;
+const MAP_HEIGHT: i32 = 43;
+
+const MAX_ROOMS: i32 = 30;
+const MAX_ROOM_WIDTH: i32 = 15;
+const MIN_ROOM_WIDTH: i32 = 6;
+const MAX_ROOM_HEIGHT: i32 = 10;
+const MIN_ROOM_HEIGHT: i32 = 5;
+// Most monsters and items in a room with ROOM_DENSITY_AREA floor tiles, at the
+// default density.  Bigger rooms get more, smaller ones fewer.
+const MAX_ROOM_MONSTERS: i32 = 3;
+const MAX_ROOM_ITEMS: i32 = 1;
+const ROOM_DENSITY_AREA: i32 = 50;
+const VAULT_CHANCE: f32 = 0.2;
+// Random spots tried for trap doors on each level, those which land on floor get one
+const TRAP_DOOR_TRIES: i32 = 4;
+// The same for spike and dart traps, and how many of those are darts
+const TRAP_TRIES: i32 = 6;
+const DART_TRAP_CHANCE: f32 = 0.4;
+const FALL_DAMAGE: i32 = 5;
+// Rooms with at least this much floor inside sometimes get rows of pillars
+const PILLAR_MIN_AREA: i32 = 50;
+const PILLAR_CHANCE: f32 = 0.4;
+const PILLAR_SPACING: i32 = 3;
+// How often each shape of room is carved in the tunnels
+const ROOM_SHAPES: &[(RoomShape, u32)] = &[
+    (RoomShape::Rectangle, 55),
+    (RoomShape::Circle, 15),
+    (RoomShape::Cross, 15),
+    (RoomShape::Cavern, 15),
+];
+const VAULT_DIR: &str = "vaults";
+const MIN_GOLD: i32 = 5;
+const MAX_GOLD: i32 = 25;
+
+const CAVE_WALL_CHANCE: f32 = 0.45;
+const CAVE_SMOOTHING_STEPS: i32 = 5;
+const MIN_CAVE_SIZE: usize = 400;
+const CAVE_TILES_PER_MONSTER: usize = 60;
+const CAVE_TILES_PER_ITEM: usize = 150;
+
+const POOL_CHANCE: f32 = 0.3;
+const MAX_POOL_RADIUS: i32 = 2;
+const CAVE_TILES_PER_POOL: usize = 250;
+
+const CRYPT_HALL_HEIGHT: i32 = 8;
+const CRACKED_WALL_GLYPH: char = '=';
+const CRYPT_NICHE_SIZE: i32 = 4;
+
+// sizes and coordinates relevant for the GUI
+const BAR_WIDTH: i32 = 20;
+// Default for --panel-height.  The stats left of the messages take up this
+// many rows, so the panel can't be any shorter.
+const PANEL_HEIGHT: i32 = 7;
+const MAX_PANEL_HEIGHT: i32 = 20;
+// Messages from earlier turns are drawn darker than new ones
+const OLD_MSG_BRIGHTNESS: f32 = 0.6;
+
+const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
+const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
+const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
+const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
+const COLOR_DARK_WATER: Color = Color { r: 0, g: 30, b: 90 };
+const COLOR_LIGHT_WATER: Color = Color { r: 40, g: 100, b: 220 };
+const COLOR_DARK_DEEP_WATER: Color = Color { r: 0, g: 10, b: 60 };
+const COLOR_LIGHT_DEEP_WATER: Color = Color { r: 20, g: 50, b: 160 };
+const COLOR_DARK_LAVA: Color = Color { r: 90, g: 20, b: 0 };
+const COLOR_LIGHT_LAVA: Color = Color { r: 255, g: 90, b: 0 };
+// How far remembered objects out of sight fade towards black
+const REMEMBERED_DIMMING: f32 = 0.5;
+// The recorded player replayed with --ghost
+const GHOST_COLOR: Color = Color { r: 90, g: 110, b: 140 };
+// The player's fading trail, see Game::recent_path.  A fresh footstep moves a
+// lit tile's color this far towards FOOTSTEP_COLOR, and it fades out over
+// FOOTSTEP_TURNS turns.
+const FOOTSTEP_COLOR: Color = Color { r: 60, g: 50, b: 40 };
+const FOOTSTEP_TINT: f32 = 0.15;
+const FOOTSTEP_TURNS: u32 = 6;
+// Tiles the player's weapon can hit, see render_threat_overlay
+const THREAT_COLOR: Color = Color { r: 200, g: 40, b: 40 };
+const THREAT_TINT: f32 = 0.25;
+
+const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
+const FOV_LIGHT_WALLS: bool = true;
+const TORCH_RADIUS: i32 = 10;
+// Patches of magical darkness, where the torch only reaches a part of the way,
+// and of bright light, seen from any distance.  See place_light_zones.
+const LIGHT_ZONE_TRIES: i32 = 3;
+const LIGHT_ZONE_RADIUS: (i32, i32) = (3, 7);
+const DARK_ZONE_CHANCE: f32 = 0.6;
+const DARKNESS_DIVISOR: i32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LightLevel {
+    Normal,
+    // The torch lights only a fraction of its usual distance here
+    Dark,
+    // Visible from anywhere in sight, torch or not
+    Bright,
+}
+
+impl LightLevel {
+    // How far away a torch of this radius shows the tile.  None if it doesn't
+    // matter how far.
+    pub fn reach(self, torch_radius: i32) -> Option<i32> {
+        match self {
+            LightLevel::Normal => Some(torch_radius),
+            LightLevel::Dark => Some(max(torch_radius / DARKNESS_DIVISOR, 1)),
+            LightLevel::Bright => None,
+        }
+    }
+}
+
+fn light_blend(
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+    close: Color,
+    far: Color,
+    max_radius: f32,
+) -> Color {
+    let dx = (max(x1, x2) - min(x1, x2)) as f32;
+    let dy = (max(y1, y2) - min(y1, y2)) as f32;
+
+    let f = (dx * dx + dy * dy) / (max_radius * max_radius);
+    // adjacent squares (f ~= 0) should be the close color
+    // squares at maximum visible distance (f ~= 1) should be the far color
+    close * (1. - f) + far * f
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        ((self.x1 + self.x2) / 2, (self.y1 + self.y2) / 2)
+    }
+
+    pub fn intersects_with(&self, other: &Rect) -> bool {
+        self.x1 <= other.x2 && self.x2 >= other.x1 && self.y1 <= other.y2 && self.y2 >= other.y1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TileKind {
+    Floor,
+    Wall,
+    Water { deep: bool }, // Slows down anything wading through it, deep water needs swimming
+    Lava,                 // Burns any fighter standing in it
+    Chasm,                // Drops the player to the next level
+    TrapDoor { hidden: bool }, // Like a chasm, but looks like floor until it opens
+    // Looks like floor until it's sprung.  Sprung traps which reset count
+    // rearm_in down to 0 and arm again, see rearm_traps.
+    Trap { kind: TrapKind, hidden: bool, armed: bool, rearm_in: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TrapKind {
+    Spikes,
+    Dart,
+}
+
+impl TrapKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TrapKind::Spikes => "spike trap",
+            TrapKind::Dart => "dart trap",
+        }
+    }
+
+    pub fn damage(self) -> i32 {
+        match self {
+            TrapKind::Spikes => SPIKE_TRAP_DAMAGE,
+            TrapKind::Dart => DART_TRAP_DAMAGE,
+        }
+    }
+
+    // How long after springing it's ready again, or None if it only goes off once
+    pub fn rearm_turns(self) -> Option<u32> {
+        match self {
+            TrapKind::Spikes => Some(SPIKE_TRAP_REARM_TURNS),
+            TrapKind::Dart => None,
+        }
+    }
+
+    pub fn message(self, victim: &str) -> String {
+        match self {
+            TrapKind::Spikes => format!("Spikes shoot up under {}!", victim),
+            TrapKind::Dart => format!("A hidden dart strikes {}!", victim),
+        }
+    }
+}
+
+impl TileKind {
+    pub fn glyph(self) -> char {
+        use TileKind::*;
+        match self {
+            Floor => '.',
+            Wall => '#',
+            Water { .. } | Lava => '~',
+            Chasm => ' ',
+            TrapDoor { hidden: true } | Trap { hidden: true, .. } => '.',
+            TrapDoor { hidden: false } | Trap { hidden: false, .. } => '^',
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use TileKind::*;
+        match self {
+            Floor => "floor",
+            Wall => "wall",
+            Water { deep: false } => "shallow water",
+            Water { deep: true } => "deep water",
+            Lava => "lava",
+            Chasm => "chasm",
+            TrapDoor { hidden: true } | Trap { hidden: true, .. } => "floor",
+            TrapDoor { hidden: false } => "trap door",
+            Trap { armed: false, .. } => "sprung trap",
+            Trap { kind, .. } => kind.name(),
+        }
+    }
+
+    // Lit and remembered background colors
+    pub fn colors(self, theme: &LevelTheme) -> (Color, Color) {
+        use TileKind::*;
+        match self {
+            Floor | TrapDoor { .. } | Trap { .. } => (theme.light_ground, theme.dark_ground),
+            Wall => (theme.light_wall, theme.dark_wall),
+            Water { deep: false } => (COLOR_LIGHT_WATER, COLOR_DARK_WATER),
+            Water { deep: true } => (COLOR_LIGHT_DEEP_WATER, COLOR_DARK_DEEP_WATER),
+            Lava => (COLOR_LIGHT_LAVA, COLOR_DARK_LAVA),
+            Chasm => (colors::BLACK, colors::BLACK),
+        }
+    }
+
+    // Monsters know better than to step onto these, and the player avoids them
+    // once they know they're there
+    pub fn is_hazard(self) -> bool {
+        matches!(self, TileKind::Lava | TileKind::Chasm | TileKind::TrapDoor { hidden: false })
+    }
+
+    // Hazards, and the traps the player has found.  Monsters don't know where
+    // the traps are, so the player can lure them in.
+    pub fn avoided_by_player(self) -> bool {
+        self.is_hazard() || matches!(self, TileKind::Trap { hidden: false, armed: true, .. })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    kind: TileKind,
+    // Derived from the kind, but used everywhere
+    is_walkable: bool,
+    is_transparent: bool,
+    explored: bool,
+    // HP left before a cracked wall gives way, see smash
+    destructible: Option<i32>,
+    light: LightLevel,
+}
+
+impl Tile {
+    pub fn empty() -> Self {
+        Tile::new(TileKind::Floor)
+    }
+
+    pub fn wall() -> Self {
+        Tile::new(TileKind::Wall)
+    }
+
+    pub fn new(kind: TileKind) -> Self {
+        let solid = kind == TileKind::Wall;
+        Tile {
+            kind,
+            is_walkable: !solid,
+            is_transparent: !solid,
+            explored: false,
+            destructible: None,
+            light: LightLevel::Normal,
+        }
+    }
+
+    pub fn cracked_wall() -> Self {
+        Tile { destructible: Some(CRACKED_WALL_HP), ..Tile::wall() }
+    }
+}
+
+type Map = Vec<Vec<Tile>>;
+type MapSlice = [Vec<Tile>];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MsgCategory {
+    Combat,
+    Item,
+    System,
+    Lore,
+}
+
+impl MsgCategory {
+    pub fn name(self) -> &'static str {
+        match self {
+            MsgCategory::Combat => "Combat",
+            MsgCategory::Item => "Items",
+            MsgCategory::System => "System",
+            MsgCategory::Lore => "Lore",
+        }
+    }
+}
+
+const MSG_CATEGORIES: [MsgCategory; 4] =
+    [MsgCategory::Combat, MsgCategory::Item, MsgCategory::System, MsgCategory::Lore];
+
+struct Message {
+    text: String,
+    color: Color,
+    category: MsgCategory,
+    // Identical consecutive messages are stored once, with a count
+    count: u32,
+}
+
+impl Message {
+    pub fn display_text(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.text, self.count)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+struct Messages {
+    messages: Vec<Message>,
+    // Messages added since the player's last action
+    new_message_count: usize,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self { messages: vec![], new_message_count: 0 }
+    }
+
+    pub fn add<T: Into<String>>(&mut self, message: T, color: Color) {
+        self.add_cat(message, color, MsgCategory::System);
+    }
+
+    pub fn add_cat<T: Into<String>>(&mut self, message: T, color: Color, category: MsgCategory) {
+        let text = message.into();
+        if let Some(last) = self.messages.last_mut() {
+            if last.text == text && last.color == color && last.category == category {
+                last.count += 1;
+                // The repeated message counts as new again
+                self.new_message_count = self.new_message_count.max(1);
+                return;
+            }
+        }
+        self.messages.push(Message { text, color, category, count: 1 });
+        self.new_message_count += 1;
+    }
+
+    // Where the player's next action starts, for start_turn
+    pub fn mark(&self) -> (usize, (usize, u32)) {
+        (self.new_message_count, self.stamp())
+    }
+
+    // The player has taken a turn, so only what's been said since `mark` is
+    // still new.  Screens and other keys which don't take a turn leave the
+    // latest turn's messages highlighted.
+    pub fn start_turn(&mut self, mark: (usize, (usize, u32))) {
+        let (count, stamp) = mark;
+        self.new_message_count = self.new_message_count.saturating_sub(count);
+        if self.new_message_count == 0 && self.stamp() != stamp {
+            // Nothing but a repeat of the last message, which is new again
+            self.new_message_count = 1;
+        }
+    }
+
+    // Changes whenever a message is added, repeats included
+    pub fn stamp(&self) -> (usize, u32) {
+        (self.messages.len(), self.messages.last().map_or(0, |last| last.count))
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> {
+        self.messages.iter()
+    }
+}
+
+// Structure to hold game "global" data
+// (Why is the Object list not in here?)
+struct Game {
+    map: Map,
+    messages: Messages,
+    inventory: Vec<Object>,
+    gold: i32,
+    dungeon_level: u32,
+    deepest_level: u32,
+    // Master seed for the dungeon's layout
+    seed: u32,
+    rngs: Rngs,
+    trace: CombatTrace,
+    // Kinds of potion the player has drunk, and so knows by name
+    identified: Vec<Item>,
+    // Player turns taken so far, counting changes of level
+    turn: u32,
+    // Tiles the player recently left, with how many turns ago, newest first
+    recent_path: VecDeque<(i32, i32, u32)>,
+    recorder: MovementRecorder,
+    ghost: Option<Ghost>,
+    // How recently the player was on each tile, for monsters tracking them
+    scent: Vec<Vec<u16>>,
+    // Levels the player has been to, other than the current one
+    levels: HashMap<u32, Level>,
+    // The player's notes on tiles of the current level, see annotate
+    notes: HashMap<(i32, i32), String>,
+    // Objects created during the turn, see flush_pending
+    pending_spawns: Vec<Object>,
+    // For the death screen
+    recent_combat: VecDeque<String>,
+    recent_hp: VecDeque<i32>,
+    killed_by: Option<String>,
+    // Set once the player has the amulet
+    won: bool,
+    mana: i32,
+    max_mana: i32,
+    spells: Vec<Spell>,
+    // Turns until each spell can be cast again
+    spell_cooldowns: HashMap<Spell, u32>,
+    // Moving carefully, to slip past monsters which haven't noticed the player
+    sneaking: bool,
+    // Set for the duration of a careful step, which gives enemies no free attack
+    careful_step: bool,
+    // Tint the tiles the player's weapon can hit, see render_threat_overlay
+    show_threats: bool,
+    mode: GameMode,
+    deaths: u32,
+    // Where the player arrived on this level, and comes back after dying in practice mode
+    entrance: (i32, i32),
+    // Levels where every monster has been killed
+    cleared_levels: HashSet<u32>,
+    // Kills of each monster type the player has come across, by name
+    bestiary: HashMap<String, u32>,
+    auto_action: Option<AutoAction>,
+    // Player HP when the auto-action last checked, to notice them getting hurt
+    auto_hp: i32,
+    // Names of the items and features the player saw for the first time last turn
+    newly_seen: Vec<String>,
+    // Groups with a member hurt or which spotted the player this turn
+    alerted_groups: Vec<u32>,
+    vaults: Vec<Vault>,
+    settings: Settings,
+    // Debug only, see DEBUG_BUILD.  The player takes no damage at all.
+    god_mode: bool,
+    // Debug only.  Every hit the player lands kills.
+    one_hit_kills: bool,
+    // Set once either of those has been on, or the player has warped or used
+    // the debug console, so the game earns no unlocks
+    cheated: bool,
+    // Tells this run's saves from any other game's, so that dying in
+    // Permadeath can remove every one of them
+    run: u32,
+}
+
+impl Game {
+    pub fn invulnerable(&self) -> bool {
+        DEBUG_BUILD && self.god_mode
+    }
+
+    pub fn kills_in_one_hit(&self) -> bool {
+        DEBUG_BUILD && self.one_hit_kills
+    }
+
+    pub fn current_weight(&self) -> i32 {
+        self.inventory.iter().filter_map(|ob| ob.item).map(Item::weight).sum()
+    }
+
+    // What the player knows an item as.  Potions they haven't drunk one of yet
+    // go by a look, which is shuffled with the seed.
+    pub fn item_name(&self, item: &Object) -> String {
+        let potion = POTIONS.iter().position(|&potion| item.item == Some(potion));
+        match potion {
+            Some(i) if !self.identified.contains(&POTIONS[i]) => {
+                let look = (i + self.seed as usize) % POTION_APPEARANCES.len();
+                format!("{} potion", POTION_APPEARANCES[look])
+            }
+            _ => item.name.clone(),
+        }
+    }
+
+    // The player only knows what a potion does once they've drunk one like it
+    pub fn knows(&self, item: &Object) -> bool {
+        item.item.is_none_or(|item| !POTIONS.contains(&item) || self.identified.contains(&item))
+    }
+
+    pub fn add_recent_combat(&mut self, message: String) {
+        self.recent_combat.push_back(message);
+        if self.recent_combat.len() > RECENT_COMBAT_LENGTH {
+            self.recent_combat.pop_front();
+        }
+    }
+}
+
+// Options which can be changed from the command line
+struct Settings {
+    // Redraw the whole map every frame instead of only the dirty tiles
+    force_full_redraw: bool,
+    // Swimming in deep water can lose items or hurt the player
+    drowning: bool,
+    // Keep moving while a movement key is held down
+    key_repeat: bool,
+    auto_pickup: AutoPickup,
+    // Dungeon level a new game starts on.  Past the first, it counts as a warp.
+    start_level: u32,
+    // Enables cheats for testing, like warping between levels
+    debug: bool,
+    // Damage multiplier against monsters which haven't noticed the player
+    sneak_attack_multiplier: i32,
+    // Extra damage for striking anyone from behind, 0 to turn it off
+    back_attack_bonus: i32,
+    // Stepping away from an adjacent enemy gives it a free attack
+    attacks_of_opportunity: bool,
+    movement: MovementMode,
+    // Ask before bumping into a monster or walking into a known hazard
+    confirm_risky_moves: bool,
+    // Read and update META_FILE, for unlocks carried between games
+    meta: bool,
+    // Write every attack and combat roll to this file, see CombatTrace
+    combat_trace: Option<String>,
+    // Check a trace written by --combat-trace instead of playing
+    replay_combat: Option<String>,
+    // Start on a level read from this file, see load_map_ascii
+    load_map: Option<String>,
+    // Attacks can miss altogether, see fumbles
+    fumbles: bool,
+    // Pause to show each monster action the player can see
+    animate_enemy_turns: bool,
+    // Frames drawn per second.  Turns only pass on input, so this is how often
+    // the screen updates and how soon a key press is noticed, nothing more.
+    fps: i32,
+    // Record the player's moves to this file, see MovementRecorder
+    record_moves: Option<String>,
+    // Replay moves recorded with --record-moves next to the player
+    ghost: Option<String>,
+    // Tint the tiles the player just walked over, see Game::recent_path
+    footsteps: bool,
+    // Also auto-save every this many turns, or only on a change of level if 0
+    auto_save_interval: u32,
+    // Describe any items the player steps onto, as the examine key does
+    examine_on_step: bool,
+    // Pack saved games to take less room, see pack
+    compress_saves: bool,
+    // Rows for the message panel, and whether it goes above the map, see Layout
+    panel_height: i32,
+    panel_top: bool,
+    gen: GenConfig,
+}
+
+// What the player picks up just by walking over it
+#[derive(Clone, Copy, Debug)]
+struct AutoPickup {
+    gold: bool,
+    items: bool,
+}
+
+impl Settings {
+    // Everything as it is with no arguments
+    pub fn defaults() -> Self {
+        Settings {
+            force_full_redraw: false,
+            drowning: true,
+            key_repeat: false,
+            auto_pickup: AutoPickup { gold: true, items: false },
+            start_level: 1,
+            debug: false,
+            sneak_attack_multiplier: SNEAK_ATTACK_MULTIPLIER,
+            back_attack_bonus: BACK_ATTACK_BONUS,
+            attacks_of_opportunity: false,
+            movement: MovementMode::EightWay,
+            confirm_risky_moves: false,
+            meta: true,
+            combat_trace: None,
+            replay_combat: None,
+            load_map: None,
+            animate_enemy_turns: false,
+            fumbles: true,
+            fps: LIMIT_FPS,
+            record_moves: None,
+            ghost: None,
+            footsteps: true,
+            auto_save_interval: AUTO_SAVE_INTERVAL,
+            examine_on_step: false,
+            compress_saves: false,
+            panel_height: PANEL_HEIGHT,
+            panel_top: false,
+            gen: GenConfig { monster_density: 1.0, item_density: 1.0 },
+        }
+    }
+
+    pub fn from_args() -> Self {
+        let mut settings = Settings::defaults();
+        for arg in std::env::args().skip(1) {
+            match arg.as_str() {
+                "--force-full-redraw" => settings.force_full_redraw = true,
+                "--no-drowning" => settings.drowning = false,
+                "--key-repeat" => settings.key_repeat = true,
+                "--no-auto-pickup-gold" => settings.auto_pickup.gold = false,
+                "--auto-pickup-items" => settings.auto_pickup.items = true,
+                "--debug" => settings.debug = true,
+                "--no-meta" => settings.meta = false,
+                "--attacks-of-opportunity" => settings.attacks_of_opportunity = true,
+                "--four-way" => settings.movement = MovementMode::FourWay,
+                "--confirm-risky-moves" => settings.confirm_risky_moves = true,
+                "--animate-enemy-turns" => settings.animate_enemy_turns = true,
+                "--no-fumbles" => settings.fumbles = false,
+                "--no-footsteps" => settings.footsteps = false,
+                "--panel-top" => settings.panel_top = true,
+                "--examine-on-step" => settings.examine_on_step = true,
+                "--compress-saves" => settings.compress_saves = true,
+                _ if arg.starts_with("--combat-trace=") => {
+                    settings.combat_trace = Some(arg["--combat-trace=".len()..].to_string())
+                }
+                _ if arg.starts_with("--replay-combat=") => {
+                    settings.replay_combat = Some(arg["--replay-combat=".len()..].to_string())
+                }
+                _ if arg.starts_with("--fps=") => match arg["--fps=".len()..].parse() {
+                    Ok(fps) if fps >= MIN_FPS => settings.fps = fps,
+                    _ => println!("Ignoring bad frame rate in {}, the least is {}", arg, MIN_FPS),
+                },
+                _ if arg.starts_with("--panel-height=") => {
+                    match arg["--panel-height=".len()..].parse() {
+                        Ok(rows) if (PANEL_HEIGHT..=MAX_PANEL_HEIGHT).contains(&rows) => {
+                            settings.panel_height = rows
+                        }
+                        _ => println!(
+                            "Ignoring bad panel height in {}, it must be {} to {}",
+                            arg, PANEL_HEIGHT, MAX_PANEL_HEIGHT
+                        ),
+                    }
+                }
+                _ if arg.starts_with("--record-moves=") => {
+                    settings.record_moves = Some(arg["--record-moves=".len()..].to_string())
+                }
+                _ if arg.starts_with("--ghost=") => {
+                    settings.ghost = Some(arg["--ghost=".len()..].to_string())
+                }
+                _ if arg.starts_with("--auto-save-interval=") => {
+                    match arg["--auto-save-interval=".len()..].parse() {
+                        Ok(turns) => settings.auto_save_interval = turns,
+                        Err(_) => println!("Ignoring bad auto-save interval in {}", arg),
+                    }
+                }
+                _ if arg.starts_with("--load-map=") => {
+                    settings.load_map = Some(arg["--load-map=".len()..].to_string())
+                }
+                _ if arg.starts_with("--start-level=") => {
+                    match arg["--start-level=".len()..].parse() {
+                        Ok(level) => settings.start_level = level,
+                        Err(_) => println!("Ignoring bad starting level in {}", arg),
+                    }
+                }
+                _ if arg.starts_with("--monster-density=") => {
+                    match arg["--monster-density=".len()..].parse() {
+                        Ok(density) => settings.gen.monster_density = density,
+                        Err(_) => println!("Ignoring bad density in {}", arg),
+                    }
+                }
+                _ if arg.starts_with("--item-density=") => {
+                    match arg["--item-density=".len()..].parse() {
+                        Ok(density) => settings.gen.item_density = density,
+                        Err(_) => println!("Ignoring bad density in {}", arg),
+                    }
+                }
+                _ if arg.starts_with("--back-attack-bonus=") => {
+                    match arg["--back-attack-bonus=".len()..].parse() {
+                        Ok(bonus) => settings.back_attack_bonus = bonus,
+                        Err(_) => println!("Ignoring bad bonus in {}", arg),
+                    }
+                }
+                _ if arg.starts_with("--sneak-attack-multiplier=") => {
+                    match arg["--sneak-attack-multiplier=".len()..].parse() {
+                        Ok(multiplier) => settings.sneak_attack_multiplier = multiplier,
+                        Err(_) => println!("Ignoring bad multiplier in {}", arg),
+                    }
+                }
+                _ => println!("Ignoring unknown argument {}", arg),
+            }
+        }
+        settings
+    }
+}
+
+fn in_map(x: i32, y: i32) -> bool {
+    (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y)
+}
+
+// Off the map counts as blocked, so callers needn't check first
+fn is_blocked_by_object(x: i32, y: i32, objects: &[Object]) -> bool {
+    !in_map(x, y) || objects.iter().any(|object| !object.is_walkable && object.pos() == (x, y))
+}
+
+fn is_blocked(map: &MapSlice, x: i32, y: i32, objects: &[Object]) -> bool {
+    if !in_map(x, y) || !map[x as usize][y as usize].is_walkable {
+        return true;
+    }
+    is_blocked_by_object(x, y, objects)
+}
+
+fn move_by(id: usize, dx: i32, dy: i32, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    if !game.settings.movement.allows(dx, dy) {
+        return PlayerAction::DidntTakeTurn;
+    }
+    let from = objects[id].pos();
+    let action = step(id, dx, dy, &game.map, objects);
+    if action == PlayerAction::TookTurn {
+        if let Some(facing) = Direction::towards(dx, dy) {
+            objects[id].facing = facing;
+        }
+    }
+    let careful = id == PLAYER && game.careful_step;
+    if action == PlayerAction::TookTurn && game.settings.attacks_of_opportunity && !careful {
+        attacks_of_opportunity(id, from, game, objects);
+    }
+    action
+}
+
+// Enemies which were next to `id` at `from`, and aren't any more, get a free
+// hit in as it goes
+fn attacks_of_opportunity(id: usize, from: (i32, i32), game: &mut Game, objects: &mut [Object]) {
+    for enemy in 0..objects.len() {
+        let object = &objects[enemy];
+        let movement = game.settings.movement;
+        let was_adjacent = object.grid_distance(from.0, from.1, movement) == 1;
+        if enemy != id
+            && object.is_alive
+            && object.fighter.is_some()
+            && object.alert_state != AlertState::Unaware
+            && object.faction.is_hostile_to(objects[id].faction)
+            && was_adjacent
+            && object.grid_distance_to(&objects[id], movement) > 1
+            && objects[id].is_alive
+        {
+            game.messages.add_cat(
+                format!("{} strikes at {} as it leaves!", object.name, objects[id].name),
+                colors::ORANGE,
+                MsgCategory::Combat,
+            );
+            attack_target(enemy, id, game, objects);
+        }
+    }
+}
+
+// Moving without provoking attacks of opportunity
+fn step(id: usize, dx: i32, dy: i32, map: &MapSlice, objects: &mut [Object]) -> PlayerAction {
+    let (x, y) = objects[id].pos();
+    // move by the given amount
+    let next_x = x + dx;
+    let next_y = y + dy;
+    if !in_map(next_x, next_y) {
+        return PlayerAction::DidntTakeTurn;
+    }
+    // Allies make way for each other
+    if id != PLAYER && objects[id].faction == Faction::Player {
+        let ally = objects.iter().enumerate().position(|(other, ob)| {
+            other != PLAYER
+                && other != id
+                && ob.pos() == (next_x, next_y)
+                && ob.ai.is_some()
+                && ob.faction == Faction::Player
+        });
+        if let Some(ally) = ally {
+            return if swap_positions(id, ally, map, objects) {
+                PlayerAction::TookTurn
+            } else {
+                PlayerAction::DidntTakeTurn
+            };
+        }
+    }
+    if !is_blocked(map, next_x, next_y, objects) {
+        let kind = map[next_x as usize][next_y as usize].kind;
+        if !can_enter(id, kind, objects) {
+            return PlayerAction::DidntTakeTurn;
+        }
+        objects[id].set_pos(next_x, next_y);
+        if let TileKind::Water { .. } = kind {
+            objects[id].lost_turns += WATER_SLOWDOWN;
+        }
+        return PlayerAction::TookTurn;
+    }
+    PlayerAction::DidntTakeTurn
+}
+
+// Whether objects[id] is willing and able to stand on this kind of terrain.
+// Only the player is reckless enough to walk into hazards.
+fn can_enter(id: usize, kind: TileKind, objects: &[Object]) -> bool {
+    if id != PLAYER && kind.is_hazard() {
+        return false;
+    }
+    kind != (TileKind::Water { deep: true }) || objects[id].can_swim
+}
+
+// Exchange the positions of two actors, if each can stand where the other is
+fn swap_positions(a: usize, b: usize, map: &MapSlice, objects: &mut [Object]) -> bool {
+    let (a_pos, b_pos) = (objects[a].pos(), objects[b].pos());
+    let a_tile = &map[a_pos.0 as usize][a_pos.1 as usize];
+    let b_tile = &map[b_pos.0 as usize][b_pos.1 as usize];
+    if !a_tile.is_walkable
+        || !b_tile.is_walkable
+        || !can_enter(a, b_tile.kind, objects)
+        || !can_enter(b, a_tile.kind, objects)
+    {
+        return false;
+    }
+    objects[a].set_pos(b_pos.0, b_pos.1);
+    objects[b].set_pos(a_pos.0, a_pos.1);
+    true
+}
+
+// Count down everyone's status effects, and undo the ones that run out
+fn tick_status_effects(game: &mut Game, objects: &mut [Object]) {
+    for object in objects.iter_mut().filter(|ob| ob.is_alive) {
+        let mut expired = vec![];
+        for effect in object.status_effects.iter_mut() {
+            let turns = match effect {
+                StatusEffect::Charmed { turns, .. }
+                | StatusEffect::Blessed { turns }
+                | StatusEffect::Hasted { turns }
+                | StatusEffect::Slowed { turns } => turns,
+            };
+            *turns -= 1;
+            if *turns <= 0 {
+                expired.push(*effect);
+            }
+        }
+        object.status_effects.retain(|effect| !expired.contains(effect));
+        for effect in expired {
+            match effect {
+                StatusEffect::Charmed { faction, .. } => {
+                    object.faction = faction;
+                    game.messages.add_cat(
+                        format!("The {} is no longer charmed!", object.name),
+                        colors::PINK,
+                        MsgCategory::Combat,
+                    );
+                }
+                StatusEffect::Blessed { .. } => {
+                    game.messages.add_cat(
+                        format!("The blessing on {} fades", object.name),
+                        colors::LIGHT_SKY,
+                        MsgCategory::Combat,
+                    );
+                }
+                StatusEffect::Hasted { .. } | StatusEffect::Slowed { .. } => {
+                    game.messages.add_cat(
+                        format!(
+                            "{} {} back to normal speed",
+                            capitalized(&object.the_name()),
+                            if object.is_player() { "are" } else { "is" }
+                        ),
+                        colors::LIGHT_SKY,
+                        MsgCategory::Combat,
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Burn every fighter standing in lava
+fn apply_terrain_effects(game: &mut Game, objects: &mut [Object]) {
+    for (id, object) in objects.iter_mut().enumerate() {
+        let (x, y) = object.pos();
+        if object.is_alive
+            && object.fighter.is_some()
+            && game.map[x as usize][y as usize].kind == TileKind::Lava
+        {
+            game.messages.add_cat(
+                format!("The lava burns {}!", object.name),
+                colors::FLAME,
+                MsgCategory::Combat,
+            );
+            object.take_damage(LAVA_DAMAGE, game);
+            if id == PLAYER && !object.is_alive {
+                game.killed_by = Some("lava".to_string());
+            }
+        }
+    }
+}
+
+// Spring the armed traps under anyone standing on them, player or monster.
+// Traps which don't reset are used up and leave plain floor behind.
+fn spring_traps(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    for (id, object) in objects.iter_mut().enumerate() {
+        if !object.is_alive || object.fighter.is_none() {
+            continue;
+        }
+        let (x, y) = object.pos();
+        let tile = &mut game.map[x as usize][y as usize];
+        let kind = match tile.kind {
+            TileKind::Trap { kind, armed: true, .. } => kind,
+            _ => continue,
+        };
+        tile.kind = match kind.rearm_turns() {
+            Some(turns) => TileKind::Trap { kind, hidden: false, armed: false, rearm_in: turns },
+            None => TileKind::Floor,
+        };
+        tcod.dirty.push((x, y));
+        if tcod.fov.is_in_fov(x, y) {
+            game.messages.add_cat(
+                kind.message(&object.the_name()),
+                colors::RED,
+                MsgCategory::Combat,
+            );
+        }
+        object.take_damage(kind.damage(), game);
+        if id == PLAYER && !object.is_alive {
+            game.killed_by = Some(format!("a {}", kind.name()));
+        }
+    }
+}
+
+// Count down the sprung traps which reset.  One that's ready waits for whoever
+// is standing on it to move off before it arms again.
+fn rearm_traps(game: &mut Game, objects: &[Object]) {
+    for (x, column) in game.map.iter_mut().enumerate() {
+        for (y, tile) in column.iter_mut().enumerate() {
+            if let TileKind::Trap { armed, rearm_in, .. } = &mut tile.kind {
+                if *armed {
+                    continue;
+                }
+                *rearm_in = rearm_in.saturating_sub(1);
+                let occupied = objects.iter().any(|ob| {
+                    ob.is_alive && ob.fighter.is_some() && ob.pos() == (x as i32, y as i32)
+                });
+                *armed = *rearm_in == 0 && !occupied;
+            }
+        }
+    }
+}
+
+fn player_move_or_attack(
+    dx: i32,
+    dy: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> PlayerAction {
+    if !game.settings.movement.allows(dx, dy) {
+        return PlayerAction::DidntTakeTurn;
+    }
+    let (x, y) = objects[PLAYER].pos();
+    let next_x = x + dx;
+    let next_y = y + dy;
+
+    // Enemies get attacked even if they have something to say
+    let interactable = objects.iter().position(|object| {
+        object.pos() == (next_x, next_y)
+            && object.on_interact.is_some()
+            && !Faction::Player.is_hostile_to(object.faction)
+    });
+    if let Some(id) = interactable {
+        return interact(id, tcod, game, objects);
+    }
+
+    let target_id = objects
+        .iter()
+        .position(|object| object.pos() == (next_x, next_y) && object.fighter.is_some());
+
+    if game.settings.confirm_risky_moves {
+        let question = match target_id {
+            Some(id) if Faction::Player.is_hostile_to(objects[id].faction) => {
+                Some(format!("Attack the {}?", objects[id].name))
+            }
+            None if in_map(next_x, next_y) => {
+                let kind = game.map[next_x as usize][next_y as usize].kind;
+                kind.avoided_by_player().then(|| format!("Really step into the {}?", kind.name()))
+            }
+            _ => None,
+        };
+        if question.is_some_and(|question| !confirm(tcod, &question)) {
+            return PlayerAction::DidntTakeTurn;
+        }
+    }
+
+    match target_id {
+        // Swap places with allies rather than attacking them
+        Some(ally) if objects[ally].faction == Faction::Player => {
+            swap_with_ally(ally, game, objects)
+        }
+        Some(_) => attack_direction(dx, dy, tcod, game, objects),
+        None => {
+            let digs = equipped_weapon(game).is_some_and(|weapon| weapon.digs);
+            let power = objects[PLAYER].power(game);
+            if smash(next_x, next_y, power, digs, tcod, game, objects) {
+                return PlayerAction::TookTurn;
+            }
+            let action = move_by(PLAYER, dx, dy, game, objects);
+            if action == PlayerAction::TookTurn {
+                // Staggering along under too much weight takes twice as long
+                if game.current_weight() > objects[PLAYER].max_weight() {
+                    objects[PLAYER].lost_turns += 1;
+                }
+                // As does creeping along
+                if game.sneaking {
+                    objects[PLAYER].lost_turns += 1;
+                }
+                auto_pickup(game, objects);
+                if game.settings.examine_on_step && game.auto_action.is_none() {
+                    let (x, y) = objects[PLAYER].pos();
+                    examine_items_at(x, y, tcod, game, objects);
+                }
+                if game.settings.drowning {
+                    swim(game, objects);
+                }
+            }
+            action
+        }
+    }
+}
+
+fn interact(id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    match objects[id].on_interact {
+        Some(InteractKind::Lore(_)) => interact_lore(tcod, id, game, objects),
+        Some(InteractKind::Talk(lines)) => {
+            if let Some(line) = rand::thread_rng().choose(lines) {
+                let text = format!("\"{}\"", line);
+                msgbox(tcod, &objects[id].name, &text, INTERACT_SCREEN_WIDTH);
+            }
+            PlayerAction::DidntTakeTurn
+        }
+        Some(InteractKind::Shrine { used: true }) => {
+            msgbox(tcod, &objects[id].name, "The shrine is silent.", INTERACT_SCREEN_WIDTH);
+            PlayerAction::DidntTakeTurn
+        }
+        Some(InteractKind::Shrine { used: false }) => {
+            let player = &mut objects[PLAYER];
+            let max_hp = player.max_hp(game);
+            let hurt = player.fighter.is_some_and(|f| f.hp < max_hp);
+            let text = if hurt {
+                player.heal(max_hp, game);
+                "You pray at the shrine, and your wounds close"
+            } else {
+                player.status_effects.push(StatusEffect::Blessed { turns: BLESSING_TURNS });
+                "You pray at the shrine, and feel blessed"
+            };
+            game.messages.add_cat(text, colors::LIGHT_SKY, MsgCategory::Lore);
+            // The state lives on the object, so it goes wherever the level does
+            objects[id].on_interact = Some(InteractKind::Shrine { used: true });
+            objects[id].color = colors::GREY;
+            tcod.dirty.push(objects[id].pos());
+            PlayerAction::TookTurn
+        }
+        Some(InteractKind::Shop) => shop(tcod, game, &objects[PLAYER]),
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
+fn interact_lore(tcod: &mut Tcod, id: usize, game: &mut Game, objects: &[Object]) -> PlayerAction {
+    if let Some(InteractKind::Lore(index)) = objects[id].on_interact {
+        let text = LORE[index % LORE.len()];
+        msgbox(tcod, &objects[id].name, text, INTERACT_SCREEN_WIDTH);
+        // Keep it around for the message log
+        game.messages.add_cat(text, colors::LIGHT_GREY, MsgCategory::Lore);
+    }
+    PlayerAction::DidntTakeTurn
+}
+
+// Potions all look alike until the player drinks one of a kind, see Game::item_name
+const POTIONS: [Item; 3] = [Item::Heal, Item::Acid, Item::Haste];
+const POTION_APPEARANCES: [&str; 5] = ["murky", "fizzing", "cloudy", "golden", "smoking"];
+const POTION_COLOR: Color = colors::LIGHT_VIOLET;
+
+// What merchants sell, and for how much gold
+const WARES: [(Item, i32); 4] =
+    [(Item::Heal, 20), (Item::Acid, 15), (Item::Spear, 40), (Item::Axe, 40)];
+
+fn shop(tcod: &mut Tcod, game: &mut Game, player: &Object) -> PlayerAction {
+    let names: Vec<String> = WARES
+        .iter()
+        .map(|&(item, price)| {
+            format!("{} ({} gold)", game.item_name(&make_item(item, 0, 0)), price)
+        })
+        .collect();
+    let title = format!("Buy something? You have {} gold", game.gold);
+    let (item, price) = match show_list(tcod, &title, &names) {
+        Some(choice) => WARES[choice],
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    if game.gold < price {
+        game.messages.add_cat("You can't afford that", colors::RED, MsgCategory::Item);
+        PlayerAction::DidntTakeTurn
+    } else if game.inventory.len() >= INVENTORY_SIZE {
+        game.messages.add_cat("Inventory is full", colors::RED, MsgCategory::Item);
+        PlayerAction::DidntTakeTurn
+    } else if game.current_weight() + item.weight() > player.max_weight() {
+        game.messages.add_cat("It's too heavy to carry.", colors::RED, MsgCategory::Item);
+        PlayerAction::DidntTakeTurn
+    } else {
+        game.gold -= price;
+        let bought = make_item(item, 0, 0);
+        game.messages.add_cat(
+            format!("You buy a {}", bought.name),
+            colors::GOLD,
+            MsgCategory::Item,
+        );
+        game.inventory.push(bought);
+        PlayerAction::TookTurn
+    }
+}
+
+fn swap_with_ally(ally: usize, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    if swap_positions(PLAYER, ally, &game.map, objects) {
+        game.messages
+            .add(format!("You swap places with the {}", objects[ally].name), colors::WHITE);
+        PlayerAction::TookTurn
+    } else {
+        game.messages
+            .add(format!("The {} won't follow you there", objects[ally].name), colors::WHITE);
+        PlayerAction::DidntTakeTurn
+    }
+}
+
+// Attack whatever is on the adjacent tile, without moving there if it's empty
+fn attack_direction(
+    dx: i32,
+    dy: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> PlayerAction {
+    let origin = objects[PLAYER].pos();
+    if attack_area(origin, dx, dy, tcod, game, objects) {
+        PlayerAction::TookTurn
+    } else {
+        game.messages.add_cat(
+            "There's nothing there to attack",
+            colors::WHITE,
+            MsgCategory::Combat,
+        );
+        PlayerAction::DidntTakeTurn
+    }
+}
+
+fn equipped_weapon(game: &Game) -> Option<Equipment> {
+    equipped_in(Slot::MainHand, game)
+}
+
+fn equipped_shield(game: &Game) -> Option<Equipment> {
+    equipped_in(Slot::OffHand, game)
+}
+
+// Whether the player's shield stops a blow or bolt aimed at them.  Only ever
+// asked about attacks, never about acid, lava, falls or drowning.
+fn player_blocks(game: &mut Game) -> bool {
+    let block_chance = equipped_shield(game).map_or(0.0, |shield| shield.block_chance);
+    if block_chance <= 0.0 {
+        return false;
+    }
+    let roll = game.rngs.combat.gen::<f32>();
+    game.trace.record(|| format!("roll block {:.3} against {:.3}", roll, block_chance));
+    let blocked = roll < block_chance;
+    if blocked {
+        game.messages.add_cat("You block the attack!", colors::LIGHT_BLUE, MsgCategory::Combat);
+    }
+    blocked
+}
+
+fn equipped_in(slot: Slot, game: &Game) -> Option<Equipment> {
+    equipped(game).find(|equipment| equipment.slot == slot).cloned()
+}
+
+// Everything the player has equipped, in whatever slot
+fn equipped(game: &Game) -> impl Iterator<Item = &Equipment> {
+    game.inventory
+        .iter()
+        .filter_map(|item| item.equipment.as_ref())
+        .filter(|equipment| equipment.equipped)
+}
+
+// How many of the player's equipped items have the affix, so that the same
+// affix on a weapon and a ring or two counts once for each
+fn equipped_affixes(affix: Affix, game: &Game) -> usize {
+    equipped(game).filter(|equipment| equipment.has(affix)).count()
+}
+
+// How far the player can see, which affixes of the Sun push further
+fn torch_radius(game: &Game) -> i32 {
+    TORCH_RADIUS + SUN_LIGHT * equipped_affixes(Affix::Sun, game) as i32
+}
+
+// The eight directions, going round clockwise
+const DIRECTIONS: [(i32, i32); 8] =
+    [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+
+// One of DIRECTIONS, in the same order, for which way something faces
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+    North,
+    NorthEast,
+}
+
+const COMPASS: [Direction; 8] = [
+    Direction::East,
+    Direction::SouthEast,
+    Direction::South,
+    Direction::SouthWest,
+    Direction::West,
+    Direction::NorthWest,
+    Direction::North,
+    Direction::NorthEast,
+];
+
+impl Direction {
+    // The way from one tile towards another (dx, dy) away, or None for no way at all
+    pub fn towards(dx: i32, dy: i32) -> Option<Direction> {
+        let delta = (dx.signum(), dy.signum());
+        DIRECTIONS.iter().position(|&dir| dir == delta).map(|i| COMPASS[i])
+    }
+
+    // Whether something (dx, dy) away is in the rear arc of whatever faces this
+    // way: straight behind it, or diagonally behind either side
+    pub fn is_behind(self, dx: i32, dy: i32) -> bool {
+        Direction::towards(dx, dy).is_some_and(|dir| {
+            let turn = (dir as usize + COMPASS.len() - self as usize) % COMPASS.len();
+            (3..=5).contains(&turn)
+        })
+    }
+}
+
+// The player's melee attack from origin towards (dx, dy).  Whatever fighter is
+// on the target tile gets hit, and depending on the weapon, so do enemies
+// beyond it (reach) or either side of it (cleave).  With no fighter there, the
+// target tile gets smashed instead.  Returns false if there was nothing to hit.
+fn attack_area(
+    origin: (i32, i32),
+    dx: i32,
+    dy: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> bool {
+    let fighter_at = |(x, y): (i32, i32), objects: &[Object]| {
+        (0..objects.len()).find(|&id| {
+            id != PLAYER
+                && objects[id].pos() == (x, y)
+                && objects[id].is_alive
+                && objects[id].fighter.is_some()
+        })
+    };
+    let enemy_at = |pos: (i32, i32), objects: &[Object]| {
+        fighter_at(pos, objects).filter(|&id| Faction::Player.is_hostile_to(objects[id].faction))
+    };
+
+    let (ox, oy) = origin;
+    let target = (ox + dx, oy + dy);
+    let mut targets = vec![];
+    if !in_map(target.0, target.1) {
+        return false;
+    }
+    targets.extend(fighter_at(target, objects));
+    if targets.is_empty() {
+        let digs = equipped_weapon(game).is_some_and(|weapon| weapon.digs);
+        let power = objects[PLAYER].power(game);
+        return smash(target.0, target.1, power, digs, tcod, game, objects);
+    }
+    if let Some(weapon) = equipped_weapon(game) {
+        for pos in weapon_extra_tiles(origin, dx, dy, &weapon, &game.map) {
+            targets.extend(enemy_at(pos, objects));
+        }
+    }
+    for &id in &targets {
+        attack_target(PLAYER, id, game, objects);
+    }
+    !targets.is_empty()
+}
+
+// Where else an attack from origin towards (dx, dy) lands, besides the target
+// tile: beyond it with reach, either side of it with cleave
+fn weapon_extra_tiles(
+    origin: (i32, i32),
+    dx: i32,
+    dy: i32,
+    weapon: &Equipment,
+    map: &MapSlice,
+) -> Vec<(i32, i32)> {
+    let (ox, oy) = origin;
+    let mut tiles = vec![];
+    let beyond = (ox + 2 * dx, oy + 2 * dy);
+    // Walls stop a reach attack
+    if weapon.reach
+        && in_map(beyond.0, beyond.1)
+        && map[(ox + dx) as usize][(oy + dy) as usize].is_walkable
+    {
+        tiles.push(beyond);
+    }
+    if weapon.cleave {
+        if let Some(i) = DIRECTIONS.iter().position(|&dir| dir == (dx, dy)) {
+            for &(sx, sy) in &[DIRECTIONS[(i + 1) % 8], DIRECTIONS[(i + 7) % 8]] {
+                let side = (ox + sx, oy + sy);
+                if in_map(side.0, side.1) {
+                    tiles.push(side);
+                }
+            }
+        }
+    }
+    tiles
+}
+
+// Break a little more of whatever is on (x, y): a barrel or other destructible
+// object first, otherwise a cracked wall, or any wall if digging.  The edge of
+// the map is never broken, and breaking only ever opens up the map, so it can't
+// cut anything off.  Returns false if there was nothing there to break.
+fn smash(
+    x: i32,
+    y: i32,
+    damage: i32,
+    digs: bool,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> bool {
+    if let Some(id) = objects.iter().position(|ob| ob.pos() == (x, y) && ob.destructible.is_some())
+    {
+        smash_object(id, damage, game, objects);
+        tcod.dirty.push((x, y));
+        return true;
+    }
+
+    if x <= 0 || y <= 0 || x >= MAP_WIDTH - 1 || y >= MAP_HEIGHT - 1 {
+        return false;
+    }
+    let tile = &mut game.map[x as usize][y as usize];
+    if tile.kind != TileKind::Wall {
+        return false;
+    }
+    let hp = match tile.destructible {
+        _ if digs => 0,
+        Some(hp) => hp - damage,
+        None => return false,
+    };
+    if hp > 0 {
+        tile.destructible = Some(hp);
+        game.messages.add_cat("The wall cracks further", colors::LIGHT_GREY, MsgCategory::Combat);
+        return true;
+    }
+    break_wall(x, y, tcod, game);
+    game.messages.add_cat("The wall crumbles away!", colors::LIGHT_GREY, MsgCategory::Combat);
+    true
+}
+
+// Turn the wall at (x, y) into floor, keeping the FOV map in step
+fn break_wall(x: i32, y: i32, tcod: &mut Tcod, game: &mut Game) {
+    let light = game.map[x as usize][y as usize].light;
+    game.map[x as usize][y as usize] = Tile { explored: true, light, ..Tile::empty() };
+    tcod.fov.set(x, y, true, true);
+    tcod.fov_changed = true;
+    tcod.dirty.push((x, y));
+}
+
+// Carve a tunnel along the line from one tile to another, breaking any barrels
+// in the way.  Stops at the edge of the map.  Returns how many walls were dug.
+fn dig(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    from: (i32, i32),
+    to: (i32, i32),
+) -> i32 {
+    let mut dug = 0;
+    for (x, y) in Line::new(from, to) {
+        if x <= 0 || y <= 0 || x >= MAP_WIDTH - 1 || y >= MAP_HEIGHT - 1 {
+            break;
+        }
+        if game.map[x as usize][y as usize].kind == TileKind::Wall {
+            break_wall(x, y, tcod, game);
+            dug += 1;
+        }
+        if let Some(id) =
+            objects.iter().position(|ob| ob.pos() == (x, y) && ob.destructible.is_some())
+        {
+            smash_object(id, BARREL_HP, game, objects);
+            tcod.dirty.push((x, y));
+        }
+    }
+    dug
+}
+
+fn smash_object(id: usize, damage: i32, game: &mut Game, objects: &mut [Object]) {
+    let object = &mut objects[id];
+    let hp = object.destructible.map_or(0, |hp| hp - damage);
+    if hp > 0 {
+        object.destructible = Some(hp);
+        game.messages.add_cat(
+            format!("The {} cracks", object.name),
+            colors::LIGHT_GREY,
+            MsgCategory::Combat,
+        );
+        return;
+    }
+    game.messages.add_cat(
+        format!("The {} breaks apart!", object.name),
+        colors::LIGHT_GREY,
+        MsgCategory::Combat,
+    );
+    object.destructible = None;
+    object.char = '%';
+    object.is_walkable = true;
+    object.name = format!("broken {}", object.name);
+    let rng = &mut game.rngs.loot;
+    if rng.gen::<f32>() < BARREL_LOOT_CHANCE {
+        let (x, y) = object.pos();
+        let item = make_item(random_item(rng), x, y);
+        game.pending_spawns.push(item);
+    }
+}
+
+// Take one step away from every visible monster, towards the tile they would
+// need the longest to reach
+fn flee_step(tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let monsters: Vec<(i32, i32)> = objects
+        .iter()
+        .filter(|ob| ob.is_alive && ob.faction.is_monster() && tcod.fov.is_in_fov(ob.x, ob.y))
+        .map(|ob| ob.pos())
+        .collect();
+    if monsters.is_empty() {
+        game.messages.add("There's nothing to flee from", colors::WHITE);
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let movement = game.settings.movement;
+    let distances = dijkstra_map_over(&game.map, &monsters, movement, |tile| tile.is_walkable);
+    let (x, y) = objects[PLAYER].pos();
+    let mut best = None;
+    let mut best_distance = distances[x as usize][y as usize];
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (nx, ny) = (x + dx, y + dy);
+            if !movement.allows(dx, dy)
+                || is_blocked(&game.map, nx, ny, objects)
+                || game.map[nx as usize][ny as usize].kind.is_hazard()
+            {
+                continue;
+            }
+            if distances[nx as usize][ny as usize] > best_distance {
+                best_distance = distances[nx as usize][ny as usize];
+                best = Some((dx, dy));
+            }
+        }
+    }
+    match best {
+        Some((dx, dy)) => {
+            let action = move_by(PLAYER, dx, dy, game, objects);
+            if action == PlayerAction::TookTurn && game.settings.drowning {
+                swim(game, objects);
+            }
+            action
+        }
+        None => {
+            game.messages.add("There's nowhere to run!", colors::RED);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+// Swimming through deep water risks the player's belongings, or their life
+fn swim(game: &mut Game, objects: &mut [Object]) {
+    let (x, y) = objects[PLAYER].pos();
+    if game.map[x as usize][y as usize].kind != (TileKind::Water { deep: true }) {
+        return;
+    }
+    let chance = DROWN_CHANCE + DROWN_CHANCE_PER_ITEM * game.inventory.len() as f32;
+    let roll = game.rngs.combat.gen::<f32>();
+    game.trace.record(|| format!("roll drowning {:.3} against {:.3}", roll, chance));
+    if roll >= chance {
+        return;
+    }
+    if game.inventory.is_empty() {
+        game.messages.add_cat(
+            "You go under and swallow a lungful of water!",
+            colors::LIGHT_BLUE,
+            MsgCategory::Combat,
+        );
+        objects[PLAYER].take_damage(DROWN_DAMAGE, game);
+        if !objects[PLAYER].is_alive {
+            game.killed_by = Some("drowning".to_string());
+        }
+    } else {
+        let id = game.rngs.loot.gen_range(0, game.inventory.len());
+        let item = game.inventory.remove(id);
+        objects[PLAYER].clamp_hp(game);
+        game.messages.add_cat(
+            format!("Your {} slips away into the depths!", game.item_name(&item)),
+            colors::LIGHT_BLUE,
+            MsgCategory::Item,
+        );
+    }
+}
+
+// What the player can tell of an item by looking at it.  Potions they haven't
+// drunk one of yet show only how they look.
+fn describe_item(game: &Game, item: &Object) -> String {
+    let mut text = capitalized(&game.item_name(item));
+    let kind = match item.item {
+        Some(kind) => kind,
+        None => return text,
+    };
+    if !game.knows(item) {
+        text.push_str("\n\nYou don't know what it does.");
+        return text;
+    }
+    text.push_str("\n\n");
+    text.push_str(kind.description());
+    if let Some(equipment) = &item.equipment {
+        if equipment.attack_bonus != 0 {
+            text.push_str(&format!("\nAttack: {:+}", equipment.attack_bonus));
+        }
+        if equipment.defense_bonus != 0 {
+            text.push_str(&format!("\nDefense: {:+}", equipment.defense_bonus));
+        }
+        if equipment.block_chance > 0.0 {
+            text.push_str(&format!("\nBlock: {:.0}%", equipment.block_chance * 100.0));
+        }
+    }
+    if kind.weight() > 0 {
+        text.push_str(&format!("\nWeight: {}", kind.weight()));
+    }
+    text
+}
+
+fn player_pick_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    if let Some(Item::Gold(amount)) = objects[object_id].item {
+        objects.swap_remove(object_id);
+        game.gold += amount;
+        game.messages.add_cat(
+            format!("You picked up {} gold", amount),
+            colors::GOLD,
+            MsgCategory::Item,
+        );
+        return;
+    }
+    if game.inventory.len() >= INVENTORY_SIZE {
+        game.messages.add_cat("Inventory is full", colors::RED, MsgCategory::Item);
+        return;
+    }
+    let weight = objects[object_id].item.map_or(0, Item::weight);
+    if game.current_weight() + weight > objects[PLAYER].max_weight() {
+        game.messages.add_cat("It's too heavy to carry.", colors::RED, MsgCategory::Item);
+        return;
+    }
+    let item = objects.swap_remove(object_id);
+    if item.item == Some(Item::Amulet) {
+        game.messages.add_cat(
+            format!("You take the {}. The tombs are yours!", item.name),
+            colors::GOLD,
+            MsgCategory::Lore,
+        );
+        game.won = true;
+    } else {
+        game.messages.add_cat(
+            format!("You picked up a {}", game.item_name(&item)),
+            item.rarity.color(),
+            MsgCategory::Item,
+        );
+    }
+    game.inventory.push(item);
+}
+
+fn player_pick_up_here(game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let player_pos = objects[PLAYER].pos();
+
+    match objects.iter().position(|ob| ob.pos() == player_pos && ob.item.is_some()) {
+        Some(id) => {
+            player_pick_up(id, game, objects);
+            PlayerAction::TookTurn
+        }
+        _ => {
+            game.messages.add_cat("There is nothing here", colors::WHITE, MsgCategory::Item);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+// Pick up everything here which fits in the inventory
+fn pick_up_all(game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let player_pos = objects[PLAYER].pos();
+    let max_weight = objects[PLAYER].max_weight();
+    let mut picked_up = 0;
+    loop {
+        let full = game.inventory.len() >= INVENTORY_SIZE;
+        let next = objects.iter().position(|ob| {
+            ob.pos() == player_pos
+                && match ob.item {
+                    Some(Item::Gold(_)) => true,
+                    Some(item) => !full && game.current_weight() + item.weight() <= max_weight,
+                    None => false,
+                }
+        });
+        match next {
+            Some(id) => {
+                player_pick_up(id, game, objects);
+                picked_up += 1;
+            }
+            None => break,
+        }
+    }
+
+    let left = objects.iter().filter(|ob| ob.pos() == player_pos && ob.item.is_some()).count();
+    if left > 0 && game.inventory.len() < INVENTORY_SIZE {
+        game.messages.add_cat("It's too heavy to carry.", colors::RED, MsgCategory::Item);
+    } else if left > 0 {
+        game.messages.add_cat(
+            format!("No room for the other {} item(s) here", left),
+            colors::RED,
+            MsgCategory::Item,
+        );
+    }
+    if picked_up > 0 {
+        PlayerAction::TookTurn
+    } else {
+        if left == 0 {
+            game.messages.add_cat("There is nothing here", colors::WHITE, MsgCategory::Item);
+        }
+        PlayerAction::DidntTakeTurn
+    }
+}
+
+// Pick up whatever the settings say to after stepping onto a tile.  Gold
+// doesn't take up room, so is picked up even with a full inventory.  Items
+// which wouldn't fit are quietly left where they are.
+fn auto_pickup(game: &mut Game, objects: &mut Vec<Object>) {
+    let player_pos = objects[PLAYER].pos();
+    let max_weight = objects[PLAYER].max_weight();
+    let auto_pickup = game.settings.auto_pickup;
+    loop {
+        let full = game.inventory.len() >= INVENTORY_SIZE;
+        let next = objects.iter().position(|ob| {
+            ob.pos() == player_pos
+                && match ob.item {
+                    Some(Item::Gold(_)) => auto_pickup.gold,
+                    Some(item) => {
+                        auto_pickup.items
+                            && !full
+                            && game.current_weight() + item.weight() <= max_weight
+                    }
+                    None => false,
+                }
+        });
+        match next {
+            Some(id) => player_pick_up(id, game, objects),
+            None => break,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RoomShape {
+    Rectangle,
+    // Really an ellipse, stretched to fill the room's rect
+    Circle,
+    // A plus sign, with arms reaching each side of the rect
+    Cross,
+    // A winding natural hollow
+    Cavern,
+}
+
+// Carve out the floor of a room.  Whatever the shape, it stays inside the
+// rect's walls, and the center is always floor since tunnels (and the player
+// and stairs) arrive there.
+fn carve_room(shape: RoomShape, room: Rect, map: &mut Map, rng: &mut StdRng) {
+    let (center_x, center_y) = room.center();
+    match shape {
+        RoomShape::Rectangle => make_room(room, map),
+        RoomShape::Circle => {
+            // Measured from the middle of the interior, in units of its half-size
+            let (mid_x, mid_y) =
+                ((room.x1 + room.x2) as f32 / 2.0, (room.y1 + room.y2) as f32 / 2.0);
+            let (radius_x, radius_y) =
+                ((room.x2 - room.x1 - 1) as f32 / 2.0, (room.y2 - room.y1 - 1) as f32 / 2.0);
+            for x in (room.x1 + 1)..room.x2 {
+                for y in (room.y1 + 1)..room.y2 {
+                    let (dx, dy) = ((x as f32 - mid_x) / radius_x, (y as f32 - mid_y) / radius_y);
+                    if dx * dx + dy * dy <= 1.0 {
+                        map[x as usize][y as usize] = Tile::empty();
+                    }
+                }
+            }
+        }
+        RoomShape::Cross => {
+            let arm_width = max(1, (room.x2 - room.x1 - 1) / 3);
+            let arm_height = max(1, (room.y2 - room.y1 - 1) / 3);
+            let left = center_x - arm_width / 2;
+            let top = center_y - arm_height / 2;
+            make_room(Rect::new(room.x1, top - 1, room.x2 - room.x1, arm_height + 1), map);
+            make_room(Rect::new(left - 1, room.y1, arm_width + 1, room.y2 - room.y1), map);
+        }
+        RoomShape::Cavern => {
+            // Wander about from the center, hollowing out about half the room
+            let area = (room.x2 - room.x1 - 1) * (room.y2 - room.y1 - 1);
+            let (mut x, mut y) = (center_x, center_y);
+            for _ in 0..area {
+                map[x as usize][y as usize] = Tile::empty();
+                x = (x + rng.gen_range(-1, 2)).clamp(room.x1 + 1, room.x2 - 1);
+                y = (y + rng.gen_range(-1, 2)).clamp(room.y1 + 1, room.y2 - 1);
+            }
+        }
+    }
+    map[center_x as usize][center_y as usize] = Tile::empty();
+}
+
+// Stand pillars in a grid across a big room, for cover.  Each one has floor
+// all around it, and the middle of the room is left clear.
+fn place_pillars(room: Rect, map: &mut Map, rng: &mut StdRng) {
+    let area = (room.x2 - room.x1 - 1) * (room.y2 - room.y1 - 1);
+    if area < PILLAR_MIN_AREA || rng.gen::<f32>() >= PILLAR_CHANCE {
+        return;
+    }
+    let (center_x, center_y) = room.center();
+    let mut pillars = vec![];
+    for x in ((room.x1 + 2)..(room.x2 - 1)).step_by(PILLAR_SPACING as usize) {
+        for y in ((room.y1 + 2)..(room.y2 - 1)).step_by(PILLAR_SPACING as usize) {
+            let near_center = (x - center_x).abs() <= 1 && (y - center_y).abs() <= 1;
+            let surrounded = (-1..=1).all(|dx| {
+                (-1..=1).all(|dy| map[(x + dx) as usize][(y + dy) as usize].kind == TileKind::Floor)
+            });
+            if !near_center && surrounded {
+                map[x as usize][y as usize] = Tile::wall();
+                pillars.push((x, y));
+            }
+        }
+    }
+
+    // The pillars are spaced out so they can't cut the room in two, but make
+    // sure.  Anything walkable in the room must still be reachable from the middle.
+    let distances = connectivity_map(map, &[(center_x, center_y)]);
+    let cut_off = ((room.x1 + 1)..room.x2).any(|x| {
+        ((room.y1 + 1)..room.y2).any(|y| {
+            map[x as usize][y as usize].is_walkable && distances[x as usize][y as usize] == u32::MAX
+        })
+    });
+    if cut_off {
+        for (x, y) in pillars {
+            map[x as usize][y as usize] = Tile::empty();
+        }
+    }
+}
+
+fn make_room(room: Rect, map: &mut Map) {
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            map[x as usize][y as usize] = Tile::empty();
+        }
+    }
+}
+
+fn make_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    for x in min(x1, x2)..=(max(x1, x2)) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn make_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    for y in min(y1, y2)..=(max(y1, y2)) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn place_item(x: i32, y: i32, objects: &mut Vec<Object>, rng: &mut StdRng) {
+    objects.push(make_item(random_item(rng), x, y));
+}
+
+fn random_item<R: Rng>(rng: &mut R) -> Item {
+    let r = rng.gen::<f32>();
+    if r < 0.51 {
+        Item::Heal
+    } else if r < 0.52 {
+        Item::Ring
+    } else if r < 0.53 {
+        Item::Pendant
+    } else if r < 0.55 {
+        Item::Enchant
+    } else if r < 0.65 {
+        Item::Acid
+    } else if r < 0.75 {
+        Item::Lightning
+    } else if r < 0.85 {
+        Item::Confusion
+    } else if r < 0.87 {
+        Item::Charm
+    } else if r < 0.88 {
+        Item::Haste
+    } else if r < 0.89 {
+        Item::Slow
+    } else if r < 0.9 {
+        Item::Digging(DIG_CHARGES)
+    } else if r < 0.94 {
+        Item::Spear
+    } else if r < 0.96 {
+        Item::Axe
+    } else if r < 0.98 {
+        Item::Shield
+    } else {
+        Item::Pick
+    }
+}
+
+fn make_item(item: Item, x: i32, y: i32) -> Object {
+    let mut object = match item {
+        Item::Heal => Object::new(x, y, '!', "health potion", POTION_COLOR),
+        Item::Acid => Object::new(x, y, '!', "potion of acid", POTION_COLOR),
+        Item::Lightning => Object::new(x, y, '#', "scroll of lightning bolt", colors::LIGHT_YELLOW),
+        Item::Confusion => Object::new(x, y, '#', "scroll of confusion", colors::LIGHT_BLUE),
+        Item::Charm => Object::new(x, y, '#', "scroll of charm monster", colors::PINK),
+        Item::Haste => Object::new(x, y, '!', "potion of speed", POTION_COLOR),
+        Item::Slow => Object::new(x, y, '#', "scroll of slowness", colors::LIGHT_BLUE),
+        Item::Enchant => Object::new(x, y, '#', "scroll of enchantment", colors::LIGHT_GREEN),
+        Item::Spear => return make_spear(x, y),
+        Item::Axe => return make_axe(x, y),
+        Item::Pick => return make_pick(x, y),
+        Item::Shield => return make_shield(x, y),
+        Item::Ring => return make_accessory(Item::Ring, x, y),
+        Item::Pendant => return make_accessory(Item::Pendant, x, y),
+        Item::Digging(charges) => {
+            Object::new(x, y, '-', &format!("wand of digging ({})", charges), colors::LIGHT_AMBER)
+        }
+        Item::Gold(_) => Object::new(x, y, '$', "pile of gold", colors::GOLD),
+        Item::Amulet => Object::new(x, y, '"', "Amulet of the Kings", colors::GOLD),
+    };
+    object.is_walkable = true;
+    object.item = Some(item);
+    object
+}
+
+fn make_spear(x: i32, y: i32) -> Object {
+    let mut spear = Object::new(x, y, '/', "spear", colors::SKY);
+    spear.is_walkable = true;
+    spear.item = Some(Item::Spear);
+    spear.equipment = Some(Equipment {
+        equipped: false,
+        slot: Slot::MainHand,
+        two_handed: false,
+        defense_bonus: 0,
+        block_chance: 0.0,
+        attack_bonus: 1,
+        enchantment: 0,
+        reach: true,
+        cleave: false,
+        digs: false,
+        affixes: vec![],
+    });
+    spear
+}
+
+fn make_axe(x: i32, y: i32) -> Object {
+    let mut axe = Object::new(x, y, '/', "axe", colors::SKY);
+    axe.is_walkable = true;
+    axe.item = Some(Item::Axe);
+    axe.equipment = Some(Equipment {
+        equipped: false,
+        slot: Slot::MainHand,
+        two_handed: true,
+        defense_bonus: 0,
+        block_chance: 0.0,
+        attack_bonus: 1,
+        enchantment: 0,
+        reach: false,
+        cleave: true,
+        digs: false,
+        affixes: vec![],
+    });
+    axe
+}
+
+fn make_pick(x: i32, y: i32) -> Object {
+    let mut pick = Object::new(x, y, '(', "pick", colors::SKY);
+    pick.is_walkable = true;
+    pick.item = Some(Item::Pick);
+    pick.equipment = Some(Equipment {
+        equipped: false,
+        slot: Slot::MainHand,
+        two_handed: true,
+        defense_bonus: 0,
+        block_chance: 0.0,
+        attack_bonus: 0,
+        enchantment: 0,
+        reach: false,
+        cleave: false,
+        digs: true,
+        affixes: vec![],
+    });
+    pick
+}
+
+fn make_shield(x: i32, y: i32) -> Object {
+    let mut shield = Object::new(x, y, '[', "shield", colors::SKY);
+    shield.is_walkable = true;
+    shield.item = Some(Item::Shield);
+    shield.equipment = Some(Equipment {
+        equipped: false,
+        slot: Slot::OffHand,
+        two_handed: false,
+        attack_bonus: 0,
+        defense_bonus: SHIELD_DEFENSE,
+        block_chance: SHIELD_BLOCK_CHANCE,
+        enchantment: 0,
+        reach: false,
+        cleave: false,
+        digs: false,
+        affixes: vec![],
+    });
+    shield
+}
+
+// Rings and pendants get their affixes along with their rarity, see apply_rarity
+fn make_accessory(item: Item, x: i32, y: i32) -> Object {
+    let (char, name, slot) = match item {
+        Item::Pendant => ('"', "pendant", Slot::Neck),
+        _ => ('=', "ring", Slot::LeftRing),
+    };
+    let mut accessory = Object::new(x, y, char, name, colors::SKY);
+    accessory.is_walkable = true;
+    accessory.item = Some(item);
+    accessory.equipment = Some(Equipment {
+        equipped: false,
+        slot,
+        two_handed: false,
+        attack_bonus: 0,
+        defense_bonus: 0,
+        block_chance: 0.0,
+        enchantment: 0,
+        reach: false,
+        cleave: false,
+        digs: false,
+        affixes: vec![],
+    });
+    accessory
+}
+
+fn place_gold(x: i32, y: i32, objects: &mut Vec<Object>, rng: &mut StdRng) {
+    let amount = rng.gen_range(MIN_GOLD, MAX_GOLD + 1);
+    let mut gold = Object::new(x, y, '$', "pile of gold", colors::GOLD);
+    gold.is_walkable = true;
+    gold.item = Some(Item::Gold(amount));
+    objects.push(gold);
+}
+
+// Inscriptions and carvings from the Tombs of the Ancient Kings
+const LORE: [&str; 8] = [
+    "A king in armour, his sword point-down at his feet. The face has been chiselled away.",
+    "A weeping woman, carved so finely that the tears seem wet.",
+    "A crowned skeleton holding an hourglass. The sand in it is real, and never runs out.",
+    "An orc warrior, in the act of kneeling. Someone has chalked a rude word on it.",
+    "HERE LIES ALDRIC THE SECOND, WHO RULED NINE YEARS AND WAS BURIED WITH ALL HIS GOLD. \
+     FOLLOW HIM AT YOUR PERIL.",
+    "A list of names, each one struck through but the last. The last one is still wet.",
+    "The kings were buried deep so that they would not hear the living. Some of them heard anyway.",
+    "A map of the tombs, scratched into the stone. Most of it is marked 'NO'.",
+];
+
+const LORE_CHANCE: f32 = 0.1;
+const SHRINE_CHANCE: f32 = 0.03;
+
+const GHOST_LINES: [&str; 4] = [
+    "The kings sleep below. Let them.",
+    "I came for the gold too, once.",
+    "Mind the water. It is deeper than it looks.",
+    "Have you seen my bones? I had them a moment ago.",
+];
+
+// Things to bump into which aren't monsters
+fn place_statue(x: i32, y: i32, objects: &mut Vec<Object>, rng: &mut StdRng) {
+    let mut statue = Object::new(x, y, '&', "statue", colors::LIGHT_GREY);
+    statue.on_interact = Some(InteractKind::Lore(rng.gen_range(0, LORE.len())));
+    objects.push(statue);
+}
+
+fn place_barrel(x: i32, y: i32, objects: &mut Vec<Object>) {
+    let mut barrel = Object::new(x, y, '0', "barrel", colors::DARKER_ORANGE);
+    barrel.destructible = Some(BARREL_HP);
+    objects.push(barrel);
+}
+
+fn place_shrine(x: i32, y: i32, objects: &mut Vec<Object>) {
+    let mut shrine = Object::new(x, y, '_', "shrine", colors::LIGHT_SKY);
+    shrine.on_interact = Some(InteractKind::Shrine { used: false });
+    objects.push(shrine);
+}
+
+fn place_merchant(x: i32, y: i32, objects: &mut Vec<Object>) {
+    let mut merchant = Object::new(x, y, '@', "merchant", colors::GOLD);
+    merchant.on_interact = Some(InteractKind::Shop);
+    objects.push(merchant);
+}
+
+fn place_ghost(x: i32, y: i32, objects: &mut Vec<Object>) {
+    let mut ghost = Object::new(x, y, 'G', "ghost", colors::LIGHTEST_SKY);
+    ghost.on_interact = Some(InteractKind::Talk(&GHOST_LINES));
+    objects.push(ghost);
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MonsterTemplate {
+    char: char,
+    name: &'static str,
+    color: Color,
+    max_hp: i32,
+    defense: i32,
+    attack: i32,
+    regen_rate: u32,
+    faction: Faction,
+    can_swim: bool,
+    summoner: bool,
+    abilities: &'static [Ability],
+    // All of this kind on a level share the group, see broadcast_alerts
+    hive: Option<u32>,
+    // Never caught from behind
+    watchful: bool,
+}
+
+const SPIDER_HIVE: u32 = 1;
+
+const ORC: MonsterTemplate = MonsterTemplate {
+    char: 'o',
+    name: "orc",
+    color: colors::DESATURATED_GREEN,
+    max_hp: 10,
+    defense: 0,
+    attack: 3,
+    regen_rate: 0,
+    faction: Faction::Monster,
+    can_swim: false,
+    summoner: false,
+    abilities: &[],
+    hive: None,
+    watchful: false,
+};
+const TROLL: MonsterTemplate = MonsterTemplate {
+    char: 'T',
+    name: "troll",
+    color: colors::DARKER_RED,
+    max_hp: 16,
+    defense: 1,
+    attack: 4,
+    regen_rate: 5,
+    faction: Faction::Monster,
+    can_swim: true,
+    summoner: false,
+    abilities: &[],
+    hive: None,
+    watchful: false,
+};
+const GIANT_SPIDER: MonsterTemplate = MonsterTemplate {
+    char: 'S',
+    name: "giant spider",
+    color: colors::DARK_SEPIA,
+    max_hp: 8,
+    defense: 0,
+    attack: 5,
+    regen_rate: 0,
+    faction: Faction::Monster,
+    can_swim: false,
+    summoner: false,
+    abilities: &[],
+    hive: Some(SPIDER_HIVE),
+    watchful: false,
+};
+const SKELETON: MonsterTemplate = MonsterTemplate {
+    char: 's',
+    name: "skeleton",
+    color: colors::LIGHTEST_GREY,
+    max_hp: 12,
+    defense: 2,
+    attack: 4,
+    regen_rate: 0,
+    faction: Faction::Undead,
+    can_swim: false,
+    summoner: false,
+    abilities: &[],
+    hive: None,
+    watchful: false,
+};
+const ZOMBIE: MonsterTemplate = MonsterTemplate {
+    char: 'Z',
+    name: "zombie",
+    color: colors::DESATURATED_CHARTREUSE,
+    max_hp: 20,
+    defense: 0,
+    attack: 4,
+    regen_rate: 0,
+    faction: Faction::Undead,
+    can_swim: false,
+    summoner: false,
+    abilities: &[],
+    hive: None,
+    watchful: false,
+};
+
+const ORC_SHAMAN: MonsterTemplate = MonsterTemplate {
+    char: 'o',
+    name: "orc shaman",
+    color: colors::LIGHT_GREEN,
+    max_hp: 8,
+    defense: 0,
+    attack: 2,
+    regen_rate: 0,
+    faction: Faction::Monster,
+    can_swim: false,
+    summoner: false,
+    abilities: &[Ability::HealAlly],
+    hive: None,
+    watchful: false,
+};
+const DARK_MAGE: MonsterTemplate = MonsterTemplate {
+    char: 'm',
+    name: "dark mage",
+    color: colors::LIGHT_FLAME,
+    max_hp: 8,
+    defense: 0,
+    attack: 2,
+    regen_rate: 0,
+    faction: Faction::Monster,
+    can_swim: false,
+    summoner: false,
+    abilities: &[Ability::Bolt],
+    hive: None,
+    watchful: false,
+};
+const NECROMANCER: MonsterTemplate = MonsterTemplate {
+    char: 'N',
+    name: "necromancer",
+    color: colors::DARK_VIOLET,
+    max_hp: 12,
+    defense: 0,
+    attack: 2,
+    regen_rate: 0,
+    faction: Faction::Undead,
+    can_swim: false,
+    summoner: true,
+    abilities: &[],
+    hive: None,
+    watchful: false,
+};
+// What necromancers raise from the dead
+const RAISED_SKELETON: MonsterTemplate = MonsterTemplate {
+    char: 's',
+    name: "raised skeleton",
+    color: colors::LIGHT_GREY,
+    max_hp: 6,
+    defense: 0,
+    attack: 2,
+    regen_rate: 0,
+    faction: Faction::Undead,
+    can_swim: false,
+    summoner: false,
+    abilities: &[],
+    hive: None,
+    watchful: false,
+};
+// Waits at the bottom of the dungeon, see BOSS_PHASES
+const ANCIENT_KING: MonsterTemplate = MonsterTemplate {
+    char: 'K',
+    name: "Ancient King",
+    color: colors::LIGHT_AMBER,
+    max_hp: 60,
+    defense: 3,
+    attack: 7,
+    regen_rate: 4,
+    faction: Faction::Undead,
+    can_swim: false,
+    summoner: false,
+    abilities: &[],
+    hive: None,
+    watchful: true,
+};
+
+// Every kind of monster, in the order the bestiary lists them
+const BESTIARY: &[&MonsterTemplate] = &[
+    &ORC,
+    &ORC_SHAMAN,
+    &TROLL,
+    &GIANT_SPIDER,
+    &DARK_MAGE,
+    &SKELETON,
+    &RAISED_SKELETON,
+    &ZOMBIE,
+    &NECROMANCER,
+    &ANCIENT_KING,
+];
+
+fn is_in_bestiary(name: &str) -> bool {
+    BESTIARY.iter().any(|template| template.name == name)
+}
+
+fn place_monster(template: &MonsterTemplate, x: i32, y: i32, objects: &mut Vec<Object>) {
+    objects.push(make_monster(template, x, y));
+}
+
+// Put a monster on a random free tile next to (x, y).  Returns false if
+// they're all taken.  The monster joins the object list at the next
+// flush_pending, so it's safe to call while iterating over the objects.
+fn spawn_monster_near(
+    x: i32,
+    y: i32,
+    template: &MonsterTemplate,
+    game: &mut Game,
+    objects: &[Object],
+) -> bool {
+    let map = &game.map;
+    let mut free = vec![];
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (nx, ny) = (x + dx, y + dy);
+            if (dx, dy) != (0, 0)
+                && nx >= 0
+                && ny >= 0
+                && nx < MAP_WIDTH
+                && ny < MAP_HEIGHT
+                && !map[nx as usize][ny as usize].kind.is_hazard()
+                && !is_blocked(map, nx, ny, objects)
+                && !is_blocked_by_object(nx, ny, &game.pending_spawns)
+            {
+                free.push((nx, ny));
+            }
+        }
+    }
+    match game.rngs.combat.choose(&free) {
+        Some(&(nx, ny)) => {
+            let mut monster = make_monster(template, nx, ny);
+            // Summons join the fight straight away
+            monster.was_seen = true;
+            game.pending_spawns.push(monster);
+            true
+        }
+        None => false,
+    }
+}
+
+// Objects are only added or removed between monster turns, so ids stay valid
+// while the monsters act.  New objects go on the end and removal keeps the order,
+// so the player always stays at index PLAYER.  Returns the tiles that were
+// cleared, which need redrawing.
+fn flush_pending(game: &mut Game, objects: &mut Vec<Object>) -> Vec<(i32, i32)> {
+    objects.append(&mut game.pending_spawns);
+
+    let is_corpse = |id: usize, ob: &Object| id != PLAYER && !ob.is_alive && ob.fighter.is_none();
+    let corpses = objects.iter().enumerate().filter(|&(id, ob)| is_corpse(id, ob)).count();
+    let mut excess = corpses.saturating_sub(MAX_CORPSES);
+    let mut cleared = vec![];
+    let mut id = 0;
+    objects.retain(|ob| {
+        let keep = excess == 0 || !is_corpse(id, ob);
+        if !keep {
+            excess -= 1;
+            cleared.push(ob.pos());
+        }
+        id += 1;
+        keep
+    });
+    cleared
+}
+
+fn place_boss(x: i32, y: i32, objects: &mut Vec<Object>) {
+    let mut boss = make_monster(&ANCIENT_KING, x, y);
+    boss.ai = Some(Ai::Boss { phase: 0 });
+    if let Some(fighter) = boss.fighter.as_mut() {
+        fighter.on_death = DeathCallback::Boss;
+    }
+    objects.push(boss);
+}
+
+fn make_monster(template: &MonsterTemplate, x: i32, y: i32) -> Object {
+    let mut monster = Object::new(x, y, template.char, template.name, template.color);
+    monster.fighter = Some(Fighter {
+        max_hp: template.max_hp,
+        hp: template.max_hp,
+        defense: template.defense,
+        attack: template.attack,
+        regen_rate: template.regen_rate,
+        regen_counter: 0,
+        on_death: DeathCallback::Monster,
+    });
+    monster.ai = Some(if template.summoner {
+        Ai::Summoner { cooldown: 0, summons_left: MAX_SUMMONS }
+    } else {
+        Ai::Basic
+    });
+    monster.can_swim = template.can_swim;
+    monster.faction = template.faction;
+    monster.abilities = template.abilities.to_vec();
+    monster.group_id = template.hive;
+    monster.watchful = template.watchful;
+    monster
+}
+
+// Pick an entry from a table of (thing, weight) pairs
+fn choose_weighted<T: Copy, R: Rng>(table: &[(T, u32)], rng: &mut R) -> T {
+    let total: u32 = table.iter().map(|&(_, weight)| weight).sum();
+    let mut roll = rng.gen_range(0, total);
+    for &(thing, weight) in table {
+        if roll < weight {
+            return thing;
+        }
+        roll -= weight;
+    }
+    unreachable!("roll is always less than the total weight")
+}
+
+fn place_random_monster(
+    theme: &LevelTheme,
+    x: i32,
+    y: i32,
+    objects: &mut Vec<Object>,
+    rng: &mut StdRng,
+) {
+    let template = choose_weighted(theme.monsters, rng);
+    place_monster(template, x, y, objects);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stairs {
+    Up,
+    Down,
+}
+
+fn place_stairs(x: i32, y: i32, direction: Stairs, objects: &mut Vec<Object>) {
+    let mut stairs = match direction {
+        Stairs::Up => Object::new(x, y, '<', "stairs up", colors::WHITE),
+        Stairs::Down => Object::new(x, y, '>', "stairs down", colors::WHITE),
+    };
+    stairs.is_walkable = true;
+    stairs.stairs = Some(direction);
+    objects.push(stairs);
+}
+
+// How crowded generated levels are, as multiples of the usual number of
+// monsters and items.  Zero leaves levels empty, for testing.
+#[derive(Clone, Copy, Debug)]
+struct GenConfig {
+    monster_density: f32,
+    item_density: f32,
+}
+
+// Most of something a room should get, scaled by its size and the density but
+// never more than it has floor for
+fn room_capacity(per_room: i32, density: f32, floor: i32) -> i32 {
+    let scaled = (per_room as f32 * density * floor as f32 / ROOM_DENSITY_AREA as f32).round();
+    min(scaled.max(0.0) as i32, floor)
+}
+
+fn place_objects(
+    room: Rect,
+    objects: &mut Vec<Object>,
+    theme: &LevelTheme,
+    gen: &GenConfig,
+    map: &MapSlice,
+    rng: &mut StdRng,
+) {
+    let floor = ((room.x1 + 1)..room.x2)
+        .flat_map(|x| ((room.y1 + 1)..room.y2).map(move |y| (x, y)))
+        .filter(|&(x, y)| map[x as usize][y as usize].kind == TileKind::Floor)
+        .count() as i32;
+    let max_monsters = room_capacity(MAX_ROOM_MONSTERS, gen.monster_density, floor);
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
+    for _ in 0..num_monsters {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if is_blocked_by_object(x, y, objects)
+            || map[x as usize][y as usize].kind != TileKind::Floor
+        {
+            continue;
+        }
+        place_random_monster(theme, x, y, objects, rng);
+    }
+
+    let max_items = room_capacity(MAX_ROOM_ITEMS, gen.item_density, floor);
+    let num_items = rng.gen_range(0, max_items + 1);
+    for _ in 0..num_items {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        // Rooms that aren't rectangles have wall inside their rect
+        if !is_blocked_by_object(x, y, objects) && map[x as usize][y as usize].is_walkable {
+            place_item(x, y, objects, rng);
+        }
+    }
+
+    if rng.gen::<f32>() < BARREL_CHANCE {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked_by_object(x, y, objects)
+            && map[x as usize][y as usize].kind == TileKind::Floor
+        {
+            place_barrel(x, y, objects);
+        }
+    }
+
+    // The odd statue or shrine, for atmosphere
+    let x = rng.gen_range(room.x1 + 1, room.x2);
+    let y = rng.gen_range(room.y1 + 1, room.y2);
+    if is_blocked_by_object(x, y, objects) || map[x as usize][y as usize].kind != TileKind::Floor {
+        return;
+    }
+    let r = rng.gen::<f32>();
+    if r < SHRINE_CHANCE {
+        place_shrine(x, y, objects);
+    } else if r < SHRINE_CHANCE + LORE_CHANCE {
+        place_statue(x, y, objects, rng);
+    }
+}
+
+// A hand-designed room, stamped into the map in place of a random room.
+// '#' is wall, '.' is floor, 'M' is a monster, '$' is gold, '!' is an item,
+// '&' is a statue, '_' is a shrine, 'P' is a merchant, 'G' is a ghost and 'B'
+// is the boss (all but the first two stand on floor).  Vaults with a boss are
+// only used for the bottom level.
+struct Vault {
+    name: String,
+    width: i32,
+    height: i32,
+    rows: Vec<Vec<char>>,
+    boss: bool,
+}
+
+impl Vault {
+    pub fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let mut rows: Vec<Vec<char>> =
+            text.lines().map(|line| line.trim_end().chars().collect()).collect();
+        // Editors like to leave blank lines at the end of the file
+        while rows.last().is_some_and(Vec::is_empty) {
+            rows.pop();
+        }
+        if rows.is_empty() {
+            return Err("vault is empty".to_string());
+        }
+
+        let width = rows[0].len();
+        for (y, row) in rows.iter().enumerate() {
+            if row.len() != width {
+                return Err(format!("line {} is {} wide, expected {}", y + 1, row.len(), width));
+            }
+            if let Some(c) = row.iter().find(|c| !"#.M$!&_PGB".contains(**c)) {
+                return Err(format!("line {} has unknown symbol '{}'", y + 1, c));
+            }
+        }
+
+        // Leave room for the surrounding wall which make_map expects around each room
+        let (width, height) = (width as i32, rows.len() as i32);
+        if width + 2 > MAP_WIDTH || height + 2 > MAP_HEIGHT {
+            return Err(format!("{}x{} is too big for the map", width, height));
+        }
+        // Tunnels are dug to the center of each room, so it had better be open
+        if rows[(height as usize - 1) / 2][(width as usize - 1) / 2] == '#' {
+            return Err("center of the vault is a wall".to_string());
+        }
+
+        let boss = rows.iter().any(|row| row.contains(&'B'));
+        Ok(Vault { name: name.to_string(), width, height, rows, boss })
+    }
+}
+
+fn load_vaults() -> Vec<Vault> {
+    let mut vaults = vec![];
+    let entries = match fs::read_dir(VAULT_DIR) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("Warning: can't read {}: {}", VAULT_DIR, err);
+            return vaults;
+        }
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let name = path.display().to_string();
+        match fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Vault::parse(&name, &text))
+        {
+            Ok(vault) => vaults.push(vault),
+            Err(err) => println!("Warning: skipping vault {}: {}", name, err),
+        }
+    }
+    // Directory order varies between systems, which would change the map for a given seed
+    vaults.sort_by(|a, b| a.name.cmp(&b.name));
+    vaults
+}
+
+// Read a hand-made level, in the format export_map_ascii writes: '#' or ' '
+// for wall, '.' floor, '~' shallow water, '^' a trap door, '=' a cracked wall,
+// '<' and '>' stairs, and a monster's letter for that monster.  Lines may be
+// shorter than the map, and the rest is wall.  objects[PLAYER] is a stand-in
+// marking where the '@' was, for the real player to take the place of.
+fn load_map_ascii(path: &str) -> Result<(Map, Vec<Object>), String> {
+    let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() > MAP_HEIGHT as usize {
+        return Err(format!("{} lines is more than the map's {}", lines.len(), MAP_HEIGHT));
+    }
+    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut objects = vec![Object::new(0, 0, '@', "start", colors::WHITE)];
+    let mut start = None;
+    for (y, line) in lines.iter().enumerate() {
+        let line = line.trim_end();
+        if line.chars().count() > MAP_WIDTH as usize {
+            return Err(format!("line {} is wider than the map's {}", y + 1, MAP_WIDTH));
+        }
+        for (x, symbol) in line.chars().enumerate() {
+            let (x, y) = (x as i32, y as i32);
+            let tile = &mut map[x as usize][y as usize];
+            match symbol {
+                '#' | ' ' => {}
+                '=' => *tile = Tile::cracked_wall(),
+                '~' => *tile = Tile::new(TileKind::Water { deep: false }),
+                '^' => *tile = Tile::new(TileKind::TrapDoor { hidden: false }),
+                _ => *tile = Tile::empty(),
+            }
+            match symbol {
+                '#' | ' ' | '=' | '~' | '^' | '.' => {}
+                '@' if start.is_some() => {
+                    return Err(format!("line {}, column {}: a second '@'", y + 1, x + 1))
+                }
+                '@' => start = Some((x, y)),
+                '<' => place_stairs(x, y, Stairs::Up, &mut objects),
+                '>' => place_stairs(x, y, Stairs::Down, &mut objects),
+                _ => match BESTIARY.iter().find(|template| template.char == symbol) {
+                    Some(template) => objects.push(make_monster(template, x, y)),
+                    None => {
+                        return Err(format!(
+                            "line {}, column {}: unknown symbol '{}'",
+                            y + 1,
+                            x + 1,
+                            symbol
+                        ))
+                    }
+                },
+            }
+        }
+    }
+    // The '@' always stands on floor, so the start is sure to be walkable
+    match start {
+        Some((x, y)) => objects[PLAYER].set_pos(x, y),
+        None => return Err("there's no '@' to start from".to_string()),
+    }
+    Ok((map, objects))
+}
+
+// Stamp the vault into the map with its top-left corner at (x, y)
+fn place_vault(
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    vault: &Vault,
+    theme: &LevelTheme,
+    x: i32,
+    y: i32,
+    rng: &mut StdRng,
+) {
+    for (dy, row) in vault.rows.iter().enumerate() {
+        for (dx, &symbol) in row.iter().enumerate() {
+            let (tx, ty) = (x + dx as i32, y + dy as i32);
+            map[tx as usize][ty as usize] =
+                if symbol == '#' { Tile::wall() } else { Tile::empty() };
+            match symbol {
+                'M' => place_random_monster(theme, tx, ty, objects, rng),
+                '$' => place_gold(tx, ty, objects, rng),
+                '!' => place_item(tx, ty, objects, rng),
+                '&' => place_statue(tx, ty, objects, rng),
+                '_' => place_shrine(tx, ty, objects),
+                'P' => place_merchant(tx, ty, objects),
+                'G' => place_ghost(tx, ty, objects),
+                'B' => place_boss(tx, ty, objects),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Generator {
+    Tunnels, // Rectangular rooms joined by tunnels
+    Caves,   // One big natural cave
+    Crypt,   // A burial hall lined with niches
+}
+
+#[derive(Debug)]
+struct LevelTheme {
+    name: &'static str,
+    // Deepest dungeon level this theme is used for
+    max_level: u32,
+    generator: Generator,
+    monsters: &'static [(&'static MonsterTemplate, u32)],
+    // Kinds of terrain for pools, may be empty
+    pools: &'static [(TileKind, u32)],
+    dark_wall: Color,
+    light_wall: Color,
+    dark_ground: Color,
+    light_ground: Color,
+}
+
+// Themes from the top of the dungeon down.  The last one is the bottom level,
+// which has no stairs further down.
+const LEVEL_THEMES: &[LevelTheme] = &[
+    LevelTheme {
+        name: "tunnels",
+        max_level: 3,
+        generator: Generator::Tunnels,
+        monsters: &[(&ORC, 70), (&TROLL, 20), (&ORC_SHAMAN, 10)],
+        pools: &[
+            (TileKind::Water { deep: false }, 60),
+            (TileKind::Lava, 20),
+            (TileKind::Chasm, 20),
+        ],
+        dark_wall: COLOR_DARK_WALL,
+        light_wall: COLOR_LIGHT_WALL,
+        dark_ground: COLOR_DARK_GROUND,
+        light_ground: COLOR_LIGHT_GROUND,
+    },
+    LevelTheme {
+        name: "caves",
+        max_level: 6,
+        generator: Generator::Caves,
+        monsters: &[(&ORC, 25), (&TROLL, 30), (&GIANT_SPIDER, 35), (&DARK_MAGE, 10)],
+        pools: &[
+            (TileKind::Water { deep: false }, 50),
+            (TileKind::Lava, 30),
+            (TileKind::Chasm, 20),
+        ],
+        dark_wall: colors::DARKEST_SEPIA,
+        light_wall: colors::SEPIA,
+        dark_ground: colors::DARKER_SEPIA,
+        light_ground: colors::LIGHT_SEPIA,
+    },
+    LevelTheme {
+        name: "crypt of the Ancient Kings",
+        max_level: 7,
+        generator: Generator::Crypt,
+        monsters: &[(&SKELETON, 55), (&ZOMBIE, 35), (&NECROMANCER, 10)],
+        pools: &[],
+        dark_wall: colors::DARKEST_GREY,
+        light_wall: colors::GREY,
+        dark_ground: colors::DARKER_GREY,
+        light_ground: colors::DESATURATED_GREEN,
+    },
+];
+
+fn level_theme(level: u32) -> &'static LevelTheme {
+    LEVEL_THEMES
+        .iter()
+        .find(|theme| level <= theme.max_level)
+        .unwrap_or(&LEVEL_THEMES[LEVEL_THEMES.len() - 1])
+}
+
+fn deepest_level() -> u32 {
+    LEVEL_THEMES[LEVEL_THEMES.len() - 1].max_level
+}
+
+// Each level gets its own generator, so a level always comes out the same for
+// a given seed, whatever the player did on the way down
+fn level_rng(seed: u32, level: u32) -> StdRng {
+    SeedableRng::from_seed(&[seed as usize, level as usize][..])
+}
+
+// Separate streams for what happens during play, all derived from the master
+// seed, so that an extra roll in one never changes the results of another.
+// Level generation doesn't use these: it has level_rng.
+// A developer's record of a game's fights, one readable line per attack or
+// combat roll, for working out afterwards how something strange happened.
+// When it's off nothing is formatted, let alone written.
+struct CombatTrace {
+    file: Option<fs::File>,
+}
+
+impl CombatTrace {
+    pub fn new(path: Option<&str>, seed: u32) -> Self {
+        let file = path.and_then(|path| match fs::File::create(path) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                println!("Warning: can't write {}: {}", path, err);
+                None
+            }
+        });
+        let mut trace = CombatTrace { file };
+        trace.record(|| format!("seed {}", seed));
+        trace
+    }
+
+    // Written straight away, so the trace survives a crash
+    pub fn record<F: FnOnce() -> String>(&mut self, entry: F) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(err) = writeln!(file, "{}", entry()) {
+                println!("Warning: can't write the combat trace: {}", err);
+                self.file = None;
+            }
+        }
+    }
+}
+
+// Work through a trace written by --combat-trace, redoing the arithmetic of
+// every attack and printing the fight with anything that doesn't add up
+fn replay_combat(path: &str) {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("Can't read {}: {}", path, err);
+            return;
+        }
+    };
+    let mut mismatches = 0;
+    for line in text.lines() {
+        let numbers: Option<Vec<i32>> = line
+            .strip_prefix("attack ")
+            .and_then(|attack| attack.split(": ").nth(1))
+            .map(|stats| stats.split_whitespace().filter_map(|word| word.parse().ok()).collect());
+        match numbers.as_deref() {
+            Some(&[power, defense, multiplier, bonus, damage, hp_before, hp_after]) => {
+                let base = power - defense;
+                let expected = if base > 0 { base * multiplier + bonus } else { base };
+                let expected_hp =
+                    if expected > 0 { max(hp_before - expected, 0) } else { hp_before };
+                if (damage, hp_after) == (expected, expected_hp) {
+                    println!("  {}", line);
+                } else {
+                    mismatches += 1;
+                    println!("! {} (expected damage {}, hp {})", line, expected, expected_hp);
+                }
+            }
+            Some(_) => {
+                mismatches += 1;
+                println!("? {}", line);
+            }
+            None => println!("  {}", line),
+        }
+    }
+    println!("{} line(s) didn't add up", mismatches);
+}
+
+// Writes where the player is after every turn, one "turn level x y" line each,
+// for playing back later as a Ghost
+struct MovementRecorder {
+    file: Option<fs::File>,
+}
+
+impl MovementRecorder {
+    pub fn new(path: Option<&str>) -> Self {
+        let file = path.and_then(|path| match fs::File::create(path) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                println!("Warning: can't write {}: {}", path, err);
+                None
+            }
+        });
+        MovementRecorder { file }
+    }
+
+    pub fn record(&mut self, turn: u32, level: u32, (x, y): (i32, i32)) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(err) = writeln!(file, "{} {} {} {}", turn, level, x, y) {
+                println!("Warning: can't write the movement recording: {}", err);
+                self.file = None;
+            }
+        }
+    }
+}
+
+// An earlier run's recorded moves, drawn alongside the player's own.  The two
+// runs needn't last as long as each other: the ghost just stops when its
+// recording does.
+struct Ghost {
+    // (turn, level, x, y), in the order they happened
+    steps: Vec<(u32, u32, i32, i32)>,
+    ended: bool,
+}
+
+impl Ghost {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut steps = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let numbers: Vec<i64> =
+                line.split_whitespace().filter_map(|w| w.parse().ok()).collect();
+            match numbers[..] {
+                [turn, level, x, y] => steps.push((turn as u32, level as u32, x as i32, y as i32)),
+                _ => return Err(format!("line {} isn't \"turn level x y\"", i + 1)),
+            }
+        }
+        Ok(Ghost { steps, ended: false })
+    }
+
+    // Where the ghost was as of `turn`, if the recording goes that far
+    pub fn position(&self, turn: u32) -> Option<(u32, i32, i32)> {
+        if self.steps.last().is_none_or(|&(last, ..)| turn > last) {
+            return None;
+        }
+        let i = self.steps.partition_point(|&(t, ..)| t <= turn);
+        i.checked_sub(1).map(|i| {
+            let (_, level, x, y) = self.steps[i];
+            (level, x, y)
+        })
+    }
+}
+
+// The --ghost recording, if there is one and it can be read
+fn load_ghost(path: Option<&str>) -> Option<Ghost> {
+    let path = path?;
+    match Ghost::load(path) {
+        Ok(ghost) => Some(ghost),
+        Err(err) => {
+            println!("Warning: can't load the ghost {}: {}", path, err);
+            None
+        }
+    }
+}
+
+struct Rngs {
+    // Where things end up on a level after it's made, like a fall's landing
+    map: StdRng,
+    // Anything that decides what happens to a creature
+    combat: StdRng,
+    // What's found, and what's lost
+    loot: StdRng,
+}
+
+impl Rngs {
+    pub fn new(seed: u32) -> Self {
+        // Three-word seeds, so they can never match a level_rng seed
+        let stream = |id: usize| SeedableRng::from_seed(&[seed as usize, 0, id][..]);
+        Rngs { map: stream(0), combat: stream(1), loot: stream(2) }
+    }
+
+    // A loaded game can't pick up the old streams where they left off, so it
+    // gets new ones, which depend on the turn it was saved on
+    pub fn resumed(seed: u32, turn: u32) -> Self {
+        let stream =
+            |id: usize| SeedableRng::from_seed(&[seed as usize, turn as usize + 1, id][..]);
+        Rngs { map: stream(0), combat: stream(1), loot: stream(2) }
+    }
+}
+
+fn make_map(
+    objects: &mut Vec<Object>,
+    vaults: &[Vault],
+    gen: &GenConfig,
+    level: u32,
+    seed: u32,
+) -> Map {
+    let theme = level_theme(level);
+    let mut rng = level_rng(seed, level);
+    let first_new = objects.len();
+    let (mut map, (stairs_x, stairs_y)) = match theme.generator {
+        Generator::Tunnels => make_tunnels(objects, vaults, theme, gen, &mut rng),
+        Generator::Caves => make_caves(objects, theme, gen, &mut rng),
+        Generator::Crypt => make_crypt(objects, theme, gen, &mut rng),
+    };
+    let (stairs_x, stairs_y) = ensure_start(&mut map, objects, level, (stairs_x, stairs_y));
+    if level > 1 {
+        let (x, y) = objects[PLAYER].pos();
+        place_stairs(x, y, Stairs::Up, objects);
+    }
+    if level < deepest_level() {
+        place_stairs(stairs_x, stairs_y, Stairs::Down, objects);
+    } else {
+        // The boss waits where the stairs would have been
+        match vaults.iter().find(|vault| vault.boss) {
+            Some(vault) => {
+                let x = (stairs_x - (vault.width - 1) / 2).clamp(1, MAP_WIDTH - 1 - vault.width);
+                let y = (stairs_y - (vault.height - 1) / 2).clamp(1, MAP_HEIGHT - 1 - vault.height);
+                place_vault(&mut map, objects, vault, theme, x, y, &mut rng);
+            }
+            None => {
+                println!("Warning: no boss vault, placing the boss by itself");
+                place_boss(stairs_x, stairs_y, objects);
+            }
+        }
+        // There's nowhere to fall to from the bottom level
+        for tile in map.iter_mut().flat_map(|column| column.iter_mut()) {
+            if tile.kind == TileKind::Chasm {
+                *tile = Tile::empty();
+            }
+        }
+    }
+    crack_walls(&mut map, &mut rng);
+    if level < deepest_level() {
+        place_trap_doors(&mut map, objects, &mut rng);
+    }
+    place_traps(&mut map, objects, &mut rng);
+    place_light_zones(&mut map, &mut rng);
+    for object in objects[first_new..].iter_mut().filter(|ob| ob.item.is_some()) {
+        let rarity = roll_rarity(level, &mut rng);
+        apply_rarity(object, rarity, level, &mut rng);
+    }
+    map
+}
+
+// If the generator didn't manage to put the player somewhere walkable (say
+// because no room fitted), carve a room in the middle of the map for them and
+// tunnel from it to the biggest open part of the level.  If something that
+// blocks is standing on their spot, move them to the nearest free tile.
+// Returns where the stairs down should go, moved to the player if the
+// generator's spot can't be walked to.
+fn ensure_start(
+    map: &mut Map,
+    objects: &mut [Object],
+    level: u32,
+    stairs: (i32, i32),
+) -> (i32, i32) {
+    let walkable =
+        |map: &Map, (x, y): (i32, i32)| in_map(x, y) && map[x as usize][y as usize].is_walkable;
+    let start = objects[PLAYER].pos();
+    if !walkable(map, start) {
+        println!("Warning: nowhere to start on level {}, carving a room", level);
+        let room = Rect::new(
+            (MAP_WIDTH - MIN_ROOM_WIDTH) / 2,
+            (MAP_HEIGHT - MIN_ROOM_HEIGHT) / 2,
+            MIN_ROOM_WIDTH,
+            MIN_ROOM_HEIGHT,
+        );
+        let region = largest_region(map);
+        make_room(room, map);
+        let (x, y) = room.center();
+        if let Some(&(rx, ry)) =
+            region.iter().min_by_key(|&&(rx, ry)| (rx - x).abs() + (ry - y).abs())
+        {
+            make_h_tunnel(x, rx, y, map);
+            make_v_tunnel(y, ry, rx, map);
+        }
+        objects[PLAYER].set_pos(x, y);
+    }
+    let occupied = |objects: &[Object], pos: (i32, i32)| {
+        objects
+            .iter()
+            .enumerate()
+            .any(|(id, ob)| id != PLAYER && !ob.is_walkable && ob.pos() == pos)
+    };
+    let start = objects[PLAYER].pos();
+    let distances = connectivity_map(map, &[start]);
+    if occupied(objects, start) {
+        let free = (0..MAP_WIDTH)
+            .flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)))
+            .filter(|&(x, y)| distances[x as usize][y as usize] != u32::MAX)
+            .filter(|&pos| !occupied(objects, pos))
+            .min_by_key(|&(x, y)| distances[x as usize][y as usize]);
+        if let Some((x, y)) = free {
+            objects[PLAYER].set_pos(x, y);
+        }
+    }
+    // Moving the player never leaves the region they started in, so the same
+    // distances still tell whether the stairs can be reached
+    if walkable(map, stairs) && distances[stairs.0 as usize][stairs.1 as usize] != u32::MAX {
+        stairs
+    } else {
+        objects[PLAYER].pos()
+    }
+}
+
+// Hide a few trap doors in the floor, away from the player and anything else
+fn place_trap_doors(map: &mut Map, objects: &[Object], rng: &mut StdRng) {
+    for _ in 0..TRAP_DOOR_TRIES {
+        let x = rng.gen_range(1, MAP_WIDTH - 1);
+        let y = rng.gen_range(1, MAP_HEIGHT - 1);
+        if map[x as usize][y as usize].kind == TileKind::Floor
+            && !objects.iter().any(|ob| ob.pos() == (x, y))
+        {
+            map[x as usize][y as usize] = Tile::new(TileKind::TrapDoor { hidden: true });
+        }
+    }
+}
+
+// Hide spike and dart traps the same way
+fn place_traps(map: &mut Map, objects: &[Object], rng: &mut StdRng) {
+    for _ in 0..TRAP_TRIES {
+        let x = rng.gen_range(1, MAP_WIDTH - 1);
+        let y = rng.gen_range(1, MAP_HEIGHT - 1);
+        let kind =
+            if rng.gen::<f32>() < DART_TRAP_CHANCE { TrapKind::Dart } else { TrapKind::Spikes };
+        if map[x as usize][y as usize].kind == TileKind::Floor
+            && !objects.iter().any(|ob| ob.pos() == (x, y))
+        {
+            let trap = TileKind::Trap { kind, hidden: true, armed: true, rearm_in: 0 };
+            map[x as usize][y as usize] = Tile::new(trap);
+        }
+    }
+}
+
+// Make a few round patches of darkness or bright light, centred on floor.
+// They take in the walls too, so a bright patch shows its whole surroundings.
+fn place_light_zones(map: &mut Map, rng: &mut StdRng) {
+    for _ in 0..LIGHT_ZONE_TRIES {
+        let cx = rng.gen_range(1, MAP_WIDTH - 1);
+        let cy = rng.gen_range(1, MAP_HEIGHT - 1);
+        let radius = rng.gen_range(LIGHT_ZONE_RADIUS.0, LIGHT_ZONE_RADIUS.1 + 1);
+        let light =
+            if rng.gen::<f32>() < DARK_ZONE_CHANCE { LightLevel::Dark } else { LightLevel::Bright };
+        if !map[cx as usize][cy as usize].is_walkable {
+            continue;
+        }
+        for x in max(cx - radius, 0)..min(cx + radius + 1, MAP_WIDTH) {
+            for y in max(cy - radius, 0)..min(cy + radius + 1, MAP_HEIGHT) {
+                if (x - cx).pow(2) + (y - cy).pow(2) <= radius * radius {
+                    map[x as usize][y as usize].light = light;
+                }
+            }
+        }
+    }
+}
+
+// Crack some of the walls which separate two open areas, making shortcuts for
+// anyone willing to break through
+fn crack_walls(map: &mut Map, rng: &mut StdRng) {
+    for x in 1..(MAP_WIDTH - 1) as usize {
+        for y in 1..(MAP_HEIGHT - 1) as usize {
+            let open_across = map[x - 1][y].is_walkable && map[x + 1][y].is_walkable;
+            let open_down = map[x][y - 1].is_walkable && map[x][y + 1].is_walkable;
+            if map[x][y].kind == TileKind::Wall
+                && (open_across || open_down)
+                && rng.gen::<f32>() < CRACKED_WALL_CHANCE
+            {
+                map[x][y] = Tile::cracked_wall();
+            }
+        }
+    }
+}
+
+// Flood the floor around (x, y) with the given kind of terrain
+fn make_pool(x: i32, y: i32, radius: i32, kind: TileKind, map: &mut Map) {
+    for px in max(x - radius, 0)..min(x + radius + 1, MAP_WIDTH) {
+        for py in max(y - radius, 0)..min(y + radius + 1, MAP_HEIGHT) {
+            let (dx, dy) = (px - x, py - y);
+            let tile = &mut map[px as usize][py as usize];
+            let distance = dx * dx + dy * dy;
+            if distance <= radius * radius && tile.kind == TileKind::Floor {
+                // Bigger pools of water get deep in the middle
+                *tile = match kind {
+                    TileKind::Water { .. } if distance < (radius - 1) * (radius - 1) => {
+                        Tile::new(TileKind::Water { deep: true })
+                    }
+                    _ => Tile::new(kind),
+                };
+            }
+        }
+    }
+}
+
+fn place_random_pool(x: i32, y: i32, theme: &LevelTheme, map: &mut Map, rng: &mut StdRng) {
+    if theme.pools.is_empty() {
+        return;
+    }
+    let kind = choose_weighted(theme.pools, rng);
+    make_pool(x, y, rng.gen_range(1, MAX_POOL_RADIUS + 1), kind, map);
+}
+
+// Returns the map and where to put the stairs down
+fn make_tunnels(
+    objects: &mut Vec<Object>,
+    vaults: &[Vault],
+    theme: &LevelTheme,
+    gen: &GenConfig,
+    rng: &mut StdRng,
+) -> (Map, (i32, i32)) {
+    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut rooms = vec![];
+    let mut prev_x = 0;
+    let mut prev_y = 0;
+    let ordinary_vaults: Vec<&Vault> = vaults.iter().filter(|vault| !vault.boss).collect();
+
+    for _ in 0..MAX_ROOMS {
+        // The player starts in the first room, so keep that one ordinary
+        let vault = if !rooms.is_empty() && rng.gen::<f32>() < VAULT_CHANCE {
+            rng.choose(&ordinary_vaults).copied()
+        } else {
+            None
+        };
+        // A room's rect includes its walls, a vault's rows only the interior
+        let (w, h) = match vault {
+            Some(vault) => (vault.width + 1, vault.height + 1),
+            None => (
+                rng.gen_range(MIN_ROOM_WIDTH, MAX_ROOM_WIDTH),
+                rng.gen_range(MIN_ROOM_HEIGHT, MAX_ROOM_HEIGHT),
+            ),
+        };
+        let room_rect =
+            Rect::new(rng.gen_range(0, MAP_WIDTH - w), rng.gen_range(0, MAP_HEIGHT - h), w, h);
+
+        let blocked = rooms.iter().any(|other_room| room_rect.intersects_with(other_room));
+        if !blocked {
+            match vault {
+                Some(vault) => {
+                    place_vault(
+                        &mut map,
+                        objects,
+                        vault,
+                        theme,
+                        room_rect.x1 + 1,
+                        room_rect.y1 + 1,
+                        rng,
+                    );
+                }
+                None => {
+                    let shape = choose_weighted(ROOM_SHAPES, rng);
+                    carve_room(shape, room_rect, &mut map, rng);
+                    place_pillars(room_rect, &mut map, rng);
+                    if rng.gen::<f32>() < POOL_CHANCE {
+                        let x = rng.gen_range(room_rect.x1 + 1, room_rect.x2);
+                        let y = rng.gen_range(room_rect.y1 + 1, room_rect.y2);
+                        place_random_pool(x, y, theme, &mut map, rng);
+                        // Keep the center dry, it's where the tunnels (and player) arrive
+                        let (center_x, center_y) = room_rect.center();
+                        map[center_x as usize][center_y as usize] = Tile::empty();
+                    }
+                    place_objects(room_rect, objects, theme, gen, &map, rng);
+                }
+            }
+            let (new_x, new_y) = room_rect.center();
+            if rooms.is_empty() {
+                objects[PLAYER].set_pos(new_x, new_y);
+            } else if rng.gen() {
+                make_h_tunnel(prev_x, new_x, prev_y, &mut map);
+                make_v_tunnel(prev_y, new_y, new_x, &mut map);
+            } else {
+                make_v_tunnel(prev_y, new_y, prev_x, &mut map);
+                make_h_tunnel(prev_x, new_x, new_y, &mut map);
+            }
+            prev_x = new_x;
+            prev_y = new_y;
+            rooms.push(room_rect)
+        }
+    }
+
+    // The last room is as good a place as any for the stairs
+    (map, (prev_x, prev_y))
+}
+
+fn count_wall_neighbours(map: &MapSlice, x: i32, y: i32) -> i32 {
+    let mut walls = 0;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            if (dx, dy) != (0, 0) && !map[(x + dx) as usize][(y + dy) as usize].is_walkable {
+                walls += 1;
+            }
+        }
+    }
+    walls
+}
+
+// Number of steps from the nearest source to every tile, or u32::MAX where no
+// source can be reached.  Movement is 8-directional like everyone's in the
+// game, so a diagonal step counts the same as a straight one.  Only the terrain
+// matters: any walkable tile (hazards included) can be crossed, and objects are
+// ignored.
+fn dijkstra_map(map: &MapSlice, sources: &[(i32, i32)]) -> Vec<Vec<u32>> {
+    dijkstra_map_over(map, sources, MovementMode::EightWay, |tile| tile.is_walkable)
+}
+
+// Like dijkstra_map, but without diagonal steps.  Level generation checks
+// connectivity with this, so that whatever it decides is reachable is
+// reachable in either movement mode.
+fn connectivity_map(map: &MapSlice, sources: &[(i32, i32)]) -> Vec<Vec<u32>> {
+    dijkstra_map_over(map, sources, MovementMode::FourWay, |tile| tile.is_walkable)
+}
+
+// Like dijkstra_map, but only crossing the tiles `passable` allows
+fn dijkstra_map_over<F: Fn(&Tile) -> bool>(
+    map: &MapSlice,
+    sources: &[(i32, i32)],
+    movement: MovementMode,
+    passable: F,
+) -> Vec<Vec<u32>> {
+    let mut distances = vec![vec![u32::MAX; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut queue = VecDeque::new();
+    for &(x, y) in sources {
+        distances[x as usize][y as usize] = 0;
+        queue.push_back((x, y));
+    }
+    // Every step costs the same, so a breadth-first search visits tiles in order
+    // of distance
+    while let Some((x, y)) = queue.pop_front() {
+        let next = distances[x as usize][y as usize] + 1;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if in_map(nx, ny)
+                    && movement.allows(dx, dy)
+                    && passable(&map[nx as usize][ny as usize])
+                    && distances[nx as usize][ny as usize] > next
+                {
+                    distances[nx as usize][ny as usize] = next;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+    }
+    distances
+}
+
+// Find the biggest group of walkable tiles which are all reachable from each other
+fn largest_region(map: &MapSlice) -> Vec<(i32, i32)> {
+    let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut largest = vec![];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if seen[x as usize][y as usize] || !map[x as usize][y as usize].is_walkable {
+                continue;
+            }
+            let distances = connectivity_map(map, &[(x, y)]);
+            let mut region = vec![];
+            for rx in 0..MAP_WIDTH {
+                for ry in 0..MAP_HEIGHT {
+                    if distances[rx as usize][ry as usize] != u32::MAX {
+                        seen[rx as usize][ry as usize] = true;
+                        region.push((rx, ry));
+                    }
+                }
+            }
+            if region.len() > largest.len() {
+                largest = region;
+            }
+        }
+    }
+    largest
+}
+
+// Cellular automaton caves: start from noise, then repeatedly smooth it out
+fn make_caves(
+    objects: &mut Vec<Object>,
+    theme: &LevelTheme,
+    gen: &GenConfig,
+    rng: &mut StdRng,
+) -> (Map, (i32, i32)) {
+    let (mut map, cave) = loop {
+        let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        // The edge of the map always stays solid
+        for x in 1..MAP_WIDTH - 1 {
+            for y in 1..MAP_HEIGHT - 1 {
+                if rng.gen::<f32>() >= CAVE_WALL_CHANCE {
+                    map[x as usize][y as usize] = Tile::empty();
+                }
+            }
+        }
+        for _ in 0..CAVE_SMOOTHING_STEPS {
+            let previous = map.clone();
+            for x in 1..MAP_WIDTH - 1 {
+                for y in 1..MAP_HEIGHT - 1 {
+                    map[x as usize][y as usize] = match count_wall_neighbours(&previous, x, y) {
+                        0..=3 => Tile::empty(),
+                        5..=8 => Tile::wall(),
+                        _ => previous[x as usize][y as usize],
+                    };
+                }
+            }
+        }
+
+        // Fill in every pocket which isn't part of the main cave
+        let cave = largest_region(&map);
+        if cave.len() >= MIN_CAVE_SIZE {
+            let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+            for &(x, y) in &cave {
+                map[x as usize][y as usize] = Tile::empty();
+            }
+            break (map, cave);
+        }
+    };
+
+    for _ in 0..cave.len() / CAVE_TILES_PER_POOL {
+        let (x, y) = cave[rng.gen_range(0, cave.len())];
+        place_random_pool(x, y, theme, &mut map, rng);
+    }
+    let cave: Vec<(i32, i32)> = cave
+        .into_iter()
+        .filter(|&(x, y)| map[x as usize][y as usize].kind == TileKind::Floor)
+        .collect();
+
+    let (player_x, player_y) = cave[rng.gen_range(0, cave.len())];
+    objects[PLAYER].set_pos(player_x, player_y);
+    let num_monsters = (cave.len() / CAVE_TILES_PER_MONSTER) as f32 * gen.monster_density;
+    for _ in 0..num_monsters.round() as usize {
+        let (x, y) = cave[rng.gen_range(0, cave.len())];
+        if !is_blocked_by_object(x, y, objects) {
+            place_random_monster(theme, x, y, objects, rng);
+        }
+    }
+    let num_items = (cave.len() / CAVE_TILES_PER_ITEM) as f32 * gen.item_density;
+    for _ in 0..num_items.round() as usize {
+        let (x, y) = cave[rng.gen_range(0, cave.len())];
+        if !is_blocked_by_object(x, y, objects) {
+            place_item(x, y, objects, rng);
+        }
+    }
+
+    // Make the player walk for it
+    let stairs = *cave
+        .iter()
+        .max_by_key(|&&(x, y)| max((x - player_x).abs(), (y - player_y).abs()))
+        .unwrap();
+    (map, stairs)
+}
+
+// One long burial hall across the middle of the map, lined with niches above and below
+fn make_crypt(
+    objects: &mut Vec<Object>,
+    theme: &LevelTheme,
+    gen: &GenConfig,
+    rng: &mut StdRng,
+) -> (Map, (i32, i32)) {
+    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let hall = Rect::new(2, (MAP_HEIGHT - CRYPT_HALL_HEIGHT) / 2, MAP_WIDTH - 5, CRYPT_HALL_HEIGHT);
+    make_room(hall, &mut map);
+    let (_, hall_y) = hall.center();
+    objects[PLAYER].set_pos(hall.x1 + 1, hall_y);
+
+    let mut x = hall.x1 + CRYPT_NICHE_SIZE;
+    while x + CRYPT_NICHE_SIZE < hall.x2 - CRYPT_NICHE_SIZE {
+        for &y in &[hall.y1 - CRYPT_NICHE_SIZE, hall.y2] {
+            let niche = Rect::new(x, y, CRYPT_NICHE_SIZE, CRYPT_NICHE_SIZE);
+            make_room(niche, &mut map);
+            let (door_x, door_y) = niche.center();
+            make_v_tunnel(door_y, hall_y, door_x, &mut map);
+            place_objects(niche, objects, theme, gen, &map, rng);
+        }
+        x += CRYPT_NICHE_SIZE + 2;
+    }
+
+    (map, (hall.x2 - 1, hall_y))
+}
+
+// A level the player has left, kept exactly as it was so it can be revisited
+struct Level {
+    map: Map,
+    // Everything on the level except the player
+    objects: Vec<Object>,
+    notes: HashMap<(i32, i32), String>,
+}
+
+fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let level = game.dungeon_level + 1;
+    change_level(tcod, game, objects, level);
+}
+
+fn prev_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let level = game.dungeon_level - 1;
+    change_level(tcod, game, objects, level);
+}
+
+// Jump straight to any level, for testing.  Levels in between are skipped, and
+// get made whenever the player does pass through them.
+fn warp_to_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>, level: u32) {
+    let level = level.clamp(1, deepest_level());
+    if level == game.dungeon_level {
+        game.messages.add(format!("You're already on level {}", level), colors::WHITE);
+        return;
+    }
+    // Skipping levels is as good as cheating, whether from the W key, the
+    // console or --start-level
+    game.cheated = true;
+    change_level(tcod, game, objects, level);
+    // Nothing makes the player any stronger on the way, so say how outmatched they are
+    if level_theme(level).name != level_theme(1).name {
+        game.messages.add(
+            format!(
+                "You warped to level {} with the {} HP and {} attack you have. Beware!",
+                level,
+                objects[PLAYER].max_hp(game),
+                objects[PLAYER].power(game)
+            ),
+            colors::YELLOW,
+        );
+    }
+}
+
+const MAX_COMMAND_LENGTH: usize = 30;
+
+// Item names the debug console's give command knows
+const CONSOLE_ITEMS: [(&str, Item); 16] = [
+    ("heal", Item::Heal),
+    ("acid", Item::Acid),
+    ("lightning", Item::Lightning),
+    ("confusion", Item::Confusion),
+    ("charm", Item::Charm),
+    ("haste", Item::Haste),
+    ("slow", Item::Slow),
+    ("enchant", Item::Enchant),
+    ("spear", Item::Spear),
+    ("axe", Item::Axe),
+    ("pick", Item::Pick),
+    ("shield", Item::Shield),
+    ("ring", Item::Ring),
+    ("pendant", Item::Pendant),
+    ("digging", Item::Digging(DIG_CHARGES)),
+    ("amulet", Item::Amulet),
+];
+
+// Text commands for testing, until the player closes the console with Escape.
+// Each command's outcome is shown as the console's title.  Monsters spawned
+// here wait in game.pending_spawns like summoned ones, so closing the console
+// after spawning anything ends the turn to bring them in.
+fn debug_console(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let mut title = "Console".to_string();
+    let level = game.dungeon_level;
+    while let Some(text) = text_input(tcod, &title, MAX_COMMAND_LENGTH) {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => continue,
+            ["spawn", name @ ..] => console_spawn(&name.join(" "), game, objects),
+            ["give", name] => console_give(name, 1, game),
+            ["give", name, count] => match count.parse() {
+                Ok(count) => console_give(name, count, game),
+                Err(_) => Err(format!("{} isn't a number", count)),
+            },
+            ["goto", target] => match target.parse() {
+                Ok(target) => {
+                    warp_to_level(tcod, game, objects, target);
+                    Ok(format!("Now on level {}", game.dungeon_level))
+                }
+                Err(_) => Err(format!("{} isn't a level", target)),
+            },
+            ["heal"] => {
+                let max_hp = objects[PLAYER].max_hp(game);
+                objects[PLAYER].heal(max_hp, game);
+                Ok("Healed".to_string())
+            }
+            ["reveal"] => {
+                for tile in game.map.iter_mut().flatten() {
+                    tile.explored = true;
+                }
+                tcod.fov_changed = true;
+                Ok("Map revealed".to_string())
+            }
+            _ => Err(format!("Unknown command: {}", text.trim())),
+        };
+        // Every command changes the game somehow, so the run earns no unlocks
+        if result.is_ok() {
+            game.cheated = true;
+        }
+        title = match result {
+            Ok(done) => done,
+            Err(err) => format!("Error: {}", err),
+        };
+        // Render so that changes like a revealed map show behind the console
+        render_all(tcod, objects, game, true);
+    }
+    // A change of level throws away anything still waiting to spawn
+    if game.dungeon_level == level && !game.pending_spawns.is_empty() {
+        PlayerAction::TookTurn
+    } else {
+        PlayerAction::DidntTakeTurn
+    }
+}
+
+// Queue a monster, by its bestiary name, next to the player
+fn console_spawn(name: &str, game: &mut Game, objects: &[Object]) -> Result<String, String> {
+    let template = match BESTIARY.iter().find(|template| template.name == name) {
+        Some(template) => template,
+        None => return Err(format!("No monster called {}", name)),
+    };
+    let (px, py) = objects[PLAYER].pos();
+    let pending = &game.pending_spawns;
+    let free = DIRECTIONS.iter().map(|&(dx, dy)| (px + dx, py + dy)).find(|&(x, y)| {
+        !is_blocked(&game.map, x, y, objects) && !pending.iter().any(|ob| ob.pos() == (x, y))
+    });
+    match free {
+        Some((x, y)) => {
+            game.pending_spawns.push(make_monster(template, x, y));
+            Ok(format!("A {} will appear", name))
+        }
+        None => Err("No room next to the player".to_string()),
+    }
+}
+
+fn console_give(name: &str, count: u32, game: &mut Game) -> Result<String, String> {
+    if name == "gold" {
+        game.gold += count as i32;
+        return Ok(format!("Given {} gold", count));
+    }
+    let item = match CONSOLE_ITEMS.iter().find(|&&(item_name, _)| item_name == name) {
+        Some(&(_, item)) => item,
+        None => return Err(format!("No item called {}", name)),
+    };
+    for _ in 0..count {
+        if game.inventory.len() >= INVENTORY_SIZE {
+            return Err("Inventory is full".to_string());
+        }
+        let mut given = make_item(item, 0, 0);
+        // Rings and pendants get their affixes from this.  The thread RNG keeps
+        // the seeded loot stream as it would have been.
+        apply_rarity(&mut given, Rarity::Common, game.dungeon_level, &mut rand::thread_rng());
+        game.inventory.push(given);
+    }
+    Ok(format!("Given {} {}", count, name))
+}
+
+// Drop through the floor to the level below, landing somewhere at random
+fn fall_to_next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    if game.dungeon_level >= deepest_level() {
+        return;
+    }
+    next_level(tcod, game, objects);
+    let mut landings = vec![];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            let tile = &game.map[x as usize][y as usize];
+            if tile.kind == TileKind::Floor && !is_blocked(&game.map, x, y, objects) {
+                landings.push((x, y));
+            }
+        }
+    }
+    if let Some(&(x, y)) = game.rngs.map.choose(&landings) {
+        objects[PLAYER].set_pos(x, y);
+        game.entrance = (x, y);
+    }
+    game.messages.add(format!("You land hard, taking {} damage.", FALL_DAMAGE), colors::RED);
+    objects[PLAYER].take_damage(FALL_DAMAGE, game);
+    if !objects[PLAYER].is_alive {
+        game.killed_by = Some("a fall".to_string());
+    }
+}
+
+fn change_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>, level: u32) {
+    enter_level(game, objects, level);
+    initialise_fov(tcod, &game.map);
+}
+
+// Put the current level away, monsters and all, and bring out `level`, as it
+// was left or freshly made
+fn enter_level(game: &mut Game, objects: &mut Vec<Object>, level: u32) {
+    let going_down = level > game.dungeon_level;
+    game.auto_action = None;
+    game.pending_spawns.clear();
+    game.scent = empty_scent();
+    let left_behind = Level {
+        map: std::mem::take(&mut game.map),
+        objects: objects.split_off(PLAYER + 1),
+        notes: std::mem::take(&mut game.notes),
+    };
+    game.levels.insert(game.dungeon_level, left_behind);
+
+    game.dungeon_level = level;
+    game.deepest_level = max(game.deepest_level, level);
+    let theme = level_theme(level);
+    if let Some(saved) = game.levels.remove(&level) {
+        game.map = saved.map;
+        objects.extend(saved.objects);
+        game.notes = saved.notes;
+        // Arrive on the stairs that lead back where we came from
+        let arrival = if going_down { Stairs::Up } else { Stairs::Down };
+        if let Some(pos) = objects.iter().find(|ob| ob.stairs == Some(arrival)).map(|ob| ob.pos()) {
+            objects[PLAYER].set_pos(pos.0, pos.1);
+        }
+        game.messages.add_cat(
+            format!("You return to the {}.", theme.name),
+            colors::RED,
+            MsgCategory::Lore,
+        );
+    } else {
+        game.messages.add_cat(
+            format!("You descend deeper, into the {}...", theme.name),
+            colors::RED,
+            MsgCategory::Lore,
+        );
+        game.map = make_map(objects, &game.vaults, &game.settings.gen, level, game.seed);
+    }
+    game.entrance = objects[PLAYER].pos();
+}
+
+//
+// primary game stuff
+//
+const SCREEN_WIDTH: i32 = 80;
+// Default for --fps.  Anything below MIN_FPS would make a frame longer than
+// KEY_REPEAT_INTERVAL.
+const LIMIT_FPS: i32 = 20;
+const MIN_FPS: i32 = 10;
+const CHARACTER_SCREEN_WIDTH: i32 = 30;
+const DEATH_SCREEN_WIDTH: i32 = 50;
+const INTERACT_SCREEN_WIDTH: i32 = 40;
+const CONFIRM_SCREEN_WIDTH: i32 = 40;
+const EXAMINE_SCREEN_WIDTH: i32 = 40;
+const MAX_SEED_LENGTH: usize = 10;
+const MAX_LEVEL_LENGTH: usize = 3;
+const MAX_NOTE_LENGTH: usize = 30;
+// Progress kept from one game to the next, see load_meta
+const META_FILE: &str = "meta.txt";
+const DELVER_POTIONS: usize = 1;
+const CRYPT_WALKER_GOLD: i32 = 30;
+// Both should be longer than a frame (1 / MIN_FPS), since only one step is taken per frame
+const KEY_REPEAT_DELAY: Duration = Duration::from_millis(300);
+const KEY_REPEAT_INTERVAL: Duration = Duration::from_millis(100);
+// Pause after each monster action the player can see, with --animate-enemy-turns
+const ENEMY_TURN_DELAY: Duration = Duration::from_millis(80);
+
+fn show_list<U: AsRef<str>>(tcod: &mut Tcod, title: &str, items: &[U]) -> Option<usize> {
+    let items: Vec<(&str, Color)> =
+        items.iter().map(|item| (item.as_ref(), colors::WHITE)).collect();
+    show_colored_list(tcod, title, &items)
+}
+
+// Like show_list, but each entry has its own color
+fn show_colored_list<U: AsRef<str>>(
+    tcod: &mut Tcod,
+    title: &str,
+    items: &[(U, Color)],
+) -> Option<usize> {
+    let (w, h) = (SCREEN_WIDTH / 2, tcod.layout.screen_height / 2);
+    let mut dialog = Offscreen::new(w, h);
+
+    dialog.print_frame(0, 0, w, h, false, BackgroundFlag::Screen, Some(title));
+    for (i, (item, color)) in items.iter().enumerate() {
+        let key = (b'a' + i as u8) as char;
+        dialog.set_default_foreground(*color);
+        dialog.print(1, i as i32 + 1, format!("{}: {}", key, item.as_ref()));
+    }
+
+    blit(&dialog, (0, 0), (w, h), &mut tcod.root, (w / 2, h / 2), 1.0, 1.0);
+    tcod.root.flush();
+    let key = tcod.root.wait_for_keypress(true);
+    if key.printable >= 'a' {
+        let id = key.printable as usize - b'a' as usize;
+        if id < items.len() {
+            return Some(id);
+        }
+    }
+    None
+}
+
+// Show some text in a box and wait for a key
+fn msgbox(tcod: &mut Tcod, title: &str, text: &str, width: i32) {
+    msgbox_key(tcod, title, text, width);
+}
+
+// Ask a yes or no question.  Anything but 'y' counts as no.
+fn confirm(tcod: &mut Tcod, question: &str) -> bool {
+    let text = format!("{}\n\n(y/n)", question);
+    msgbox_key(tcod, "Are you sure?", &text, CONFIRM_SCREEN_WIDTH).printable == 'y'
+}
+
+// A message box which returns the key that dismissed it
+fn msgbox_key(tcod: &mut Tcod, title: &str, text: &str, width: i32) -> Key {
+    let h = tcod.root.get_height_rect(0, 0, width - 2, tcod.layout.screen_height, text) + 2;
+    let mut dialog = Offscreen::new(width, h);
+
+    dialog.print_frame(0, 0, width, h, true, BackgroundFlag::Screen, Some(title));
+    dialog.print_rect(1, 1, width - 2, h - 2, text);
+
+    let (x, y) = ((SCREEN_WIDTH - width) / 2, (tcod.layout.screen_height - h) / 2);
+    blit(&dialog, (0, 0), (width, h), &mut tcod.root, (x, y), 1.0, 1.0);
+    tcod.root.flush();
+    tcod.root.wait_for_keypress(true)
+}
+
+// Let the player type a line of text.  Returns None if they pressed Escape.
+fn text_input(tcod: &mut Tcod, prompt: &str, max_len: usize) -> Option<String> {
+    let (w, h) = (SCREEN_WIDTH / 2, 3);
+    let y = (tcod.layout.screen_height - h) / 2;
+    let mut dialog = Offscreen::new(w, h);
+    let mut text = String::new();
+    while !tcod.root.window_closed() {
+        dialog.print_frame(0, 0, w, h, true, BackgroundFlag::Screen, Some(prompt));
+        dialog.print(1, 1, format!("{}_", text));
+        blit(&dialog, (0, 0), (w, h), &mut tcod.root, (w / 2, y), 1.0, 1.0);
+        tcod.root.flush();
+
+        let key = tcod.root.wait_for_keypress(true);
+        match key.code {
+            KeyCode::Enter => return Some(text),
+            KeyCode::Escape => return None,
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            _ => {
+                if (' '..='~').contains(&key.printable) && text.len() < max_len {
+                    text.push(key.printable);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn show_character(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    if let Some(fighter) = objects[PLAYER].fighter {
+        let text = format!(
+            "Dungeon level: {}\nDeepest level: {}\n\nHP: {}/{}\nAttack: {}\nDefense: {}\nGold: {}\nWeight: {}/{}\n\nMode: {}\nDeaths: {}\nSeed: {}",
+            game.dungeon_level,
+            game.deepest_level,
+            fighter.hp,
+            objects[PLAYER].max_hp(game),
+            objects[PLAYER].power(game),
+            objects[PLAYER].defense(game),
+            game.gold,
+            game.current_weight(),
+            objects[PLAYER].max_weight(),
+            game.mode.name(),
+            game.deaths,
+            game.seed,
+        );
+        msgbox(tcod, "Character", &text, CHARACTER_SCREEN_WIDTH);
+    }
+}
+
+// Full-screen message history.  Tab cycles through the category filters.
+fn show_message_log(tcod: &mut Tcod, game: &Game) {
+    let (w, h) = (SCREEN_WIDTH, tcod.layout.screen_height);
+    let mut window = Offscreen::new(w, h);
+    let mut filter: Option<usize> = None;
+    while !tcod.root.window_closed() {
+        let title = match filter {
+            Some(i) => format!("Messages: {} (Tab to change)", MSG_CATEGORIES[i].name()),
+            None => "Messages: All (Tab to change)".to_string(),
+        };
+        window.set_default_foreground(colors::WHITE);
+        window.print_frame(0, 0, w, h, true, BackgroundFlag::Set, Some(title));
+
+        let mut y = h - 1;
+        let shown = game
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| filter.is_none_or(|i| message.category == MSG_CATEGORIES[i]));
+        for message in shown {
+            let text = message.display_text();
+            y -= window.get_height_rect(1, y, w - 2, 0, &text);
+            if y < 1 {
+                break;
+            }
+            window.set_default_foreground(message.color);
+            window.print_rect(1, y, w - 2, 0, &text);
+        }
+        blit(&window, (0, 0), (w, h), &mut tcod.root, (0, 0), 1.0, 1.0);
+        tcod.root.flush();
+
+        let key = tcod.root.wait_for_keypress(true);
+        if key.code == KeyCode::Tab {
+            filter = match filter {
+                None => Some(0),
+                Some(i) if i + 1 < MSG_CATEGORIES.len() => Some(i + 1),
+                Some(_) => None,
+            };
+        } else {
+            break;
+        }
+    }
+}
+
+// Monsters the player has met, with more about them the more of them die
+fn show_bestiary(tcod: &mut Tcod, game: &Game) {
+    let (w, h) = (SCREEN_WIDTH, tcod.layout.screen_height);
+    let mut window = Offscreen::new(w, h);
+    let known: Vec<_> = BESTIARY
+        .iter()
+        .filter_map(|template| game.bestiary.get(template.name).map(|&kills| (template, kills)))
+        .collect();
+    // Room for the frame and the footer
+    let per_page = (h - 4) as usize;
+    let pages = max(1, known.len().div_ceil(per_page));
+    let mut page = 0;
+    while !tcod.root.window_closed() {
+        window.set_default_background(colors::BLACK);
+        window.clear();
+        window.set_default_foreground(colors::WHITE);
+        window.print_frame(0, 0, w, h, true, BackgroundFlag::Set, Some("Bestiary"));
+        if known.is_empty() {
+            window.print(2, 2, "You haven't met any monsters yet.");
+        }
+        for (i, &(template, kills)) in known.iter().skip(page * per_page).take(per_page).enumerate()
+        {
+            let y = i as i32 + 2;
+            window.set_default_foreground(template.color);
+            window.put_char(2, y, template.char, BackgroundFlag::None);
+            window.set_default_foreground(colors::WHITE);
+            let mut text = format!("{:<16} killed {:>3}", template.name, kills);
+            if kills >= BESTIARY_HP_KILLS {
+                text += &format!("   HP {:>3}", template.max_hp);
+            }
+            if kills >= BESTIARY_STATS_KILLS {
+                text +=
+                    &format!("   attack {:>2}   defense {:>2}", template.attack, template.defense);
+            }
+            window.print(4, y, text);
+        }
+        window.print(
+            2,
+            h - 2,
+            format!("Page {}/{} (Left/Right to turn, any other key to close)", page + 1, pages),
+        );
+        blit(&window, (0, 0), (w, h), &mut tcod.root, (0, 0), 1.0, 1.0);
+        tcod.root.flush();
+
+        let key = tcod.root.wait_for_keypress(true);
+        match key.code {
+            KeyCode::Right if page + 1 < pages => page += 1,
+            KeyCode::Left if page > 0 => page -= 1,
+            KeyCode::Right | KeyCode::Left => {}
+            _ => break,
+        }
+    }
+}
+
+// Key bindings, and what everything on the map looks like
+fn show_help(tcod: &mut Tcod, game: &Game) {
+    let (w, h) = (SCREEN_WIDTH, tcod.layout.screen_height);
+    let mut window = Offscreen::new(w, h);
+    window.set_default_background(colors::BLACK);
+    window.clear();
+    window.set_default_foreground(colors::WHITE);
+    window.print_frame(0, 0, w, h, true, BackgroundFlag::Set, Some("Help (any key to close)"));
+
+    let mut bindings = KEY_BINDINGS.to_vec();
+    if game.settings.debug {
+        bindings.extend_from_slice(DEBUG_KEY_BINDINGS);
+    }
+    for (i, &(keys, action)) in bindings.iter().enumerate() {
+        window.print(2, i as i32 + 2, format!("{:<13}{}", keys, action));
+    }
+
+    // Terrain shows on its usual background, for the current level's theme
+    let theme = level_theme(game.dungeon_level);
+    let column = w / 2;
+    let mut y = 2;
+    for &kind in &[
+        TileKind::Floor,
+        TileKind::Wall,
+        TileKind::Water { deep: false },
+        TileKind::Water { deep: true },
+        TileKind::Lava,
+        TileKind::Chasm,
+        TileKind::TrapDoor { hidden: false },
+        TileKind::Trap { kind: TrapKind::Spikes, hidden: false, armed: true, rearm_in: 0 },
+    ] {
+        window.set_default_foreground(colors::WHITE);
+        window.set_char_background(column, y, kind.colors(theme).0, BackgroundFlag::Set);
+        window.put_char(column, y, kind.glyph(), BackgroundFlag::None);
+        window.print(column + 2, y, kind.name());
+        y += 1;
+    }
+    window.set_char_background(column, y, theme.light_wall, BackgroundFlag::Set);
+    window.put_char(column, y, CRACKED_WALL_GLYPH, BackgroundFlag::None);
+    window.print(column + 2, y, "cracked wall");
+    y += 2;
+
+    // Everything else is an object, so show what the game really makes
+    let mut samples = vec![];
+    samples.push(Object::new(0, 0, '@', "you", colors::WHITE));
+    place_stairs(0, 0, Stairs::Down, &mut samples);
+    place_stairs(0, 0, Stairs::Up, &mut samples);
+    samples.push(Object::new(0, 0, '%', "remains", colors::DARK_RED));
+    for &item in &[
+        Item::Heal,
+        Item::Acid,
+        Item::Lightning,
+        Item::Spear,
+        Item::Ring,
+        Item::Digging(DIG_CHARGES),
+        Item::Gold(0),
+        Item::Amulet,
+    ] {
+        samples.push(make_item(item, 0, 0));
+    }
+    place_barrel(0, 0, &mut samples);
+    place_statue(0, 0, &mut samples, &mut level_rng(game.seed, game.dungeon_level));
+    place_shrine(0, 0, &mut samples);
+    place_merchant(0, 0, &mut samples);
+    place_ghost(0, 0, &mut samples);
+    samples.extend(BESTIARY.iter().map(|template| make_monster(template, 0, 0)));
+    for sample in &samples {
+        if y >= h - 1 {
+            break;
+        }
+        window.set_default_foreground(sample.color);
+        window.put_char(column, y, sample.char, BackgroundFlag::None);
+        window.set_default_foreground(colors::WHITE);
+        window.print(column + 2, y, &sample.name);
+        y += 1;
+    }
+
+    blit(&window, (0, 0), (w, h), &mut tcod.root, (0, 0), 1.0, 1.0);
+    tcod.root.flush();
+    tcod.root.wait_for_keypress(true);
+}
+
+// Practice mode's idea of death: back to the level entrance, fully healed but
+// with some of the player's gold gone
+fn respawn_player(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let lost = game.gold * PRACTICE_GOLD_PENALTY_PERCENT / 100;
+    game.gold -= lost;
+    game.deaths += 1;
+    game.killed_by = None;
+    game.auto_action = None;
+
+    // Whoever did the deed might be standing on the entrance
+    let (x, y) = game.entrance;
+    let distances = dijkstra_map(&game.map, &[(x, y)]);
+    let spot = (0..MAP_WIDTH)
+        .flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)))
+        .filter(|&(x, y)| !is_blocked(&game.map, x, y, objects))
+        .min_by_key(|&(x, y)| distances[x as usize][y as usize]);
+    let player = &mut objects[PLAYER];
+    if let Some((x, y)) = spot {
+        player.set_pos(x, y);
+    }
+    player.is_alive = true;
+    player.status_effects.clear();
+    let max_hp = objects[PLAYER].max_hp(game);
+    if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+        fighter.hp = max_hp;
+    }
+    tcod.fov_changed = true;
+
+    let text = format!(
+        "This was only practice. You wake up where you came into the level, {} gold lighter.",
+        lost
+    );
+    msgbox(tcod, "You died", &text, DEATH_SCREEN_WIDTH);
+}
+
+// Returns true if the player wants to go again in the same dungeon
+fn show_death_screen(tcod: &mut Tcod, game: &Game, objects: &[Object]) -> bool {
+    let killer = game.killed_by.as_ref().map_or("something", |name| name.as_str());
+    let mut text = format!("Killed by {} on dungeon level {}.\n", killer, game.dungeon_level);
+    if let Some(hp) = game.recent_hp.front() {
+        let lost = hp - objects[PLAYER].fighter.map_or(0, |f| f.hp);
+        text += &format!("You lost {} HP in your last {} turns.\n", lost, game.recent_hp.len());
+    }
+    if objects[PLAYER].fighter.is_some() {
+        text += &format!(
+            "\nMax HP: {}\nAttack: {}\nDefense: {}\nGold: {}\n",
+            objects[PLAYER].max_hp(game),
+            objects[PLAYER].power(game),
+            objects[PLAYER].defense(game),
+            game.gold
+        );
+    }
+    if !game.recent_combat.is_empty() {
+        text += "\nThe last blows:\n";
+        for message in &game.recent_combat {
+            text += &format!("{}\n", message);
+        }
+    }
+    text +=
+        &format!("\nSeed: {}\n\nPress r to retry (same seed), any other key to go on.", game.seed);
+    msgbox_key(tcod, "You died!", &text, DEATH_SCREEN_WIDTH).printable == 'r'
+}
+
+fn show_victory_screen(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let mut text =
+        "You stand in the tombs with the Amulet of the Kings in your hand.\n".to_string();
+    if let Some(fighter) = objects[PLAYER].fighter {
+        text += &format!(
+            "\nHP: {}/{}\nAttack: {}\nDefense: {}\nGold: {}\n",
+            fighter.hp,
+            objects[PLAYER].max_hp(game),
+            objects[PLAYER].power(game),
+            objects[PLAYER].defense(game),
+            game.gold
+        );
+    }
+    text += &format!("\nSeed: {}", game.seed);
+    msgbox(tcod, "Victory!", &text, DEATH_SCREEN_WIDTH);
+}
+
+fn cast_heal(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    objects[PLAYER].heal(POTION_HEAL, game);
+    UseResult::UsedUp
+}
+
+fn cast_acid(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    game.messages.add_cat("The acid burns your throat!", colors::LIME, MsgCategory::Item);
+    objects[PLAYER].take_damage(ACID_DAMAGE, game);
+    if !objects[PLAYER].is_alive {
+        game.killed_by = Some("a potion of acid".to_string());
+    }
+    UseResult::UsedUp
+}
+
+// Ties go to the lowest object index, see nearest_enemy
+fn closest_monster(range: i32, metric: Metric, objects: &[Object]) -> Option<usize> {
+    let mut closest: Option<(usize, f32)> = None;
+    for (id, ob) in objects.iter().enumerate() {
+        // Reference code has ai.is_some, but I'm not sure why.
+        // Possibly, it's to guarantee that the confusion spell can
+        // safely dereferen
+        if ob.fighter.is_some() && ob.faction.is_monster() {
+            let dist = ob.distance_to(&objects[PLAYER], metric);
+            if dist <= range as f32 && closest.is_none_or(|(_, best)| dist < best) {
+                closest = Some((id, dist));
+            }
+        }
+    }
+    closest.map(|(id, _)| id)
+}
+
+fn cast_lightning(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let monster_id = closest_monster(LIGHTNING_RANGE, Metric::Euclidean, objects);
+    if let Some(id) = monster_id {
+        strike_lightning(id, LIGHTNING_DAMAGE, game, objects);
+        UseResult::UsedUp
+    } else {
+        game.messages.add_cat("No monsters in range", colors::RED, MsgCategory::Item);
+        UseResult::Cancelled
+    }
+}
+
+// Shared by the scroll, which hits the closest monster, and the spell, which
+// lets the player pick
+fn strike_lightning(id: usize, damage: i32, game: &mut Game, objects: &mut [Object]) {
+    game.messages.add_cat(
+        format!("A lightning bolt strikes the {}!", objects[id].name),
+        colors::LIGHT_BLUE,
+        MsgCategory::Combat,
+    );
+    objects[id].take_damage(damage, game);
+}
+
+// Burn every fighter around a tile the player picks, themselves included if
+// they're too close.  Returns false if cancelled.
+fn cast_fireball(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    let (x, y) = match target_tile(tcod, game, objects, FIREBALL_RANGE, Metric::Euclidean) {
+        Some(pos) => pos,
+        None => return false,
+    };
+    game.messages.add_cat("The fireball explodes!", colors::ORANGE, MsgCategory::Combat);
+    for id in 0..objects.len() {
+        let ob = &objects[id];
+        if ob.is_alive
+            && ob.fighter.is_some()
+            && ob.distance(x, y, Metric::Euclidean) <= FIREBALL_RADIUS as f32
+        {
+            game.messages.add_cat(
+                format!("The {} is burned for {} damage", objects[id].name, FIREBALL_DAMAGE),
+                colors::ORANGE,
+                MsgCategory::Combat,
+            );
+            objects[id].take_damage(FIREBALL_DAMAGE, game);
+            if id == PLAYER && !objects[PLAYER].is_alive {
+                game.killed_by = Some("their own fireball".to_string());
+            }
+        }
+    }
+    true
+}
+
+fn cast_spell(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    spell_id: usize,
+) -> PlayerAction {
+    let spell = game.spells[spell_id];
+    if game.spell_cooldowns.get(&spell).is_some_and(|&turns| turns > 0) {
+        game.messages.add_cat(
+            format!("The {} spell isn't ready yet.", spell.name()),
+            colors::RED,
+            MsgCategory::Combat,
+        );
+        return PlayerAction::DidntTakeTurn;
+    }
+    if game.mana < spell.mana_cost() {
+        game.messages.add_cat("Not enough mana.", colors::RED, MsgCategory::Combat);
+        return PlayerAction::DidntTakeTurn;
+    }
+    let cast = match spell {
+        Spell::Fireball => cast_fireball(tcod, game, objects),
+        Spell::Lightning => match cycle_target(tcod, game, objects, LIGHTNING_RANGE) {
+            Some(id) => {
+                strike_lightning(id, SPELL_LIGHTNING_DAMAGE, game, objects);
+                true
+            }
+            None => false,
+        },
+        Spell::Heal => {
+            game.messages.add_cat(
+                "Your wounds start to close",
+                colors::LIGHT_VIOLET,
+                MsgCategory::Combat,
+            );
+            objects[PLAYER].heal(SPELL_HEAL, game);
+            true
+        }
+    };
+    if !cast {
+        return PlayerAction::DidntTakeTurn;
+    }
+    game.mana -= spell.mana_cost();
+    // One more than the cooldown, as it ticks down at the end of this turn
+    game.spell_cooldowns.insert(spell, spell.cooldown() + 1);
+    PlayerAction::TookTurn
+}
+
+fn show_spell_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let names: Vec<(String, Color)> = game
+        .spells
+        .iter()
+        .map(|&spell| {
+            let cooldown = game.spell_cooldowns.get(&spell).cloned().unwrap_or(0);
+            if cooldown > 0 {
+                (format!("{} (ready in {})", spell.name(), cooldown), colors::GREY)
+            } else if game.mana < spell.mana_cost() {
+                (format!("{} ({} mana)", spell.name(), spell.mana_cost()), colors::GREY)
+            } else {
+                (format!("{} ({} mana)", spell.name(), spell.mana_cost()), colors::WHITE)
+            }
+        })
+        .collect();
+    let title = format!("Cast which spell? {}/{} mana", game.mana, game.max_mana);
+    match show_colored_list(tcod, &title, &names) {
+        Some(id) => cast_spell(tcod, game, objects, id),
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
+fn cast_dig(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let charges = match game.inventory[inventory_id].item {
+        Some(Item::Digging(charges)) => charges,
+        _ => return UseResult::Cancelled,
+    };
+    let from = objects[PLAYER].pos();
+    let target = match target_tile(tcod, game, objects, DIG_RANGE, Metric::Euclidean) {
+        Some(target) if target != from => target,
+        _ => return UseResult::Cancelled,
+    };
+    // Carry on past the target, out to the wand's full range
+    let (dx, dy) = (target.0 - from.0, target.1 - from.1);
+    let scale = DIG_RANGE as f32 / ((dx * dx + dy * dy) as f32).sqrt();
+    let to =
+        (from.0 + (dx as f32 * scale).round() as i32, from.1 + (dy as f32 * scale).round() as i32);
+    let dug = dig(tcod, game, objects, from, to);
+    if dug > 0 {
+        game.messages.add_cat(
+            "The rock melts away before you!",
+            colors::LIGHT_AMBER,
+            MsgCategory::Item,
+        );
+    } else {
+        game.messages.add_cat(
+            "The wand hums, but there's nothing to dig",
+            colors::WHITE,
+            MsgCategory::Item,
+        );
+    }
+
+    let charges = charges - 1;
+    if charges <= 0 {
+        game.messages.add_cat("The wand crumbles to dust", colors::LIGHT_AMBER, MsgCategory::Item);
+        return UseResult::UsedUp;
+    }
+    let wand = &mut game.inventory[inventory_id];
+    wand.item = Some(Item::Digging(charges));
+    wand.name = format!("{}wand of digging ({})", wand.rarity.prefix(), charges);
+    UseResult::UsedAndKept
+}
+
+fn cast_confusion(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let monster_id = closest_monster(LIGHTNING_RANGE, Metric::Euclidean, objects);
+    if let Some(id) = monster_id {
+        game.messages.add_cat(
+            format!("The {} begins acting strangely!", objects[id].name),
+            colors::LIGHT_BLUE,
+            MsgCategory::Combat,
+        );
+        objects[id].ai = Some(Ai::Confused {
+            previous: Box::new(objects[id].ai.clone().unwrap()),
+            turns: CONFUSION_TURNS,
+        });
+        UseResult::UsedUp
+    } else {
+        game.messages.add_cat("No monsters in range", colors::RED, MsgCategory::Item);
+        UseResult::Cancelled
+    }
+}
+
+// Pick something to enchant.  Backing out, or picking something which can't
+// take it, leaves the scroll unread.
+fn cast_enchant(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    let names: Vec<(String, Color)> =
+        game.inventory.iter().map(|ob| (game.item_name(ob), ob.rarity.color())).collect();
+    let target = match show_colored_list(tcod, "Enchant which item?", &names) {
+        Some(target) if target != inventory_id => target,
+        _ => {
+            game.messages.add_cat("cancelled", colors::WHITE, MsgCategory::Item);
+            return UseResult::Cancelled;
+        }
+    };
+    let item = &mut game.inventory[target];
+    let equipment = match item.equipment.as_mut() {
+        Some(equipment) => equipment,
+        None => {
+            let message = format!("The {} can't be enchanted", item.name);
+            game.messages.add_cat(message, colors::YELLOW, MsgCategory::Item);
+            return UseResult::Cancelled;
+        }
+    };
+    if equipment.enchantment >= MAX_ENCHANTMENT {
+        let message = format!("The {} can take no more enchantment", item.name);
+        game.messages.add_cat(message, colors::YELLOW, MsgCategory::Item);
+        return UseResult::Cancelled;
+    }
+
+    let old_suffix = format!(" +{}", equipment.enchantment);
+    equipment.enchantment += 1;
+    match equipment.slot {
+        Slot::MainHand => equipment.attack_bonus += 1,
+        Slot::OffHand | Slot::LeftRing | Slot::RightRing | Slot::Neck => {
+            equipment.defense_bonus += 1
+        }
+    }
+    let base = item.name.strip_suffix(&old_suffix).unwrap_or(&item.name);
+    item.name = format!("{} +{}", base, equipment.enchantment);
+    let message = format!("It glows, and is now a {}", item.name);
+    game.messages.add_cat(message, colors::LIGHT_GREEN, MsgCategory::Item);
+    UseResult::UsedUp
+}
+
+fn cast_haste(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    game.messages.add_cat("You feel yourself speed up!", colors::LIGHT_YELLOW, MsgCategory::Item);
+    objects[PLAYER].set_speed_effect(StatusEffect::Hasted { turns: HASTE_TURNS });
+    UseResult::UsedUp
+}
+
+fn cast_slow(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let monster_id = closest_monster(LIGHTNING_RANGE, Metric::Euclidean, objects);
+    if let Some(id) = monster_id {
+        game.messages.add_cat(
+            format!("The {} slows down!", objects[id].name),
+            colors::LIGHT_BLUE,
+            MsgCategory::Combat,
+        );
+        objects[id].set_speed_effect(StatusEffect::Slowed { turns: SLOW_TURNS });
+        UseResult::UsedUp
+    } else {
+        game.messages.add_cat("No monsters in range", colors::RED, MsgCategory::Item);
+        UseResult::Cancelled
+    }
+}
+
+fn cast_charm(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    if let Some(id) = target_monster(tcod, game, objects, CHARM_RANGE, Metric::Euclidean) {
+        game.messages.add_cat(
+            format!("The {} is now your ally!", objects[id].name),
+            colors::PINK,
+            MsgCategory::Combat,
+        );
+        let faction = objects[id].faction;
+        objects[id].faction = Faction::Player;
+        objects[id].status_effects.push(StatusEffect::Charmed { turns: CHARM_TURNS, faction });
+        UseResult::UsedUp
+    } else {
+        game.messages.add_cat("cancelled", colors::WHITE, MsgCategory::Item);
+        UseResult::Cancelled
+    }
+}
+
+// Wield, wear or put away a piece of equipment, putting away whatever was in
+// its way first
+fn toggle_equipment(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let mut chosen = match &game.inventory[inventory_id].equipment {
+        Some(equipment) => equipment.clone(),
+        None => return UseResult::Cancelled,
+    };
+    let equip = !chosen.equipped;
+    // A ring goes on a free hand, or replaces the one on the left if both are taken
+    if equip && chosen.slot.is_ring() {
+        chosen.slot = [Slot::LeftRing, Slot::RightRing]
+            .iter()
+            .copied()
+            .find(|&slot| equipped_in(slot, game).is_none())
+            .unwrap_or(Slot::LeftRing);
+        if let Some(equipment) = game.inventory[inventory_id].equipment.as_mut() {
+            equipment.slot = chosen.slot;
+        }
+    }
+    let mut put_away = vec![];
+    for (id, item) in game.inventory.iter_mut().enumerate() {
+        if let Some(equipment) = item.equipment.as_mut() {
+            if id == inventory_id {
+                equipment.equipped = equip;
+            } else if equip && equipment.equipped && equipment.conflicts_with(&chosen) {
+                // A two-handed weapon and a shield push each other out
+                equipment.equipped = false;
+                put_away.push(item.name.clone());
+            }
+        }
+    }
+    for name in put_away {
+        game.messages.add_cat(
+            format!("You put away the {}", name),
+            colors::LIGHT_YELLOW,
+            MsgCategory::Item,
+        );
+    }
+    objects[PLAYER].clamp_hp(game);
+    // Light from affixes of the Sun may have come or gone
+    tcod.fov_changed = true;
+    let name = game.inventory[inventory_id].name.clone();
+    let verb = match chosen.slot {
+        Slot::MainHand => "wield",
+        Slot::OffHand => "ready",
+        Slot::LeftRing | Slot::RightRing | Slot::Neck => "put on",
+    };
+    if equip {
+        game.messages.add_cat(
+            format!("You {} the {}", verb, name),
+            colors::LIGHT_GREEN,
+            MsgCategory::Item,
+        );
+    } else {
+        game.messages.add_cat(
+            format!("You put away the {}", name),
+            colors::LIGHT_YELLOW,
+            MsgCategory::Item,
+        );
+    }
+    UseResult::UsedAndKept
+}
+
+fn use_item(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    if let Some(item) = game.inventory[inventory_id].item {
+        // Drinking an unknown potion is a gamble, which finds out what it is
+        if POTIONS.contains(&item) && !game.identified.contains(&item) {
+            let name = game.item_name(&game.inventory[inventory_id]);
+            let question = format!("Drink the unknown {}?", name);
+            if game.settings.confirm_risky_moves && !confirm(tcod, &question) {
+                return UseResult::Cancelled;
+            }
+            game.identified.push(item);
+            game.messages.add_cat(
+                format!("The {} was a {}!", name, game.inventory[inventory_id].name),
+                colors::WHITE,
+                MsgCategory::Item,
+            );
+        }
+        let on_use = match item {
+            Item::Heal => cast_heal,
+            Item::Acid => cast_acid,
+            Item::Lightning => cast_lightning,
+            Item::Confusion => cast_confusion,
+            Item::Charm => cast_charm,
+            Item::Haste => cast_haste,
+            Item::Slow => cast_slow,
+            Item::Enchant => cast_enchant,
+            Item::Spear | Item::Axe | Item::Pick | Item::Shield | Item::Ring | Item::Pendant => {
+                toggle_equipment
+            }
+            Item::Digging(_) => cast_dig,
+            // Gold goes straight into the purse, so never ends up in the inventory
+            Item::Gold(_) => return UseResult::Cancelled,
+            Item::Amulet => return UseResult::Cancelled,
+        };
+        match on_use(inventory_id, tcod, game, objects) {
+            UseResult::UsedUp => {
+                // swap_remove is more efficient, but would re-order things,
+                // which might surprise the player
+                game.inventory.remove(inventory_id);
+                UseResult::UsedUp
+            }
+            UseResult::UsedAndKept => UseResult::UsedAndKept,
+            UseResult::Cancelled => UseResult::Cancelled,
+        }
+    } else {
+        game.messages.add_cat("Not sure what to do with this", colors::YELLOW, MsgCategory::Item);
+        UseResult::Cancelled
+    }
+}
+
+fn show_inventory(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let inv_names: Vec<(String, Color)> = game
+        .inventory
+        .iter()
+        .map(|ob| {
+            let name = match &ob.equipment {
+                Some(equipment) if equipment.equipped => {
+                    format!("{} ({})", ob.name, equipment.slot.worn_label())
+                }
+                Some(equipment) if equipment.two_handed => {
+                    format!("{} (requires both hands)", ob.name)
+                }
+                _ => game.item_name(ob),
+            };
+            (name, ob.rarity.color())
+        })
+        .collect();
+    match show_colored_list(tcod, "Inventory", &inv_names) {
+        Some(id) => match use_item(id, tcod, game, objects) {
+            UseResult::UsedUp | UseResult::UsedAndKept => PlayerAction::TookTurn,
+            UseResult::Cancelled => PlayerAction::DidntTakeTurn,
+        },
+        None => {
+            game.messages.add_cat("cancelled", colors::WHITE, MsgCategory::Item);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+// Splash effects of thrown potions, applied to a fighter near the impact
+fn splash_heal(id: usize, game: &mut Game, objects: &mut [Object]) {
+    game.messages.add_cat(
+        format!("{} looks healthier", objects[id].name),
+        colors::ORANGE,
+        MsgCategory::Item,
+    );
+    objects[id].heal(POTION_HEAL, game);
+}
+
+fn splash_acid(id: usize, game: &mut Game, objects: &mut [Object]) {
+    game.messages.add_cat(
+        format!("The acid burns {}!", objects[id].name),
+        colors::LIME,
+        MsgCategory::Combat,
+    );
+    objects[id].take_damage(ACID_DAMAGE, game);
+    if id == PLAYER && !objects[PLAYER].is_alive {
+        game.killed_by = Some("a splash of acid".to_string());
+    }
+}
+
+// Every fighter close enough to a potion shattering at (x, y) to be splashed
+fn splashed_by(x: i32, y: i32, game: &Game, objects: &[Object]) -> Vec<usize> {
+    (0..objects.len())
+        .filter(|&id| {
+            let ob = &objects[id];
+            ob.is_alive
+                && ob.fighter.is_some()
+                && ob.grid_distance(x, y, game.settings.movement) <= SPLASH_RADIUS
+        })
+        .collect()
+}
+
+fn throw_item(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    let on_splash = match game.inventory[inventory_id].item {
+        Some(Item::Heal) => splash_heal,
+        Some(Item::Acid) => splash_acid,
+        _ => {
+            game.messages.add_cat("You can only throw potions", colors::YELLOW, MsgCategory::Item);
+            return UseResult::Cancelled;
+        }
+    };
+    let (x, y) = match target_tile(tcod, game, objects, THROW_RANGE, Metric::Euclidean) {
+        Some(pos) => pos,
+        None => return UseResult::Cancelled,
+    };
+
+    let potion = game.inventory.remove(inventory_id);
+    game.messages.add_cat(
+        format!("The {} shatters!", game.item_name(&potion)),
+        colors::WHITE,
+        MsgCategory::Item,
+    );
+    let splashed = splashed_by(x, y, game, objects);
+    // Acid eats into barrels and cracked walls too
+    let mut smashed = false;
+    if potion.item == Some(Item::Acid) {
+        for sx in (x - SPLASH_RADIUS)..=(x + SPLASH_RADIUS) {
+            for sy in (y - SPLASH_RADIUS)..=(y + SPLASH_RADIUS) {
+                smashed |= smash(sx, sy, ACID_DAMAGE, false, tcod, game, objects);
+            }
+        }
+    }
+    if splashed.is_empty() && !smashed {
+        game.messages.add_cat("The potion is wasted", colors::WHITE, MsgCategory::Item);
+    }
+    for id in splashed {
+        on_splash(id, game, objects);
+    }
+    UseResult::UsedUp
+}
+
+fn show_throw_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let inv_names: Vec<(String, Color)> =
+        game.inventory.iter().map(|ob| (game.item_name(ob), ob.rarity.color())).collect();
+    match show_colored_list(tcod, "Throw which item?", &inv_names) {
+        Some(id) => match throw_item(id, tcod, game, objects) {
+            UseResult::UsedUp | UseResult::UsedAndKept => PlayerAction::TookTurn,
+            UseResult::Cancelled => PlayerAction::DidntTakeTurn,
+        },
+        None => {
+            game.messages.add_cat("cancelled", colors::WHITE, MsgCategory::Item);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+// Let the player pick a visible tile within range of themselves.  Returns None
+// if they cancel, see pick_tile.
+fn target_tile(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+    max_range: i32,
+    metric: Metric,
+) -> Option<(i32, i32)> {
+    let in_range = |tcod: &Tcod, _: &Game, x: i32, y: i32| {
+        tcod.fov.is_in_fov(x, y) && objects[PLAYER].distance(x, y, metric) <= max_range as f32
+    };
+    game.messages.add("Choose a target, or Escape to cancel", colors::LIGHT_CYAN);
+    pick_tile(tcod, game, objects, &in_range)
+}
+
+// Let the player pick one of the tiles `allowed` accepts, with the mouse or by
+// moving a cursor with the movement keys.  Returns None if they cancel with
+// Escape or the right mouse button.
+fn pick_tile(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+    allowed: &dyn Fn(&Tcod, &Game, i32, i32) -> bool,
+) -> Option<(i32, i32)> {
+    let (mut x, mut y) = objects[PLAYER].pos();
+    while !tcod.root.window_closed() {
+        render_all(tcod, objects, game, false);
+        // Show which tiles can be picked
+        let map_y = tcod.layout.map_y;
+        for tx in 0..MAP_WIDTH {
+            for ty in 0..MAP_HEIGHT {
+                if allowed(tcod, game, tx, ty) {
+                    let flag = BackgroundFlag::Add;
+                    tcod.root.set_char_background(tx, ty + map_y, TARGET_RANGE_TINT, flag);
+                }
+            }
+        }
+        let cursor =
+            if allowed(tcod, game, x, y) { colors::LIGHT_YELLOW } else { colors::DARK_RED };
+        tcod.root.set_char_background(x, y + map_y, cursor, BackgroundFlag::Set);
+        tcod.root.flush();
+
+        let mut selected = false;
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(m))) => {
+                tcod.mouse = m;
+                if m.rbutton_pressed {
+                    return None;
+                }
+                let (mx, my) = tcod.layout.map_tile(m.cx as i32, m.cy as i32);
+                if in_map(mx, my) {
+                    x = mx;
+                    y = my;
+                    selected = m.lbutton_pressed;
+                }
+            }
+            Some((_, Event::Key(k))) => match k.code {
+                KeyCode::Escape => return None,
+                KeyCode::Enter => selected = true,
+                _ => {
+                    if let Some((dx, dy)) = movement_delta(k) {
+                        x = (x + dx).clamp(0, MAP_WIDTH - 1);
+                        y = (y + dy).clamp(0, MAP_HEIGHT - 1);
+                    }
+                }
+            },
+            None => {}
+        }
+        if selected && allowed(tcod, game, x, y) {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+// Write a note on any explored tile of the level, or clear one by leaving it
+// empty.  Notes show when the mouse is over the tile, and stay with the level.
+fn annotate(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let explored =
+        |_: &Tcod, game: &Game, x: i32, y: i32| game.map[x as usize][y as usize].explored;
+    game.messages.add("Choose a tile to note, or Escape to cancel", colors::LIGHT_CYAN);
+    let pos = match pick_tile(tcod, game, objects, &explored) {
+        Some(pos) => pos,
+        None => return,
+    };
+    let text = match text_input(tcod, "Note", MAX_NOTE_LENGTH) {
+        Some(text) => text.trim().to_string(),
+        None => return,
+    };
+    if text.is_empty() {
+        if game.notes.remove(&pos).is_some() {
+            game.messages.add("Note removed.", colors::WHITE);
+        }
+    } else {
+        game.notes.insert(pos, text);
+        game.messages.add("Noted.", colors::WHITE);
+    }
+}
+
+// Look over the items on a tile in sight before picking any up.  Looking is
+// free, so this never takes a turn.
+fn examine(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let has_items = |tcod: &Tcod, _: &Game, x: i32, y: i32| {
+        tcod.fov.is_in_fov(x, y) && objects.iter().any(|ob| ob.pos() == (x, y) && ob.item.is_some())
+    };
+    game.messages.add("Choose an item to examine, or Escape to cancel", colors::LIGHT_CYAN);
+    if let Some((x, y)) = pick_tile(tcod, game, objects, &has_items) {
+        examine_items_at(x, y, tcod, game, objects);
+    }
+}
+
+fn examine_items_at(x: i32, y: i32, tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let descriptions: Vec<_> = objects
+        .iter()
+        .filter(|ob| ob.pos() == (x, y) && ob.item.is_some())
+        .map(|ob| describe_item(game, ob))
+        .collect();
+    if !descriptions.is_empty() {
+        msgbox(tcod, "Examine", &descriptions.join("\n\n"), EXAMINE_SCREEN_WIDTH);
+    }
+}
+
+// Like target_tile, but only accepts a tile with a hostile monster on it
+fn target_monster(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+    max_range: i32,
+    metric: Metric,
+) -> Option<usize> {
+    loop {
+        let (x, y) = target_tile(tcod, game, objects, max_range, metric)?;
+        let monster = objects.iter().position(|ob| {
+            ob.pos() == (x, y) && ob.is_alive && ob.fighter.is_some() && ob.faction.is_monster()
+        });
+        if monster.is_some() {
+            return monster;
+        }
+        game.messages.add("There's no monster there", colors::WHITE);
+    }
+}
+
+// Keyboard targeting: Tab (or Shift+Tab) steps through the visible enemies in
+// range, nearest first, Enter picks the highlighted one and Escape cancels
+fn cycle_target(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+    max_range: i32,
+) -> Option<usize> {
+    let mut targets: Vec<usize> = (0..objects.len())
+        .filter(|&id| {
+            let ob = &objects[id];
+            ob.is_alive
+                && ob.fighter.is_some()
+                && Faction::Player.is_hostile_to(ob.faction)
+                && tcod.fov.is_in_fov(ob.x, ob.y)
+                && objects[PLAYER].distance_to(ob, Metric::Euclidean) <= max_range as f32
+        })
+        .collect();
+    if targets.is_empty() {
+        game.messages.add_cat("No targets in range", colors::RED, MsgCategory::Combat);
+        return None;
+    }
+    // A stable sort, so equally close targets stay in object order
+    targets.sort_by(|&a, &b| {
+        let distance = |id: usize| objects[PLAYER].distance_to(&objects[id], Metric::Euclidean);
+        distance(a).total_cmp(&distance(b))
+    });
+
+    game.messages
+        .add("Tab to change target, Enter to confirm, Escape to cancel", colors::LIGHT_CYAN);
+    let mut current = 0;
+    while !tcod.root.window_closed() {
+        let target = &objects[targets[current]];
+        render_all(tcod, objects, game, false);
+        tcod.root.set_char_background(
+            target.x,
+            target.y + tcod.layout.map_y,
+            colors::LIGHT_YELLOW,
+            BackgroundFlag::Set,
+        );
+        tcod.root.flush();
+
+        let key = tcod.root.wait_for_keypress(true);
+        match key.code {
+            KeyCode::Escape => return None,
+            KeyCode::Enter => return Some(targets[current]),
+            KeyCode::Tab if key.shift => current = (current + targets.len() - 1) % targets.len(),
+            KeyCode::Tab => current = (current + 1) % targets.len(),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn movement_delta(key: Key) -> Option<(i32, i32)> {
+    use tcod::input::KeyCode::*;
+    match (key.code, key.printable) {
+        (Up, _) | (Char, 'k') => Some((0, -1)),
+        (Down, _) | (Char, 'j') => Some((0, 1)),
+        (Left, _) | (Char, 'h') => Some((-1, 0)),
+        (Right, _) | (Char, 'l') => Some((1, 0)),
+        (Char, 'y') => Some((-1, -1)),
+        (Char, 'u') => Some((1, -1)),
+        (Char, 'b') => Some((-1, 1)),
+        (Char, 'n') => Some((1, 1)),
+        _ => None,
+    }
+}
+
+fn is_movement_key(key: Key) -> bool {
+    use tcod::input::KeyCode::*;
+    match key.code {
+        Up | Down | Left | Right => true,
+        Char => "hjklyubn".contains(key.printable),
+        _ => false,
+    }
+}
+
+fn is_same_key(a: Key, b: Key) -> bool {
+    a.code == b.code && (a.code != KeyCode::Char || a.printable == b.printable)
+}
+
+// Read the next input event into tcod.key and tcod.mouse.  With key repeat on,
+// a held movement key is fed back in as a fresh keypress every so often, as long
+// as there are no monsters in view.
+fn read_input(tcod: &mut Tcod, objects: &[Object], key_repeat: bool) {
+    let mask = if key_repeat { input::MOUSE | input::KEY } else { input::MOUSE | input::KEY_PRESS };
+    let now = tcod::system::get_elapsed_time();
+    tcod.key = Default::default();
+    match input::check_for_event(mask) {
+        Some((_, Event::Mouse(m))) => tcod.mouse = m,
+        Some((_, Event::Key(k))) if k.pressed => {
+            // The OS sends its own repeats for held keys, we do our own timing instead
+            if tcod.held_key.is_some_and(|(held, _)| is_same_key(held, k)) {
+                return;
+            }
+            tcod.key = k;
+            tcod.held_key = if key_repeat && is_movement_key(k) {
+                Some((k, now + KEY_REPEAT_DELAY))
+            } else {
+                None
+            };
+        }
+        Some((_, Event::Key(k))) => {
+            if tcod.held_key.is_some_and(|(held, _)| is_same_key(held, k)) {
+                tcod.held_key = None;
+            }
+        }
+        None => {
+            if let Some((held, repeat_at)) = tcod.held_key {
+                let monster_in_view = objects.iter().any(|ob| {
+                    ob.is_alive && ob.faction.is_monster() && tcod.fov.is_in_fov(ob.x, ob.y)
+                });
+                if monster_in_view {
+                    tcod.held_key = None;
+                } else if now >= repeat_at {
+                    tcod.key = held;
+                    tcod.held_key = Some((held, now + KEY_REPEAT_INTERVAL));
+                }
+            }
+        }
+    }
+}
+
+// Wait a while, or until the player presses a key, in which case say so
+fn key_pressed_within(time: Duration) -> bool {
+    let end = tcod::system::get_elapsed_time() + time;
+    while tcod::system::get_elapsed_time() < end {
+        if input::check_for_event(input::KEY_PRESS).is_some() {
+            return true;
+        }
+        tcod::system::sleep(Duration::from_millis(5));
+    }
+    false
+}
+
+// Why the player should stop resting, or doing anything else automatically,
+// if anything.  Every auto-action checks this before each step.
+fn should_interrupt_rest(
+    action: AutoAction,
+    tcod: &Tcod,
+    game: &Game,
+    objects: &[Object],
+) -> Option<String> {
+    let player = &objects[PLAYER];
+    if let AutoAction::Travel { clear: true, .. } = action {
+        if !coast_is_clear(game, objects) {
+            return Some("You are no longer alone!".into());
+        }
+    }
+    if let Some(monster) = objects.iter().find(|ob| {
+        ob.is_alive
+            && ob.fighter.is_some()
+            && ob.faction.is_hostile_to(player.faction)
+            && tcod.fov.is_in_fov(ob.x, ob.y)
+    }) {
+        return Some(format!("You see a {}.", monster.name));
+    }
+    if player.fighter.map_or(0, |f| f.hp) < game.auto_hp {
+        return Some("You are hurt!".into());
+    }
+    // Items, stairs and the like only stop the player the first time they're seen
+    if let Some(name) = game.newly_seen.first() {
+        if !matches!(action, AutoAction::Travel { clear: true, .. }) {
+            return Some(format!("You see a {}.", name));
+        }
+    }
+    None
+}
+
+// No hostile monster anywhere on the level, counting any summoned or spawned
+// this turn which haven't been placed yet
+fn coast_is_clear(game: &Game, objects: &[Object]) -> bool {
+    count_hostiles(objects) == 0 && count_hostiles(&game.pending_spawns) == 0
+}
+
+fn start_auto_action(action: AutoAction, tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    game.auto_hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    if let Some(reason) = should_interrupt_rest(action, tcod, game, objects) {
+        game.messages.add(format!("You can't do that now. {}", reason), colors::WHITE);
+        return;
+    }
+    game.auto_action = Some(action);
+}
+
+// Take the next turn of the player's auto-action, or stop it if something
+// has come up or it's finished
+fn auto_step(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let Some(action) = game.auto_action else {
+        return PlayerAction::DidntTakeTurn;
+    };
+    if let Some(reason) = should_interrupt_rest(action, tcod, game, objects) {
+        game.auto_action = None;
+        game.messages.add(reason, colors::ORANGE);
+        return PlayerAction::DidntTakeTurn;
+    }
+    game.auto_hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    match action {
+        AutoAction::Rest { turns_left } => {
+            let rested = game.auto_hp >= objects[PLAYER].max_hp(game) && game.mana >= game.max_mana;
+            if rested || turns_left == 0 {
+                game.auto_action = None;
+                game.messages.add("You finish resting.", colors::WHITE);
+                return PlayerAction::DidntTakeTurn;
+            }
+            game.auto_action = Some(AutoAction::Rest { turns_left: turns_left - 1 });
+            PlayerAction::TookTurn
+        }
+        AutoAction::Travel { to, .. } => travel_step(to, tcod, game, objects),
+    }
+}
+
+// What each key does, for the help screen.  Keep this in step with handle_keys.
+const KEY_BINDINGS: &[(&str, &str)] = &[
+    ("arrows, hjkl", "move or attack"),
+    ("yubn", "move or attack diagonally"),
+    ("F", "attack in a direction"),
+    ("f", "step away from enemies"),
+    ("C", "step carefully, without giving enemies a free attack"),
+    ("s", "wait a turn"),
+    ("S", "start or stop sneaking"),
+    ("T", "show or hide the tiles your weapon can hit"),
+    ("R", "rest"),
+    (".", "pick up an item"),
+    (",", "pick up everything here"),
+    ("i", "inventory"),
+    ("t", "throw an item"),
+    ("z", "cast a spell"),
+    ("< >", "take the stairs"),
+    ("G", "go to the stairs down"),
+    ("D", "go to the stairs down without stopping, once the level is clear"),
+    ("c", "character sheet"),
+    ("m", "message log"),
+    ("B", "bestiary"),
+    ("N", "write a note on the map"),
+    ("x", "examine an item before picking it up"),
+    ("X", "export the map to a text file"),
+    ("Q", "quick save"),
+    ("? or F1", "this help"),
+    ("Alt+Enter", "toggle fullscreen"),
+    ("Escape", "back to the main menu"),
+];
+const DEBUG_KEY_BINDINGS: &[(&str, &str)] = &[
+    ("W", "warp to a level"),
+    ("I", "toggle god mode (debug builds only)"),
+    ("K", "toggle one-hit kills (debug builds only)"),
+    ("`", "debug console (debug builds only)"),
+];
+// Cheats like god mode and the debug console are for testing deep levels, and
+// can't be switched on in a release build
+const DEBUG_BUILD: bool = cfg!(debug_assertions);
+
+// One step along the shortest way to `to`, keeping to explored tiles and out
+// of lava and chasms
+fn travel_step(
+    to: (i32, i32),
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> PlayerAction {
+    let (x, y) = objects[PLAYER].pos();
+    if (x, y) == to {
+        game.auto_action = None;
+        game.messages.add("You have arrived.", colors::WHITE);
+        return PlayerAction::DidntTakeTurn;
+    }
+    let movement = game.settings.movement;
+    let distances = dijkstra_map_over(&game.map, &[to], movement, |tile| {
+        tile.explored && tile.is_walkable && !tile.kind.avoided_by_player()
+    });
+    let step = (-1..=1)
+        .flat_map(|dx| (-1..=1).map(move |dy| (dx, dy)))
+        .filter(|&(dx, dy)| {
+            let (nx, ny) = (x + dx, y + dy);
+            (dx, dy) != (0, 0) && movement.allows(dx, dy) && in_map(nx, ny)
+        })
+        .min_by_key(|&(dx, dy)| distances[(x + dx) as usize][(y + dy) as usize]);
+    match step {
+        Some((dx, dy)) if distances[(x + dx) as usize][(y + dy) as usize] != u32::MAX => {
+            if is_blocked_by_object(x + dx, y + dy, objects) {
+                game.auto_action = None;
+                game.messages.add("Something is in the way.", colors::WHITE);
+                return PlayerAction::DidntTakeTurn;
+            }
+            player_move_or_attack(dx, dy, tcod, game, objects)
+        }
+        _ => {
+            game.auto_action = None;
+            game.messages.add("You don't know a safe way there.", colors::WHITE);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+// Head for the nearest stairs down the player has seen.  With `clear` the
+// player only goes if there are no monsters left on the level.
+fn go_to_stairs(clear: bool, tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    if clear && !coast_is_clear(game, objects) {
+        game.messages.add("There are still monsters about.", colors::WHITE);
+        return;
+    }
+    let player = &objects[PLAYER];
+    let stairs = objects
+        .iter()
+        .filter(|ob| ob.stairs == Some(Stairs::Down))
+        .filter_map(|ob| ob.remembered_at)
+        .min_by_key(|&(x, y)| player.grid_distance(x, y, game.settings.movement));
+    match stairs {
+        Some(pos) if pos == player.pos() => {
+            game.messages.add("You're already on the stairs.", colors::WHITE)
+        }
+        Some(pos) => start_auto_action(AutoAction::Travel { to: pos, clear }, tcod, game, objects),
+        None => game.messages.add("You haven't found the stairs.", colors::WHITE),
+    }
+}
 
 fn handle_keys(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) -> PlayerAction {
     use tcod::input::KeyCode::*;
     use PlayerAction::*;
 
-    let player_alive = objects[PLAYER].is_alive;
-    match (tcod.key, player_alive) {
-        (Key { code: Enter, alt: true, .. }, _) => {
-            let fullscreen = tcod.root.is_fullscreen();
-            tcod.root.set_fullscreen(!fullscreen);
-            DidntTakeTurn
+    let player_alive = objects[PLAYER].is_alive;
+    match (tcod.key, player_alive) {
+        (Key { code: Enter, alt: true, .. }, _) => {
+            let fullscreen = tcod.root.is_fullscreen();
+            tcod.root.set_fullscreen(!fullscreen);
+            DidntTakeTurn
+        }
+        (Key { code: Escape, .. }, _) => Exit,
+
+        // Arrow Movement Keys
+        (Key { code: Up, .. }, true) => player_move_or_attack(0, -1, tcod, game, objects),
+        (Key { code: Down, .. }, true) => player_move_or_attack(0, 1, tcod, game, objects),
+        (Key { code: Left, .. }, true) => player_move_or_attack(-1, 0, tcod, game, objects),
+        (Key { code: Right, .. }, true) => player_move_or_attack(1, 0, tcod, game, objects),
+
+        // vi-style cardinal movement keys
+        (Key { printable: 'k', .. }, true) => player_move_or_attack(0, -1, tcod, game, objects),
+        (Key { printable: 'j', .. }, true) => player_move_or_attack(0, 1, tcod, game, objects),
+        (Key { printable: 'h', .. }, true) => player_move_or_attack(-1, 0, tcod, game, objects),
+        (Key { printable: 'l', .. }, true) => player_move_or_attack(1, 0, tcod, game, objects),
+
+        // not-really-vi-style diagonal movement keys
+        (Key { printable: 'y' | 'u' | 'b' | 'n', .. }, true)
+            if game.settings.movement == MovementMode::FourWay =>
+        {
+            game.messages.add("Diagonal moves are turned off", colors::WHITE);
+            DidntTakeTurn
+        }
+        (Key { printable: 'y', .. }, true) => player_move_or_attack(-1, -1, tcod, game, objects),
+        (Key { printable: 'u', .. }, true) => player_move_or_attack(1, -1, tcod, game, objects),
+        (Key { printable: 'b', .. }, true) => player_move_or_attack(-1, 1, tcod, game, objects),
+        (Key { printable: 'n', .. }, true) => player_move_or_attack(1, 1, tcod, game, objects),
+
+        (Key { printable: '.', .. }, true) => player_pick_up_here(game, objects),
+        (Key { printable: ',', .. }, true) => pick_up_all(game, objects),
+
+        (Key { printable: '>', .. }, true) => {
+            let player_pos = objects[PLAYER].pos();
+            if objects.iter().any(|ob| ob.stairs == Some(Stairs::Down) && ob.pos() == player_pos) {
+                next_level(tcod, game, objects);
+            } else {
+                game.messages.add("There are no stairs down here", colors::WHITE);
+            }
+            DidntTakeTurn
+        }
+        (Key { printable: '<', .. }, true) => {
+            let player_pos = objects[PLAYER].pos();
+            if objects.iter().any(|ob| ob.stairs == Some(Stairs::Up) && ob.pos() == player_pos) {
+                prev_level(tcod, game, objects);
+            } else {
+                game.messages.add("There are no stairs up here", colors::WHITE);
+            }
+            DidntTakeTurn
+        }
+
+        (Key { printable: 'i', .. }, true) => show_inventory(tcod, game, objects),
+
+        (Key { printable: 'c', .. }, _) => {
+            show_character(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Key { printable: 't', .. }, true) => show_throw_menu(tcod, game, objects),
+        (Key { printable: 'f', .. }, true) => flee_step(tcod, game, objects),
+        (Key { printable: 'F', .. }, true) => {
+            game.messages.add("Attack in which direction?", colors::LIGHT_CYAN);
+            render_all(tcod, objects, game, false);
+            tcod.root.flush();
+            let key = tcod.root.wait_for_keypress(true);
+            match movement_delta(key).filter(|&(dx, dy)| game.settings.movement.allows(dx, dy)) {
+                Some((dx, dy)) => attack_direction(dx, dy, tcod, game, objects),
+                None => DidntTakeTurn,
+            }
+        }
+        (Key { printable: 'C', .. }, true) => {
+            game.messages.add("Step carefully in which direction?", colors::LIGHT_CYAN);
+            render_all(tcod, objects, game, false);
+            tcod.root.flush();
+            let key = tcod.root.wait_for_keypress(true);
+            match movement_delta(key).filter(|&(dx, dy)| game.settings.movement.allows(dx, dy)) {
+                Some((dx, dy)) => {
+                    game.careful_step = true;
+                    let action = player_move_or_attack(dx, dy, tcod, game, objects);
+                    game.careful_step = false;
+                    // Watching your back slows you down
+                    if action == TookTurn {
+                        objects[PLAYER].lost_turns += 1;
+                    }
+                    action
+                }
+                None => DidntTakeTurn,
+            }
+        }
+        (Key { printable: 'm', .. }, _) => {
+            show_message_log(tcod, game);
+            DidntTakeTurn
+        }
+        (Key { printable: 'z', .. }, true) => show_spell_menu(tcod, game, objects),
+        (Key { printable: 'I', .. }, true) if game.settings.debug && DEBUG_BUILD => {
+            game.god_mode = !game.god_mode;
+            game.cheated = true;
+            let state = if game.god_mode { "on" } else { "off" };
+            game.messages.add(format!("God mode {}", state), colors::LIGHT_MAGENTA);
+            DidntTakeTurn
+        }
+        (Key { printable: 'K', .. }, true) if game.settings.debug && DEBUG_BUILD => {
+            game.one_hit_kills = !game.one_hit_kills;
+            game.cheated = true;
+            let state = if game.one_hit_kills { "on" } else { "off" };
+            game.messages.add(format!("One-hit kills {}", state), colors::LIGHT_MAGENTA);
+            DidntTakeTurn
+        }
+        (Key { printable: '`', .. }, true) if game.settings.debug && DEBUG_BUILD => {
+            debug_console(tcod, game, objects)
+        }
+        (Key { printable: 'W', .. }, true) if game.settings.debug => {
+            let text = text_input(tcod, "Warp to level", MAX_LEVEL_LENGTH);
+            match text.and_then(|text| text.trim().parse().ok()) {
+                Some(level) => warp_to_level(tcod, game, objects, level),
+                None => game.messages.add("That isn't a level", colors::YELLOW),
+            }
+            DidntTakeTurn
+        }
+        (Key { printable: '?', .. }, _) | (Key { code: F1, .. }, _) => {
+            show_help(tcod, game);
+            DidntTakeTurn
+        }
+        (Key { printable: 'B', .. }, _) => {
+            show_bestiary(tcod, game);
+            DidntTakeTurn
+        }
+        (Key { printable: 'Q', .. }, true) => {
+            quick_save(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Key { printable: 'X', .. }, _) => {
+            let path = format!("map-{}-{}.txt", game.seed, game.dungeon_level);
+            match export_map_ascii(game, objects, &tcod.fov, &path) {
+                Ok(()) => game.messages.add(format!("Map saved to {}", path), colors::WHITE),
+                Err(err) => {
+                    game.messages.add(format!("Couldn't save {}: {}", path, err), colors::RED)
+                }
+            }
+            DidntTakeTurn
+        }
+        (Key { printable: 's', .. }, true) => TookTurn,
+        (Key { printable: 'N', .. }, true) => {
+            annotate(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Key { printable: 'x', .. }, true) => {
+            examine(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Key { printable: 'T', .. }, true) => {
+            game.show_threats = !game.show_threats;
+            DidntTakeTurn
+        }
+        (Key { printable: 'S', .. }, true) => {
+            game.sneaking = !game.sneaking;
+            let message = if game.sneaking { "You start sneaking." } else { "You stop sneaking." };
+            game.messages.add(message, colors::LIGHT_BLUE);
+            DidntTakeTurn
+        }
+        (Key { printable: 'G', .. }, true) => {
+            go_to_stairs(false, tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Key { printable: 'D', .. }, true) => {
+            go_to_stairs(true, tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Key { printable: 'R', .. }, true) => {
+            start_auto_action(AutoAction::Rest { turns_left: REST_TURNS }, tcod, game, objects);
+            DidntTakeTurn
+        }
+
+        _ => DidntTakeTurn,
+    }
+}
+
+fn get_names_under_mouse(tcod: &Tcod, game: &Game, objects: &[Object]) -> String {
+    let (x, y) = tcod.layout.map_tile(tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+    if !in_map(x, y) {
+        return "".to_string();
+    }
+
+    let mut names = vec![];
+    if tcod.fov.is_in_fov(x, y) {
+        let here: Vec<usize> =
+            (0..objects.len()).filter(|&id| objects[id].pos() == (x, y)).collect();
+        names.extend(here.iter().map(|&id| {
+            let name = game.item_name(&objects[id]);
+            match objects[id].speed_label().filter(|_| objects[id].is_alive) {
+                Some(label) => format!("{} ({})", name, label),
+                None => name,
+            }
+        }));
+        // A lone item also says what it does, if the player knows
+        if let [id] = here[..] {
+            if let Some(kind) = objects[id].item.filter(|_| game.knows(&objects[id])) {
+                names[0] = format!("{}: {}", names[0], kind.description());
+            }
+        }
+    }
+    // The player's own notes show even out of sight
+    if let Some(note) = game.notes.get(&(x, y)) {
+        names.push(format!("note: {}", note));
+    }
+
+    names.join(", ")
+}
+
+fn render_bar(
+    panel: &mut Offscreen,
+    x: i32,
+    y: i32,
+    total_width: i32,
+    _name: &str,
+    value: i32,
+    maximum: i32,
+    bar_color: Color,
+    back_color: Color,
+) {
+    let real_width = value as f32 / maximum as f32 * total_width as f32;
+    let bar_width = real_width.ceil() as i32;
+
+    // render the background first
+    panel.set_default_background(back_color);
+    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+
+    panel.set_default_background(bar_color);
+    if bar_width > 0 {
+        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+    }
+}
+
+// Write what the player knows of the level as plain text, with the screen's
+// glyphs: explored tiles, everything in view, and the things remembered out of
+// it.  It goes through write_atomically, so a failed export never leaves half a
+// map behind.
+fn export_map_ascii(
+    game: &Game,
+    objects: &[Object],
+    fov: &Vision,
+    path: &str,
+) -> std::io::Result<()> {
+    let mut rows = vec![vec![' '; MAP_WIDTH as usize]; MAP_HEIGHT as usize];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            let tile = &game.map[x as usize][y as usize];
+            if tile.explored {
+                let glyph = if tile.destructible.is_some() {
+                    CRACKED_WALL_GLYPH
+                } else {
+                    tile.kind.glyph()
+                };
+                rows[y as usize][x as usize] = glyph;
+            }
+        }
+    }
+    // Same order as render_all, so whatever stands on an item hides it
+    let background = objects.iter().filter(|ob| ob.is_walkable);
+    let foreground = objects.iter().filter(|ob| !ob.is_walkable);
+    for object in background.chain(foreground) {
+        let pos = if fov.is_in_fov(object.x, object.y) {
+            Some(object.pos())
+        } else {
+            object.remembered_at.filter(|_| object.is_walkable)
+        };
+        if let Some((x, y)) = pos {
+            rows[y as usize][x as usize] = object.char;
+        }
+    }
+
+    let mut text = String::new();
+    for row in rows {
+        text.extend(row);
+        text.push('\n');
+    }
+    write_atomically(path, text)
+}
+
+fn render_tile(tcod: &mut Tcod, game: &mut Game, player: &Object, x: i32, y: i32) {
+    let theme = level_theme(game.dungeon_level);
+    let radius = torch_radius(game);
+    let visible = tcod.fov.is_in_fov(x, y);
+    let tile = &mut game.map[x as usize][y as usize];
+    if visible {
+        tile.explored = true;
+    }
+    // Unexplored tiles are never drawn, so don't bother working out their color
+    if !tile.explored {
+        return;
+    }
+
+    let (light, dark) = tile.kind.colors(theme);
+    // Only lit tiles need blending, remembered ones use the fixed dark colors.
+    // Bright tiles are fully lit however far away they are.
+    let color = if visible {
+        let lit = match tile.light.reach(radius) {
+            Some(reach) => light_blend(player.x, player.y, x, y, light, dark, reach as f32),
+            None => light,
+        };
+        footstep_tint(lit, x, y, &game.recent_path)
+    } else {
+        dark
+    };
+    tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+    let glyph = if tile.destructible.is_some() { CRACKED_WALL_GLYPH } else { tile.kind.glyph() };
+    tcod.con.put_char(x, y, glyph, BackgroundFlag::None);
+}
+
+// Tint every visible tile the player could hit this turn, attacking in any
+// direction they can move, with whatever weapon they have now.  Drawn over the
+// map each frame, and the tiles are marked dirty so the next frame starts from
+// their plain colors.  That's at most a couple of dozen tiles.
+fn render_threat_overlay(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    if !game.show_threats || !objects[PLAYER].is_alive {
+        return;
+    }
+    let origin = objects[PLAYER].pos();
+    let weapon = equipped_weapon(game);
+    let mut tiles = vec![];
+    for &(dx, dy) in DIRECTIONS.iter().filter(|&&(dx, dy)| game.settings.movement.allows(dx, dy)) {
+        let target = (origin.0 + dx, origin.1 + dy);
+        if in_map(target.0, target.1) {
+            tiles.push(target);
+            if let Some(weapon) = weapon.as_ref() {
+                tiles.extend(weapon_extra_tiles(origin, dx, dy, weapon, &game.map));
+            }
+        }
+    }
+    tiles.sort_unstable();
+    tiles.dedup();
+    for (x, y) in tiles {
+        if tcod.fov.is_in_fov(x, y) && game.map[x as usize][y as usize].is_walkable {
+            let color = tcod.con.get_char_background(x, y);
+            let color = colors::lerp(color, THREAT_COLOR, THREAT_TINT);
+            tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+            tcod.dirty.push((x, y));
+        }
+    }
+}
+
+// A lit tile's color with the player's trail over it, if they were just here.
+// Only nudges the color, so whatever lighting made it still shows through.
+fn footstep_tint(color: Color, x: i32, y: i32, path: &VecDeque<(i32, i32, u32)>) -> Color {
+    match path.iter().find(|&&(px, py, _)| (px, py) == (x, y)) {
+        Some(&(_, _, age)) => {
+            let fade = 1.0 - age as f32 / FOOTSTEP_TURNS as f32;
+            colors::lerp(color, FOOTSTEP_COLOR, FOOTSTEP_TINT * fade)
+        }
+        None => color,
+    }
+}
+
+// Age the trail by a turn, and add the tile the player just left to it.  With
+// --no-footsteps it stays empty.
+fn update_recent_path(previous_pos: (i32, i32), tcod: &mut Tcod, game: &mut Game, player: &Object) {
+    if !game.settings.footsteps {
+        return;
+    }
+    for step in game.recent_path.iter_mut() {
+        step.2 += 1;
+        tcod.dirty.push((step.0, step.1));
+    }
+    while game.recent_path.back().is_some_and(|&(_, _, age)| age >= FOOTSTEP_TURNS) {
+        game.recent_path.pop_back();
+    }
+    if player.pos() != previous_pos {
+        game.recent_path.retain(|&(x, y, _)| (x, y) != previous_pos);
+        game.recent_path.push_front((previous_pos.0, previous_pos.1, 0));
+        tcod.dirty.push(previous_pos);
+    }
+}
+
+// Living monsters still out to get the player, not counting allies or
+// bystanders
+fn count_hostiles(objects: &[Object]) -> usize {
+    objects
+        .iter()
+        .filter(|ob| {
+            ob.is_alive && ob.fighter.is_some() && Faction::Player.is_hostile_to(ob.faction)
+        })
+        .count()
+}
+
+// Once the last monster on the level dies, say so (just the once) and show the
+// player the way down
+fn check_level_cleared(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    if count_hostiles(objects) > 0 || !game.cleared_levels.insert(game.dungeon_level) {
+        return;
+    }
+    game.messages.add("The level is cleared.", colors::LIGHT_GREEN);
+    for stairs in objects.iter_mut().filter(|ob| ob.stairs == Some(Stairs::Down)) {
+        let (x, y) = stairs.pos();
+        stairs.remembered_at = Some((x, y));
+        game.map[x as usize][y as usize].explored = true;
+        tcod.dirty.push((x, y));
+    }
+}
+
+// Note where the player can see background objects and features now.
+// Anything which turns up out of sight stays unknown until the player actually
+// sees it.  Interesting things seen for the first time go in game.newly_seen.
+fn remember_objects(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    game.newly_seen.clear();
+    for object in objects.iter_mut().filter(|o| o.is_walkable || o.on_interact.is_some()) {
+        if tcod.fov.is_in_fov(object.x, object.y) {
+            match object.remembered_at {
+                // It moved while out of sight, so clear the old glyph
+                Some(pos) if pos != object.pos() => tcod.dirty.push(pos),
+                Some(_) => {}
+                None => {
+                    let notable = object.item.is_some()
+                        || object.stairs.is_some()
+                        || object.on_interact.is_some();
+                    if notable {
+                        game.newly_seen.push(object.name.clone());
+                    }
+                }
+            }
+            object.remembered_at = Some(object.pos());
+        }
+    }
+}
+
+// Work out what the player can see from where they stand
+fn compute_player_fov(tcod: &mut Tcod, game: &Game, player: &Object) {
+    let radius = torch_radius(game);
+    tcod.fov.compute(player.x, player.y, radius, &game.map);
+}
+
+fn render_all(tcod: &mut Tcod, objects: &[Object], game: &mut Game, recompute_fov: bool) {
+    let player = &objects[PLAYER];
+    if recompute_fov {
+        compute_player_fov(tcod, game, player);
+    }
+
+    tcod.con.set_default_foreground(colors::WHITE);
+    // Moving the player shifts the FOV boundary and the lighting of every visible
+    // tile, so that always needs a full redraw.  Otherwise only the tiles marked
+    // dirty since the last frame have changed.
+    if recompute_fov || game.settings.force_full_redraw {
+        tcod.dirty.clear();
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                render_tile(tcod, game, player, x, y);
+            }
+        }
+    } else {
+        let dirty: Vec<(i32, i32)> = tcod.dirty.drain(..).collect();
+        for (x, y) in dirty {
+            render_tile(tcod, game, player, x, y);
+        }
+    }
+
+    render_threat_overlay(tcod, game, objects);
+
+    // Draw "background" objects first.  Out of sight, they're drawn dimmed
+    // where the player last saw them, so loot left behind isn't forgotten.
+    for object in objects {
+        if !object.is_walkable {
+            continue;
+        }
+        if tcod.fov.is_in_fov(object.x, object.y) {
+            object.draw(&mut tcod.con);
+        } else if let Some((x, y)) = object.remembered_at {
+            let color = colors::lerp(object.display_color(), colors::BLACK, REMEMBERED_DIMMING);
+            tcod.con.set_default_foreground(color);
+            tcod.con.put_char(x, y, object.char, BackgroundFlag::None);
+        }
+    }
+    // Then "foreground" objects
+    for object in objects {
+        if tcod.fov.is_in_fov(object.x, object.y) && !object.is_walkable {
+            object.draw(&mut tcod.con);
+        }
+    }
+    // The ghost of an earlier run, on top of everything but the player
+    let ghost = game.ghost.as_ref().and_then(|ghost| ghost.position(game.turn));
+    if let Some((level, x, y)) = ghost {
+        if level == game.dungeon_level && in_map(x, y) && (x, y) != player.pos() {
+            tcod.con.set_default_foreground(GHOST_COLOR);
+            tcod.con.put_char(x, y, '@', BackgroundFlag::None);
+            tcod.dirty.push((x, y));
+        }
+    }
+    // And alert indicators above monsters, unless someone is standing there
+    for object in objects {
+        if let Some(indicator) = object.alert_state.indicator() {
+            let (x, y) = (object.x, object.y - 1);
+            if object.is_alive
+                && object.faction.is_monster()
+                && tcod.fov.is_in_fov(object.x, object.y)
+                && y >= 0
+                && !is_blocked_by_object(x, y, objects)
+            {
+                tcod.con.set_default_foreground(colors::YELLOW);
+                tcod.con.put_char(x, y, indicator, BackgroundFlag::None);
+                // Gets drawn over again next frame if it's still needed
+                tcod.dirty.push((x, y));
+            }
+        }
+    }
+
+    let layout = tcod.layout;
+    blit(&tcod.con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), &mut tcod.root, (0, layout.map_y), 1.0, 1.0);
+    // show the player's stats graphically
+    tcod.panel.set_default_background(colors::BLACK);
+    tcod.panel.clear();
+
+    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    let max_hp = objects[PLAYER].max_hp(game);
+
+    render_bar(
+        &mut tcod.panel,
+        1,
+        1,
+        BAR_WIDTH,
+        "HP",
+        hp,
+        max_hp,
+        colors::LIGHT_RED,
+        colors::DARKER_RED,
+    );
+    // Only casters have any use for mana
+    if !game.spells.is_empty() {
+        render_bar(
+            &mut tcod.panel,
+            1,
+            2,
+            BAR_WIDTH,
+            "Mana",
+            game.mana,
+            game.max_mana,
+            colors::LIGHT_BLUE,
+            colors::DARKER_BLUE,
+        );
+    }
+
+    tcod.panel.set_default_foreground(colors::GOLD);
+    tcod.panel.print_ex(
+        1,
+        3,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Gold: {}", game.gold),
+    );
+
+    // Everything left of the messages has to fit in BAR_WIDTH
+    tcod.panel.set_default_foreground(colors::LIGHT_GREY);
+    tcod.panel.print_ex(
+        1,
+        4,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Dungeon level: {}", game.dungeon_level),
+    );
+    if game.deepest_level > game.dungeon_level {
+        tcod.panel.print_ex(
+            1,
+            5,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("Deepest level: {}", game.deepest_level),
+        );
+    }
+    if game.sneaking {
+        tcod.panel.set_default_foreground(colors::LIGHT_BLUE);
+        tcod.panel.print_ex(1, 6, BackgroundFlag::None, TextAlignment::Left, "Sneaking");
+    }
+    if let Some(label) = objects[PLAYER].speed_label() {
+        tcod.panel.set_default_foreground(colors::LIGHT_YELLOW);
+        tcod.panel.print_ex(10, 6, BackgroundFlag::None, TextAlignment::Left, capitalized(label));
+    }
+
+    tcod.panel.set_default_foreground(colors::WHITE);
+    tcod.panel.set_default_background(colors::LIGHT_GREY);
+    tcod.panel.print_ex(
+        1,
+        0,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        get_names_under_mouse(tcod, game, objects),
+    );
+
+    let mut y = layout.msg_height;
+    let new_messages = game.messages.new_message_count;
+    for (i, message) in game.messages.iter().rev().enumerate() {
+        let (msg, color) = (message.display_text(), message.color);
+        let msg_height = tcod.panel.get_height_rect(layout.msg_x, y, layout.msg_width, 0, &msg);
+        y -= msg_height;
+        if y < 0 {
+            break;
+        }
+        let color =
+            if new_messages > 0 && i >= new_messages { color * OLD_MSG_BRIGHTNESS } else { color };
+        tcod.panel.set_default_foreground(color);
+        tcod.panel.print_rect(layout.msg_x, y, layout.msg_width, 0, &msg);
+    }
+
+    // blit the contents of `panel` to the root console
+    let size = (SCREEN_WIDTH, layout.panel_height);
+    blit(&tcod.panel, (0, 0), size, &mut tcod.root, (0, layout.panel_y), 1.0, 1.0);
+    // show the player's stats
+    /*
+    if let Some(fighter) = objects[PLAYER].fighter {
+        tcod.root.print_ex(
+            1,
+            SCREEN_HEIGHT - 2,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("HP: {}/{} ", fighter.hp, fighter.max_hp),
+        );
+    }
+    */
+}
+
+fn initialise_fov(tcod: &mut Tcod, map: &MapSlice) {
+    tcod.fov.reset(map);
+    // Get rid of whatever was drawn for the previous level
+    tcod.con.clear();
+}
+
+// The player's field of view.  tcod's FOV only has the one radius, so this
+// works out the line of sight with no limit, then keeps only the tiles the
+// light there lets the player see.
+struct Vision {
+    map: FovMap,
+    visible: Vec<Vec<bool>>,
+}
+
+impl Vision {
+    pub fn new() -> Self {
+        Vision {
+            map: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+            visible: vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize],
+        }
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, transparent: bool, walkable: bool) {
+        self.map.set(x, y, transparent, walkable);
+    }
+
+    // Take every tile's properties from the map, as for a new level
+    pub fn reset(&mut self, map: &MapSlice) {
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                let tile = &map[x as usize][y as usize];
+                self.set(x, y, tile.is_transparent, tile.is_walkable);
+            }
+        }
+    }
+
+    pub fn compute(&mut self, origin_x: i32, origin_y: i32, torch_radius: i32, map: &MapSlice) {
+        self.map.compute_fov(origin_x, origin_y, 0, FOV_LIGHT_WALLS, FOV_ALGO);
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                let reach = map[x as usize][y as usize].light.reach(torch_radius);
+                let distance2 = (x - origin_x).pow(2) + (y - origin_y).pow(2);
+                self.visible[x as usize][y as usize] = self.map.is_in_fov(x, y)
+                    && reach.is_none_or(|reach| distance2 <= reach * reach);
+            }
+        }
+    }
+
+    pub fn is_in_fov(&self, x: i32, y: i32) -> bool {
+        in_map(x, y) && self.visible[x as usize][y as usize]
+    }
+}
+
+// Where the map and the message panel go on the screen, which is just tall
+// enough for the two of them
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    screen_height: i32,
+    panel_height: i32,
+    panel_y: i32,
+    map_y: i32,
+    msg_x: i32,
+    msg_width: i32,
+    // Rows the messages can fill, counting down from the top of the panel
+    msg_height: i32,
+}
+
+impl Layout {
+    pub fn new(settings: &Settings) -> Self {
+        let panel_height = settings.panel_height;
+        let (panel_y, map_y) = if settings.panel_top { (0, panel_height) } else { (MAP_HEIGHT, 0) };
+        Layout {
+            screen_height: MAP_HEIGHT + panel_height,
+            panel_height,
+            panel_y,
+            map_y,
+            msg_x: BAR_WIDTH + 2,
+            msg_width: SCREEN_WIDTH - BAR_WIDTH - 2,
+            msg_height: panel_height - 1,
+        }
+    }
+
+    // The map tile at a spot on the screen, which may be off the map
+    pub fn map_tile(&self, screen_x: i32, screen_y: i32) -> (i32, i32) {
+        (screen_x, screen_y - self.map_y)
+    }
+}
+
+struct Tcod {
+    root: Root,
+    layout: Layout,
+    con: Offscreen,
+    panel: Offscreen,
+    fov: Vision,
+    key: Key,
+    mouse: Mouse,
+    // Map tiles which need to be redrawn on the next frame
+    dirty: Vec<(i32, i32)>,
+    // Set when a tile's transparency changed, so the FOV needs recomputing
+    fov_changed: bool,
+    // Movement key being held down, and when it next repeats
+    held_key: Option<(Key, Duration)>,
+}
+
+fn main() {
+    let settings = Settings::from_args();
+    if let Some(path) = settings.replay_combat {
+        replay_combat(&path);
+        return;
+    }
+    let layout = Layout::new(&settings);
+    let root = Root::initializer()
+        .font("arial10x10.png", FontLayout::Tcod)
+        .font_type(FontType::Greyscale)
+        .size(SCREEN_WIDTH, layout.screen_height)
+        .title("Rust/libtcod tutorial")
+        .init();
+
+    let mut tcod = Tcod {
+        root,
+        layout,
+        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        panel: Offscreen::new(SCREEN_WIDTH, layout.panel_height),
+        fov: Vision::new(),
+        key: Default::default(),
+        mouse: Default::default(),
+        dirty: vec![],
+        held_key: None,
+        fov_changed: false,
+    };
+
+    tcod::system::set_fps(settings.fps);
+
+    main_menu(&mut tcod);
+}
+
+fn main_menu(tcod: &mut Tcod) {
+    recover_interrupted_saves(tcod);
+    let choices = ["Play a new game", "Play a new game from a seed", "Load a saved game", "Quit"];
+    while !tcod.root.window_closed() {
+        tcod.root.set_default_background(colors::BLACK);
+        tcod.root.clear();
+        tcod.root.set_default_foreground(colors::LIGHT_YELLOW);
+        tcod.root.print_ex(
+            SCREEN_WIDTH / 2,
+            tcod.layout.screen_height / 8,
+            BackgroundFlag::None,
+            TextAlignment::Center,
+            "TOMBS OF THE ANCIENT KINGS",
+        );
+        tcod.root.set_default_foreground(colors::WHITE);
+
+        match show_list(tcod, "Main menu", &choices) {
+            Some(0) => {
+                if let Some(mode) = choose_mode(tcod) {
+                    let (game, objects) = new_game(tcod, rand::random(), mode);
+                    run_game(tcod, game, objects);
+                }
+            }
+            Some(1) => {
+                // Escape at the seed prompt goes straight back to the menu
+                let Some(text) = text_input(tcod, "Seed", MAX_SEED_LENGTH) else {
+                    continue;
+                };
+                if let Some(mode) = choose_mode(tcod) {
+                    let seed = text.trim().parse().ok();
+                    let (mut game, objects) =
+                        new_game(tcod, seed.unwrap_or_else(rand::random), mode);
+                    if seed.is_none() {
+                        game.messages.add(
+                            format!("\"{}\" isn't a seed, using a random one", text),
+                            colors::YELLOW,
+                        );
+                    }
+                    run_game(tcod, game, objects);
+                }
+            }
+            Some(2) => {
+                if let Some(slot) = choose_save(tcod) {
+                    match load_from_slot(slot, tcod) {
+                        Ok((game, objects)) => run_game(tcod, game, objects),
+                        Err(err) => msgbox(tcod, "Load", &err, CONFIRM_SCREEN_WIDTH),
+                    }
+                }
+            }
+            Some(3) => break,
+            _ => {}
+        }
+    }
+}
+
+// Rewards for reaching milestones, which make later games a little easier
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unlock {
+    Delver,
+    CryptWalker,
+    Kingslayer,
+}
+
+const UNLOCKS: [Unlock; 3] = [Unlock::Delver, Unlock::CryptWalker, Unlock::Kingslayer];
+
+impl Unlock {
+    // As written in META_FILE
+    pub fn name(self) -> &'static str {
+        match self {
+            Unlock::Delver => "delver",
+            Unlock::CryptWalker => "crypt-walker",
+            Unlock::Kingslayer => "kingslayer",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Unlock::Delver => "Delver: start with an extra health potion",
+            Unlock::CryptWalker => "Crypt walker: start with some gold",
+            Unlock::Kingslayer => "Kingslayer: wear the gold of the kings",
+        }
+    }
+
+    pub fn is_earned(self, game: &Game) -> bool {
+        match self {
+            Unlock::Delver => game.deepest_level > LEVEL_THEMES[0].max_level,
+            Unlock::CryptWalker => game.deepest_level >= deepest_level(),
+            Unlock::Kingslayer => game.won,
+        }
+    }
+}
+
+// Saved games.  The auto-save is written on every change of level and every
+// --auto-save-interval turns, and the quick-save slots whenever the player
+// asks, and any of them can be loaded from the main menu.  Settings always come
+// from the command line of the game doing the loading, and the dice start fresh
+// streams, see Rngs::resumed.
+// The first line of a save is SAVE_MAGIC and the version of the format it was
+// written in.  What each version added, which older saves load with the
+// defaults for, see migrate_save:
+//   2: Object::group_id
+//   3: trap tiles (no new fields)
+//   4: Object::facing and watchful
+//   5: Tile::light
+//   6: notes, on the current level and cached ones
+//   7: Object::energy, and the haste and slowness effects and items
+//   8: Game::run, in the header too
+const SAVE_MAGIC: &str = "roguelike save";
+const SAVE_VERSION: u32 = 8;
+const AUTO_SAVE_SLOT: &str = "auto";
+const QUICK_SAVE_SLOTS: [&str; 3] = ["1", "2", "3"];
+const AUTO_SAVE_INTERVAL: u32 = 100;
+
+fn save_path(slot: &str) -> String {
+    format!("save-{}.txt", slot)
+}
+
+// Where a save is written before being renamed into place
+fn partial_save_path(slot: &str) -> String {
+    partial_path(&save_path(slot))
+}
+
+fn partial_path(path: &str) -> String {
+    format!("{}.part", path)
+}
+
+// Write a whole file under another name and then rename it into place, so a
+// failed write never leaves half a file behind, only the old one if any
+fn write_atomically<C: AsRef<[u8]>>(path: &str, contents: C) -> std::io::Result<()> {
+    let partial = partial_path(path);
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)
+}
+
+fn all_save_slots() -> impl Iterator<Item = &'static str> {
+    std::iter::once(AUTO_SAVE_SLOT).chain(QUICK_SAVE_SLOTS.iter().copied())
+}
+
+fn slot_name(slot: &str) -> String {
+    if slot == AUTO_SAVE_SLOT {
+        "Auto-save".to_string()
+    } else {
+        format!("Slot {}", slot)
+    }
+}
+
+// The version of the format a save's first line says it's in
+fn save_version(first_line: &str) -> Option<u32> {
+    first_line.strip_prefix(SAVE_MAGIC)?.trim().parse().ok()
+}
+
+// What the menus show about a save, from the lines before its state
+struct SaveInfo {
+    version: u32,
+    level: u32,
+    turn: u32,
+    mode: String,
+    // Not in saves from before version 8
+    run: Option<u32>,
+}
+
+fn read_save_info(slot: &str) -> Option<SaveInfo> {
+    read_save_info_at(&save_path(slot))
+}
+
+fn read_save_info_at(path: &str) -> Option<SaveInfo> {
+    let text = read_save_text(path).ok()?;
+    let mut lines = text.lines();
+    let version = save_version(lines.next()?)?;
+    let mut info = SaveInfo { version, level: 0, turn: 0, mode: String::new(), run: None };
+    for line in lines.take_while(|&line| line != "state") {
+        match line.split_once(' ') {
+            Some(("level", level)) => info.level = level.parse().ok()?,
+            Some(("turn", turn)) => info.turn = turn.parse().ok()?,
+            Some(("mode", mode)) => info.mode = mode.to_string(),
+            Some(("run", run)) => info.run = Some(run.parse().ok()?),
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+fn describe_slot(slot: &str) -> String {
+    match read_save_info(slot) {
+        Some(info) if info.version > SAVE_VERSION => {
+            format!("{}: from a newer version of the game", slot_name(slot))
+        }
+        Some(info) => {
+            format!("{}: level {}, turn {} ({})", slot_name(slot), info.level, info.turn, info.mode)
+        }
+        None if Path::new(&save_path(slot)).exists() => format!("{}: damaged", slot_name(slot)),
+        None => format!("{}: empty", slot_name(slot)),
+    }
+}
+
+// A few lines the menus can read quickly, then everything else as one long
+// run of tokens.  The whole save is put together in memory and written in one
+// go with write_atomically, so a failed save never leaves half a game behind.
+fn save_to_slot(slot: &str, game: &Game, objects: &[Object]) -> std::io::Result<()> {
+    let mut out = SaveWriter::new(SAVE_VERSION);
+    save_game_state(game, objects, &mut out);
+    let text = format!(
+        "{} {}\nlevel {}\nturn {}\nmode {}\nrun {}\nstate\n{}\n",
+        SAVE_MAGIC,
+        SAVE_VERSION,
+        game.dungeon_level,
+        game.turn,
+        game.mode.name(),
+        game.run,
+        out.text
+    );
+    let bytes =
+        if game.settings.compress_saves { pack(text.as_bytes()) } else { text.into_bytes() };
+    write_atomically(&save_path(slot), bytes)
+}
+
+// Saves written with --compress-saves start with this rather than SAVE_MAGIC,
+// and the rest is the plain text packed, see pack.  Plain saves are still
+// written by default, and either kind loads whatever the setting.
+const PACKED_SAVE_MAGIC: &[u8] = b"RLPK";
+// Repeats closer than this are packed, and none shorter than MIN_REPEAT
+const PACK_WINDOW: usize = 1 << 16;
+const MIN_REPEAT: usize = 4;
+// Far more than any real save unpacks to.  A damaged file asking for more is
+// turned down rather than run the game out of memory.
+const MAX_SAVE_SIZE: usize = 64 << 20;
+
+// A save file's text, unpacked first if need be
+fn read_save_text(path: &str) -> std::io::Result<String> {
+    let bytes = fs::read(path)?;
+    let bytes = match bytes.strip_prefix(PACKED_SAVE_MAGIC) {
+        Some(packed) => unpack(packed)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "bad packing"))?,
+        None => bytes,
+    };
+    String::from_utf8(bytes)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+// A simple LZ77: runs of literal bytes, each followed by a copy of something
+// seen shortly before.  Saves are mostly the same few tiles over and over, so
+// this shrinks them a lot.  Each run is its length, the bytes, then the length
+// of the copy and how far back it starts, all lengths as LEB128 numbers.
+fn pack(text: &[u8]) -> Vec<u8> {
+    let mut out = PACKED_SAVE_MAGIC.to_vec();
+    let mut last_seen: HashMap<&[u8], usize> = HashMap::new();
+    let (mut i, mut literals) = (0, 0);
+    while i < text.len() {
+        let key = &text[i..min(i + MIN_REPEAT, text.len())];
+        let repeat = match last_seen.insert(key, i) {
+            Some(start) if key.len() == MIN_REPEAT && i - start <= PACK_WINDOW => {
+                let length =
+                    text[i..].iter().zip(&text[start..]).take_while(|(a, b)| a == b).count();
+                Some((length, i - start))
+            }
+            _ => None,
+        };
+        match repeat {
+            Some((length, distance)) => {
+                push_number(&mut out, literals);
+                out.extend_from_slice(&text[i - literals..i]);
+                push_number(&mut out, length);
+                push_number(&mut out, distance);
+                for j in (i + 1)..min(i + length, text.len().saturating_sub(MIN_REPEAT)) {
+                    last_seen.insert(&text[j..j + MIN_REPEAT], j);
+                }
+                i += length;
+                literals = 0;
+            }
+            None => {
+                i += 1;
+                literals += 1;
+            }
+        }
+    }
+    push_number(&mut out, literals);
+    out.extend_from_slice(&text[text.len() - literals..]);
+    out
+}
+
+// The other way from pack, without the magic.  None if it's garbled.
+fn unpack(mut packed: &[u8]) -> Option<Vec<u8>> {
+    let mut text = vec![];
+    loop {
+        let literals = take_number(&mut packed)?;
+        text.extend_from_slice(packed.get(..literals)?);
+        packed = &packed[literals..];
+        if packed.is_empty() {
+            return Some(text);
+        }
+        let (length, distance) = (take_number(&mut packed)?, take_number(&mut packed)?);
+        if distance == 0
+            || distance > text.len()
+            || length > MAX_SAVE_SIZE.saturating_sub(text.len())
+        {
+            return None;
+        }
+        // A copy can overlap what it's copying, so go a byte at a time
+        let start = text.len() - distance;
+        for j in 0..length {
+            text.push(text[start + j]);
+        }
+    }
+}
+
+fn push_number(out: &mut Vec<u8>, mut number: usize) {
+    while number >= 0x80 {
+        out.push((number & 0x7f) as u8 | 0x80);
+        number >>= 7;
+    }
+    out.push(number as u8);
+}
+
+fn take_number(packed: &mut &[u8]) -> Option<usize> {
+    let mut number = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = packed.split_first()?;
+        *packed = rest;
+        number |= ((byte & 0x7f) as usize) << shift;
+        if byte < 0x80 {
+            return Some(number);
+        }
+    }
+    None
+}
+
+// Remove every save the given run made, whichever slot it's in and whenever
+// it was made, along with any half-written ones.  Other games' saves stay.
+fn discard_run_saves<'a>(run: u32, slots: impl Iterator<Item = &'a str>) {
+    for slot in slots {
+        let of_run = |path: &str| read_save_info_at(path).and_then(|info| info.run) == Some(run);
+        if of_run(&save_path(slot)) || of_run(&partial_save_path(slot)) {
+            discard_save(slot);
+        }
+    }
+}
+
+// Remove a slot's save along with any half-written one
+fn discard_save(slot: &str) {
+    for path in [save_path(slot), partial_save_path(slot)] {
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                println!("Couldn't remove {}: {}", path, err);
+            }
+        }
+    }
+}
+
+// The auto-save after a change of level or every --auto-save-interval turns
+fn auto_save(game: &mut Game, objects: &[Object], changed_level: bool) {
+    let interval = game.settings.auto_save_interval;
+    let due = changed_level || (interval > 0 && game.turn.is_multiple_of(interval));
+    if !due {
+        return;
+    }
+    if let Err(err) = save_if_alive(AUTO_SAVE_SLOT, game, objects) {
+        game.messages.add(format!("Couldn't auto-save: {}", err), colors::RED);
+    }
+}
+
+// A dead player is never saved, so in Permadeath there is nothing to go back
+// to.  Leaving the game saves too, since loading a Permadeath game deletes its
+// save, see load_from_slot.
+fn save_if_alive(slot: &str, game: &Game, objects: &[Object]) -> std::io::Result<()> {
+    if !objects[PLAYER].is_alive {
+        return Ok(());
+    }
+    save_to_slot(slot, game, objects)
+}
+
+// Why a save couldn't be loaded
+#[derive(Debug, PartialEq)]
+enum SaveError {
+    // Written by a newer version of the game, in a format this one doesn't know
+    Incompatible(u32),
+    // Not a save at all, or cut short or garbled
+    Corrupt(String),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SaveError::Incompatible(version) => write!(
+                f,
+                "it's from a newer version of the game (save version {}, this one reads up to {})",
+                version, SAVE_VERSION
+            ),
+            SaveError::Corrupt(reason) => write!(f, "it's damaged: {}", reason),
+        }
+    }
+}
+
+fn parse_save(text: &str) -> Result<(Game, Vec<Object>), SaveError> {
+    let not_a_save = || SaveError::Corrupt("it isn't a saved game".to_string());
+    let (header, state) = text.split_once("\nstate\n").ok_or_else(not_a_save)?;
+    let version = header.lines().next().and_then(save_version).ok_or_else(not_a_save)?;
+    migrate_save(state, version)
+}
+
+// Load a save's state from any version of the format up to this one.  Older
+// versions are read field by field as they were written, with the defaults for
+// whatever they don't have, see SAVE_VERSION.
+fn migrate_save(raw: &str, version: u32) -> Result<(Game, Vec<Object>), SaveError> {
+    if version == 0 || version > SAVE_VERSION {
+        return Err(SaveError::Incompatible(version));
+    }
+    load_game_state(&mut SaveReader::new(raw, version)).map_err(SaveError::Corrupt)
+}
+
+// A save left under its temporary name means the game stopped while writing
+// it.  If it's whole after all it can take the place of the older save,
+// otherwise it's thrown away and the older save, if any, stays as it was.
+fn recover_interrupted_saves(tcod: &mut Tcod) {
+    for slot in all_save_slots() {
+        let partial = partial_save_path(slot);
+        let text = match read_save_text(&partial) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        let name = slot_name(slot).to_lowercase();
+        let recovered = if parse_save(&text).is_ok() {
+            let question = format!("The game stopped while writing the {}. Recover it?", name);
+            confirm(tcod, &question) && fs::rename(&partial, save_path(slot)).is_ok()
+        } else {
+            let text = format!(
+                "The game stopped while writing the {}, and it can't be recovered. \
+                 Any earlier save there is kept.",
+                name
+            );
+            msgbox(tcod, "Recovery", &text, CONFIRM_SCREEN_WIDTH);
+            false
+        };
+        if !recovered {
+            if let Err(err) = fs::remove_file(&partial) {
+                println!("Couldn't remove {}: {}", partial, err);
+            }
+        }
+    }
+}
+
+fn load_from_slot(slot: &str, tcod: &mut Tcod) -> Result<(Game, Vec<Object>), String> {
+    let path = save_path(slot);
+    let text = read_save_text(&path).map_err(|err| format!("can't read {}: {}", path, err))?;
+    let (mut game, objects) =
+        parse_save(&text).map_err(|err| format!("can't load {}: {}", path, err))?;
+    // A Permadeath save is good for one load.  It's only removed once it has
+    // loaded, so a save that can't be read is left alone, and the next
+    // auto-save writes a fresh one.
+    if game.mode == GameMode::Permadeath {
+        discard_save(slot);
+    }
+
+    restore_vision(&mut tcod.fov, &mut game, &objects[PLAYER]);
+    tcod.con.clear();
+    tcod.dirty.clear();
+    tcod.held_key = None;
+    game.messages.add(format!("Loaded {}.", slot_name(slot).to_lowercase()), colors::WHITE);
+    Ok((game, objects))
+}
+
+// The FOV map can't be saved, so a loaded game rebuilds it from the tiles and
+// looks around from the saved position straight away.  Explored tiles come
+// from the save, and whatever is in view now counts as explored too, as
+// drawing the turn the game was saved on made it.
+fn restore_vision(fov: &mut Vision, game: &mut Game, player: &Object) {
+    fov.reset(&game.map);
+    fov.compute(player.x, player.y, torch_radius(game), &game.map);
+    for (x, column) in game.map.iter_mut().enumerate() {
+        for (y, tile) in column.iter_mut().enumerate() {
+            if fov.is_in_fov(x as i32, y as i32) {
+                tile.explored = true;
+            }
+        }
+    }
+}
+
+// The quick-save key.  Saving doesn't take a turn.
+fn quick_save(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let names: Vec<String> = QUICK_SAVE_SLOTS.iter().map(|slot| describe_slot(slot)).collect();
+    let slot = match show_list(tcod, "Save to which slot?", &names) {
+        Some(choice) => QUICK_SAVE_SLOTS[choice],
+        None => return,
+    };
+    let question = format!("Overwrite {}?", slot_name(slot).to_lowercase());
+    if Path::new(&save_path(slot)).exists() && !confirm(tcod, &question) {
+        return;
+    }
+    match save_to_slot(slot, game, objects) {
+        Ok(()) => game.messages.add(format!("Saved to {}.", slot_name(slot)), colors::WHITE),
+        Err(err) => game.messages.add(format!("Couldn't save: {}", err), colors::RED),
+    }
+}
+
+// The load menu, listing only the slots with a save in them
+fn choose_save(tcod: &mut Tcod) -> Option<&'static str> {
+    let slots: Vec<&'static str> =
+        all_save_slots().filter(|slot| read_save_info(slot).is_some()).collect();
+    if slots.is_empty() {
+        msgbox(tcod, "Load", "There are no saved games.", CONFIRM_SCREEN_WIDTH);
+        return None;
+    }
+    let names: Vec<String> = slots.iter().map(|slot| describe_slot(slot)).collect();
+    show_list(tcod, "Load which game?", &names).map(|choice| slots[choice])
+}
+
+// Game fields in the order they're saved.  Anything left out is remade, like
+// the vaults and settings, or only matters within a turn.
+fn save_game_state(game: &Game, objects: &[Object], out: &mut SaveWriter) {
+    game.seed.save(out);
+    game.turn.save(out);
+    game.map.save(out);
+    game.messages.save(out);
+    game.inventory.save(out);
+    game.gold.save(out);
+    game.dungeon_level.save(out);
+    game.deepest_level.save(out);
+    game.identified.save(out);
+    game.recent_path.save(out);
+    game.scent.save(out);
+    game.levels.save(out);
+    if out.since(6) {
+        game.notes.save(out);
+    }
+    game.pending_spawns.save(out);
+    game.recent_combat.save(out);
+    game.recent_hp.save(out);
+    game.killed_by.save(out);
+    game.won.save(out);
+    game.mana.save(out);
+    game.max_mana.save(out);
+    game.spells.save(out);
+    game.spell_cooldowns.save(out);
+    game.sneaking.save(out);
+    game.mode.save(out);
+    game.deaths.save(out);
+    game.entrance.save(out);
+    game.cleared_levels.save(out);
+    game.bestiary.save(out);
+    game.god_mode.save(out);
+    game.one_hit_kills.save(out);
+    game.cheated.save(out);
+    if out.since(8) {
+        game.run.save(out);
+    }
+    objects.len().save(out);
+    for object in objects {
+        object.save(out);
+    }
+}
+
+fn load_game_state(input: &mut SaveReader) -> Result<(Game, Vec<Object>), String> {
+    let settings = Settings::from_args();
+    let (seed, turn) = (u32::load(input)?, u32::load(input)?);
+    // Fields load in the order they're written, which is the order they were saved in
+    let game = Game {
+        seed,
+        turn,
+        rngs: Rngs::resumed(seed, turn),
+        trace: CombatTrace::new(settings.combat_trace.as_deref(), seed),
+        recorder: MovementRecorder::new(settings.record_moves.as_deref()),
+        ghost: load_ghost(settings.ghost.as_deref()),
+        map: Persist::load(input)?,
+        messages: Persist::load(input)?,
+        inventory: Persist::load(input)?,
+        gold: Persist::load(input)?,
+        dungeon_level: Persist::load(input)?,
+        deepest_level: Persist::load(input)?,
+        identified: Persist::load(input)?,
+        recent_path: Persist::load(input)?,
+        scent: Persist::load(input)?,
+        levels: Persist::load(input)?,
+        notes: if input.since(6) { Persist::load(input)? } else { HashMap::new() },
+        pending_spawns: Persist::load(input)?,
+        recent_combat: Persist::load(input)?,
+        recent_hp: Persist::load(input)?,
+        killed_by: Persist::load(input)?,
+        won: Persist::load(input)?,
+        mana: Persist::load(input)?,
+        max_mana: Persist::load(input)?,
+        spells: Persist::load(input)?,
+        spell_cooldowns: Persist::load(input)?,
+        sneaking: Persist::load(input)?,
+        careful_step: false,
+        show_threats: false,
+        mode: Persist::load(input)?,
+        deaths: Persist::load(input)?,
+        entrance: Persist::load(input)?,
+        cleared_levels: Persist::load(input)?,
+        bestiary: Persist::load(input)?,
+        auto_action: None,
+        auto_hp: 0,
+        newly_seen: vec![],
+        alerted_groups: vec![],
+        vaults: load_vaults(),
+        settings,
+        god_mode: Persist::load(input)?,
+        one_hit_kills: Persist::load(input)?,
+        cheated: Persist::load(input)?,
+        // Older saves can't say which run they belong to, so they start a new one
+        run: if input.since(8) { Persist::load(input)? } else { rand::random() },
+    };
+    let objects: Vec<Object> = Persist::load(input)?;
+    if objects.is_empty() || !objects[PLAYER].is_player() {
+        return Err("the saved game has no player".to_string());
+    }
+    Ok((game, objects))
+}
+
+// Saved games are whitespace-separated tokens, see Persist.  Both ends know the
+// version of the format, so a field added in a later version can be left out
+// of older ones.
+struct SaveWriter {
+    text: String,
+    version: u32,
+}
+
+impl SaveWriter {
+    pub fn new(version: u32) -> Self {
+        SaveWriter { text: String::new(), version }
+    }
+
+    // Whether the format has fields added in `version`
+    pub fn since(&self, version: u32) -> bool {
+        self.version >= version
+    }
+
+    pub fn token<T: std::fmt::Display>(&mut self, token: T) {
+        if !self.text.is_empty() {
+            self.text.push(' ');
+        }
+        self.text += &token.to_string();
+    }
+}
+
+struct SaveReader<'a> {
+    tokens: std::str::SplitWhitespace<'a>,
+    version: u32,
+}
+
+impl<'a> SaveReader<'a> {
+    pub fn new(text: &'a str, version: u32) -> Self {
+        SaveReader { tokens: text.split_whitespace(), version }
+    }
+
+    pub fn since(&self, version: u32) -> bool {
+        self.version >= version
+    }
+
+    pub fn token(&mut self) -> Result<&'a str, String> {
+        self.tokens.next().ok_or_else(|| "the saved game ends too soon".to_string())
+    }
+
+    pub fn parse<T: std::str::FromStr>(&mut self) -> Result<T, String> {
+        let token = self.token()?;
+        token.parse().map_err(|_| format!("unexpected {} in the saved game", token))
+    }
+}
+
+// Something that can be written to a saved game and read back
+trait Persist: Sized {
+    fn save(&self, out: &mut SaveWriter);
+    fn load(input: &mut SaveReader) -> Result<Self, String>;
+}
+
+impl Persist for i32 {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+impl Persist for u32 {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+impl Persist for u16 {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+impl Persist for u8 {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+impl Persist for usize {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+impl Persist for f32 {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+impl Persist for bool {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(self);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        input.parse()
+    }
+}
+
+// By code, since a glyph might be a space
+impl Persist for char {
+    fn save(&self, out: &mut SaveWriter) {
+        out.token(*self as u32);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        char::from_u32(input.parse()?).ok_or_else(|| "bad character in the saved game".to_string())
+    }
+}
+
+// Quoted, with whitespace and backslashes escaped as \<hex code>; so the text
+// stays one token even when it's empty
+impl Persist for String {
+    fn save(&self, out: &mut SaveWriter) {
+        let mut token = String::from("\"");
+        for c in self.chars() {
+            if c.is_whitespace() || c == '\\' {
+                token += &format!("\\{:x};", c as u32);
+            } else {
+                token.push(c);
+            }
         }
-        (Key { code: Escape, .. }, _) => Exit,
+        out.token(token);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let token = input.token()?;
+        let escaped = token.strip_prefix('"').ok_or_else(|| format!("{} isn't text", token))?;
+        let mut text = String::new();
+        let mut parts = escaped.split('\\');
+        text += parts.next().unwrap_or("");
+        for part in parts {
+            let (code, rest) = part.split_once(';').ok_or_else(|| format!("bad text {}", token))?;
+            let c = u32::from_str_radix(code, 16).ok().and_then(char::from_u32);
+            text.push(c.ok_or_else(|| format!("bad text {}", token))?);
+            text += rest;
+        }
+        Ok(text)
+    }
+}
+
+impl Persist for Color {
+    fn save(&self, out: &mut SaveWriter) {
+        (self.r, self.g, self.b).save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let (r, g, b) = Persist::load(input)?;
+        Ok(Color { r, g, b })
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn save(&self, out: &mut SaveWriter) {
+        match self {
+            Some(value) => {
+                out.token("some");
+                value.save(out);
+            }
+            None => out.token("none"),
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        match input.token()? {
+            "some" => Ok(Some(T::load(input)?)),
+            "none" => Ok(None),
+            other => Err(format!("unexpected {} in the saved game", other)),
+        }
+    }
+}
+
+impl<T: Persist> Persist for Box<T> {
+    fn save(&self, out: &mut SaveWriter) {
+        (**self).save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(Box::new(T::load(input)?))
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn save(&self, out: &mut SaveWriter) {
+        self.0.save(out);
+        self.1.save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok((A::load(input)?, B::load(input)?))
+    }
+}
+
+impl<A: Persist, B: Persist, C: Persist> Persist for (A, B, C) {
+    fn save(&self, out: &mut SaveWriter) {
+        self.0.save(out);
+        self.1.save(out);
+        self.2.save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok((A::load(input)?, B::load(input)?, C::load(input)?))
+    }
+}
+
+// Collections are their length, then each element
+impl<T: Persist> Persist for Vec<T> {
+    fn save(&self, out: &mut SaveWriter) {
+        self.len().save(out);
+        for element in self {
+            element.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let len = usize::load(input)?;
+        (0..len).map(|_| T::load(input)).collect()
+    }
+}
+
+impl<T: Persist> Persist for VecDeque<T> {
+    fn save(&self, out: &mut SaveWriter) {
+        self.len().save(out);
+        for element in self {
+            element.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let len = usize::load(input)?;
+        (0..len).map(|_| T::load(input)).collect()
+    }
+}
+
+impl<T: Persist + Eq + std::hash::Hash> Persist for HashSet<T> {
+    fn save(&self, out: &mut SaveWriter) {
+        self.len().save(out);
+        for element in self {
+            element.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let len = usize::load(input)?;
+        (0..len).map(|_| T::load(input)).collect()
+    }
+}
+
+impl<K: Persist + Eq + std::hash::Hash, V: Persist> Persist for HashMap<K, V> {
+    fn save(&self, out: &mut SaveWriter) {
+        self.len().save(out);
+        for (key, value) in self {
+            key.save(out);
+            value.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let len = usize::load(input)?;
+        (0..len)
+            .map(|_| -> Result<(K, V), String> { Ok((K::load(input)?, V::load(input)?)) })
+            .collect()
+    }
+}
+
+// Enums without fields are saved as their place in a list of every variant
+fn save_variant<T: PartialEq>(value: &T, variants: &[T], out: &mut SaveWriter) {
+    let index = variants.iter().position(|variant| variant == value);
+    index.expect("every variant is listed").save(out);
+}
+
+fn load_variant<T: Copy>(variants: &[T], input: &mut SaveReader) -> Result<T, String> {
+    let index = usize::load(input)?;
+    variants.get(index).copied().ok_or_else(|| format!("unknown variant {}", index))
+}
+
+const DEATH_CALLBACKS: [DeathCallback; 3] =
+    [DeathCallback::Player, DeathCallback::Monster, DeathCallback::Boss];
+const GAME_MODES: [GameMode; 2] = [GameMode::Permadeath, GameMode::Practice];
+const RARITIES: [Rarity; 4] = [Rarity::Common, Rarity::Uncommon, Rarity::Rare, Rarity::Epic];
+const ALL_AFFIXES: [Affix; 7] = [
+    Affix::Keen,
+    Affix::Flaming,
+    Affix::Vampiric,
+    Affix::Bear,
+    Affix::Turtle,
+    Affix::Troll,
+    Affix::Sun,
+];
+const ABILITIES: [Ability; 2] = [Ability::HealAlly, Ability::Bolt];
+const SPELLS: [Spell; 3] = [Spell::Fireball, Spell::Lightning, Spell::Heal];
+const ALERT_STATES: [AlertState; 5] = [
+    AlertState::Unaware,
+    AlertState::Noticed,
+    AlertState::Hunting,
+    AlertState::LostTrack,
+    AlertState::Searching,
+];
+const SLOTS: [Slot; 5] =
+    [Slot::MainHand, Slot::OffHand, Slot::LeftRing, Slot::RightRing, Slot::Neck];
+const FACTIONS: [Faction; 4] =
+    [Faction::Player, Faction::Monster, Faction::Undead, Faction::Neutral];
+const STAIRS: [Stairs; 2] = [Stairs::Up, Stairs::Down];
+const TRAP_KINDS: [TrapKind; 2] = [TrapKind::Spikes, TrapKind::Dart];
+const LIGHT_LEVELS: [LightLevel; 3] = [LightLevel::Normal, LightLevel::Dark, LightLevel::Bright];
+
+impl Persist for DeathCallback {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &DEATH_CALLBACKS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&DEATH_CALLBACKS, input)
+    }
+}
+
+impl Persist for GameMode {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &GAME_MODES, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&GAME_MODES, input)
+    }
+}
+
+impl Persist for Rarity {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &RARITIES, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&RARITIES, input)
+    }
+}
+
+impl Persist for Affix {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &ALL_AFFIXES, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&ALL_AFFIXES, input)
+    }
+}
+
+impl Persist for Ability {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &ABILITIES, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&ABILITIES, input)
+    }
+}
+
+impl Persist for Spell {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &SPELLS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&SPELLS, input)
+    }
+}
+
+impl Persist for AlertState {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &ALERT_STATES, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&ALERT_STATES, input)
+    }
+}
+
+impl Persist for Slot {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &SLOTS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&SLOTS, input)
+    }
+}
+
+impl Persist for Faction {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &FACTIONS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&FACTIONS, input)
+    }
+}
+
+impl Persist for Direction {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &COMPASS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&COMPASS, input)
+    }
+}
+
+impl Persist for LightLevel {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &LIGHT_LEVELS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&LIGHT_LEVELS, input)
+    }
+}
+
+impl Persist for TrapKind {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &TRAP_KINDS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&TRAP_KINDS, input)
+    }
+}
+
+impl Persist for Stairs {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &STAIRS, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&STAIRS, input)
+    }
+}
+
+impl Persist for MsgCategory {
+    fn save(&self, out: &mut SaveWriter) {
+        save_variant(self, &MSG_CATEGORIES, out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        load_variant(&MSG_CATEGORIES, input)
+    }
+}
+
+// Enums with fields are a word naming the variant, then the fields
+impl Persist for Item {
+    fn save(&self, out: &mut SaveWriter) {
+        match self {
+            Item::Heal => out.token("heal"),
+            Item::Acid => out.token("acid"),
+            Item::Lightning => out.token("lightning"),
+            Item::Confusion => out.token("confusion"),
+            Item::Charm => out.token("charm"),
+            Item::Haste => out.token("haste"),
+            Item::Slow => out.token("slow"),
+            Item::Enchant => out.token("enchant"),
+            Item::Spear => out.token("spear"),
+            Item::Axe => out.token("axe"),
+            Item::Pick => out.token("pick"),
+            Item::Shield => out.token("shield"),
+            Item::Ring => out.token("ring"),
+            Item::Pendant => out.token("pendant"),
+            Item::Digging(charges) => {
+                out.token("digging");
+                charges.save(out);
+            }
+            Item::Gold(amount) => {
+                out.token("gold");
+                amount.save(out);
+            }
+            Item::Amulet => out.token("amulet"),
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(match input.token()? {
+            "heal" => Item::Heal,
+            "acid" => Item::Acid,
+            "lightning" => Item::Lightning,
+            "confusion" => Item::Confusion,
+            "charm" => Item::Charm,
+            "haste" => Item::Haste,
+            "slow" => Item::Slow,
+            "enchant" => Item::Enchant,
+            "spear" => Item::Spear,
+            "axe" => Item::Axe,
+            "pick" => Item::Pick,
+            "shield" => Item::Shield,
+            "ring" => Item::Ring,
+            "pendant" => Item::Pendant,
+            "digging" => Item::Digging(Persist::load(input)?),
+            "gold" => Item::Gold(Persist::load(input)?),
+            "amulet" => Item::Amulet,
+            other => return Err(format!("unknown item {}", other)),
+        })
+    }
+}
+
+impl Persist for Ai {
+    fn save(&self, out: &mut SaveWriter) {
+        match self {
+            Ai::Basic => out.token("basic"),
+            Ai::Confused { previous, turns } => {
+                out.token("confused");
+                previous.save(out);
+                turns.save(out);
+            }
+            Ai::Summoner { cooldown, summons_left } => {
+                out.token("summoner");
+                cooldown.save(out);
+                summons_left.save(out);
+            }
+            Ai::Boss { phase } => {
+                out.token("boss");
+                phase.save(out);
+            }
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(match input.token()? {
+            "basic" => Ai::Basic,
+            "confused" => {
+                Ai::Confused { previous: Persist::load(input)?, turns: Persist::load(input)? }
+            }
+            "summoner" => Ai::Summoner {
+                cooldown: Persist::load(input)?,
+                summons_left: Persist::load(input)?,
+            },
+            "boss" => Ai::Boss { phase: Persist::load(input)? },
+            other => return Err(format!("unknown AI {}", other)),
+        })
+    }
+}
+
+impl Persist for StatusEffect {
+    fn save(&self, out: &mut SaveWriter) {
+        match self {
+            StatusEffect::Charmed { turns, faction } => {
+                out.token("charmed");
+                turns.save(out);
+                faction.save(out);
+            }
+            StatusEffect::Blessed { turns } => {
+                out.token("blessed");
+                turns.save(out);
+            }
+            StatusEffect::Hasted { turns } => {
+                out.token("hasted");
+                turns.save(out);
+            }
+            StatusEffect::Slowed { turns } => {
+                out.token("slowed");
+                turns.save(out);
+            }
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(match input.token()? {
+            "charmed" => StatusEffect::Charmed {
+                turns: Persist::load(input)?,
+                faction: Persist::load(input)?,
+            },
+            "blessed" => StatusEffect::Blessed { turns: Persist::load(input)? },
+            "hasted" => StatusEffect::Hasted { turns: Persist::load(input)? },
+            "slowed" => StatusEffect::Slowed { turns: Persist::load(input)? },
+            other => return Err(format!("unknown status effect {}", other)),
+        })
+    }
+}
+
+impl Persist for InteractKind {
+    fn save(&self, out: &mut SaveWriter) {
+        match self {
+            InteractKind::Lore(index) => {
+                out.token("lore");
+                index.save(out);
+            }
+            // GHOST_LINES are the only lines anything says
+            InteractKind::Talk(_) => out.token("talk"),
+            InteractKind::Shrine { used } => {
+                out.token("shrine");
+                used.save(out);
+            }
+            InteractKind::Shop => out.token("shop"),
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(match input.token()? {
+            "lore" => InteractKind::Lore(Persist::load(input)?),
+            "talk" => InteractKind::Talk(&GHOST_LINES),
+            "shrine" => InteractKind::Shrine { used: Persist::load(input)? },
+            "shop" => InteractKind::Shop,
+            other => return Err(format!("unknown interaction {}", other)),
+        })
+    }
+}
+
+impl Persist for TileKind {
+    fn save(&self, out: &mut SaveWriter) {
+        match self {
+            TileKind::Floor => out.token("floor"),
+            TileKind::Wall => out.token("wall"),
+            TileKind::Water { deep } => {
+                out.token("water");
+                deep.save(out);
+            }
+            TileKind::Lava => out.token("lava"),
+            TileKind::Chasm => out.token("chasm"),
+            TileKind::TrapDoor { hidden } => {
+                out.token("trapdoor");
+                hidden.save(out);
+            }
+            TileKind::Trap { kind, hidden, armed, rearm_in } => {
+                out.token("trap");
+                kind.save(out);
+                (*hidden, *armed, *rearm_in).save(out);
+            }
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(match input.token()? {
+            "floor" => TileKind::Floor,
+            "wall" => TileKind::Wall,
+            "water" => TileKind::Water { deep: Persist::load(input)? },
+            "lava" => TileKind::Lava,
+            "chasm" => TileKind::Chasm,
+            "trapdoor" => TileKind::TrapDoor { hidden: Persist::load(input)? },
+            "trap" => {
+                let kind = Persist::load(input)?;
+                let (hidden, armed, rearm_in) = Persist::load(input)?;
+                TileKind::Trap { kind, hidden, armed, rearm_in }
+            }
+            other => return Err(format!("unknown tile {}", other)),
+        })
+    }
+}
+
+impl Persist for Tile {
+    fn save(&self, out: &mut SaveWriter) {
+        self.kind.save(out);
+        self.is_walkable.save(out);
+        self.is_transparent.save(out);
+        self.explored.save(out);
+        self.destructible.save(out);
+        if out.since(5) {
+            self.light.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(Tile {
+            kind: Persist::load(input)?,
+            is_walkable: Persist::load(input)?,
+            is_transparent: Persist::load(input)?,
+            explored: Persist::load(input)?,
+            destructible: Persist::load(input)?,
+            light: if input.since(5) { Persist::load(input)? } else { LightLevel::Normal },
+        })
+    }
+}
+
+impl Persist for Fighter {
+    fn save(&self, out: &mut SaveWriter) {
+        self.max_hp.save(out);
+        self.hp.save(out);
+        self.defense.save(out);
+        self.attack.save(out);
+        self.regen_rate.save(out);
+        self.regen_counter.save(out);
+        self.on_death.save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(Fighter {
+            max_hp: Persist::load(input)?,
+            hp: Persist::load(input)?,
+            defense: Persist::load(input)?,
+            attack: Persist::load(input)?,
+            regen_rate: Persist::load(input)?,
+            regen_counter: Persist::load(input)?,
+            on_death: Persist::load(input)?,
+        })
+    }
+}
+
+impl Persist for Equipment {
+    fn save(&self, out: &mut SaveWriter) {
+        self.equipped.save(out);
+        self.slot.save(out);
+        self.two_handed.save(out);
+        self.attack_bonus.save(out);
+        self.defense_bonus.save(out);
+        self.block_chance.save(out);
+        self.enchantment.save(out);
+        self.reach.save(out);
+        self.cleave.save(out);
+        self.digs.save(out);
+        self.affixes.save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(Equipment {
+            equipped: Persist::load(input)?,
+            slot: Persist::load(input)?,
+            two_handed: Persist::load(input)?,
+            attack_bonus: Persist::load(input)?,
+            defense_bonus: Persist::load(input)?,
+            block_chance: Persist::load(input)?,
+            enchantment: Persist::load(input)?,
+            reach: Persist::load(input)?,
+            cleave: Persist::load(input)?,
+            digs: Persist::load(input)?,
+            affixes: Persist::load(input)?,
+        })
+    }
+}
+
+impl Persist for Object {
+    fn save(&self, out: &mut SaveWriter) {
+        (self.x, self.y, self.char).save(out);
+        self.color.save(out);
+        self.name.save(out);
+        self.lost_turns.save(out);
+        self.faction.save(out);
+        self.status_effects.save(out);
+        self.abilities.save(out);
+        self.cooldowns.save(out);
+        self.alert_state.save(out);
+        self.queued_turns.save(out);
+        if out.since(2) {
+            self.group_id.save(out);
+        }
+        if out.since(4) {
+            (self.facing, self.watchful).save(out);
+        }
+        if out.since(7) {
+            self.energy.save(out);
+        }
+        self.fighter.save(out);
+        self.ai.save(out);
+        self.item.save(out);
+        self.equipment.save(out);
+        self.on_interact.save(out);
+        self.destructible.save(out);
+        self.rarity.save(out);
+        self.remembered_at.save(out);
+        (self.is_walkable, self.is_alive, self.was_seen).save(out);
+        self.stairs.save(out);
+        self.can_swim.save(out);
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let (x, y, char) = Persist::load(input)?;
+        let color = Persist::load(input)?;
+        let name: String = Persist::load(input)?;
+        let mut object = Object::new(x, y, char, &name, color);
+        object.lost_turns = Persist::load(input)?;
+        object.faction = Persist::load(input)?;
+        object.status_effects = Persist::load(input)?;
+        object.abilities = Persist::load(input)?;
+        object.cooldowns = Persist::load(input)?;
+        object.alert_state = Persist::load(input)?;
+        object.queued_turns = Persist::load(input)?;
+        if input.since(2) {
+            object.group_id = Persist::load(input)?;
+        }
+        if input.since(4) {
+            (object.facing, object.watchful) = Persist::load(input)?;
+        }
+        if input.since(7) {
+            object.energy = Persist::load(input)?;
+        }
+        object.fighter = Persist::load(input)?;
+        object.ai = Persist::load(input)?;
+        object.item = Persist::load(input)?;
+        object.equipment = Persist::load(input)?;
+        object.on_interact = Persist::load(input)?;
+        object.destructible = Persist::load(input)?;
+        object.rarity = Persist::load(input)?;
+        object.remembered_at = Persist::load(input)?;
+        (object.is_walkable, object.is_alive, object.was_seen) = Persist::load(input)?;
+        object.stairs = Persist::load(input)?;
+        object.can_swim = Persist::load(input)?;
+        Ok(object)
+    }
+}
+
+impl Persist for Level {
+    fn save(&self, out: &mut SaveWriter) {
+        self.map.save(out);
+        self.objects.save(out);
+        if out.since(6) {
+            self.notes.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        Ok(Level {
+            map: Persist::load(input)?,
+            objects: Persist::load(input)?,
+            notes: if input.since(6) { Persist::load(input)? } else { HashMap::new() },
+        })
+    }
+}
+
+impl Persist for Messages {
+    fn save(&self, out: &mut SaveWriter) {
+        self.messages.len().save(out);
+        for message in &self.messages {
+            message.text.save(out);
+            message.color.save(out);
+            message.category.save(out);
+            message.count.save(out);
+        }
+    }
+    fn load(input: &mut SaveReader) -> Result<Self, String> {
+        let mut messages = Messages::new();
+        for _ in 0..usize::load(input)? {
+            messages.messages.push(Message {
+                text: Persist::load(input)?,
+                color: Persist::load(input)?,
+                category: Persist::load(input)?,
+                count: Persist::load(input)?,
+            });
+        }
+        Ok(messages)
+    }
+}
+
+// Everything carried over from earlier games
+#[derive(Default)]
+struct Meta {
+    unlocks: Vec<Unlock>,
+    // The bestiary, so the player doesn't have to learn each monster again
+    bestiary: HashMap<String, u32>,
+}
+
+// One entry per line, "unlock <name>" or "bestiary <kills> <monster name>".
+// With no file yet, nothing has been unlocked.
+fn load_meta() -> Meta {
+    let mut meta = Meta::default();
+    let text = match fs::read_to_string(META_FILE) {
+        Ok(text) => text,
+        Err(_) => return meta,
+    };
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.splitn(2, ' ');
+        let parsed = match (words.next(), words.next()) {
+            (Some("unlock"), Some(name)) => {
+                match UNLOCKS.iter().find(|unlock| unlock.name() == name) {
+                    Some(&unlock) => {
+                        meta.unlocks.push(unlock);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (Some("bestiary"), Some(rest)) => {
+                let mut words = rest.splitn(2, ' ');
+                match (words.next().and_then(|kills| kills.parse().ok()), words.next()) {
+                    (Some(kills), Some(name)) => {
+                        meta.bestiary.insert(name.to_string(), kills);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        if !parsed {
+            println!("Warning: ignoring line in {}: {}", META_FILE, line);
+        }
+    }
+    meta
+}
+
+fn save_meta(meta: &Meta) {
+    let mut text = String::new();
+    for unlock in &meta.unlocks {
+        text += &format!("unlock {}\n", unlock.name());
+    }
+    let mut bestiary: Vec<_> = meta.bestiary.iter().collect();
+    bestiary.sort();
+    for (name, kills) in bestiary {
+        text += &format!("bestiary {} {}\n", kills, name);
+    }
+    if let Err(err) = write_atomically(META_FILE, text) {
+        println!("Warning: can't write {}: {}", META_FILE, err);
+    }
+}
+
+// Give a new character whatever earlier games have unlocked
+fn apply_meta(meta: &Meta, game: &mut Game, objects: &mut [Object]) {
+    for &unlock in &meta.unlocks {
+        match unlock {
+            Unlock::Delver => {
+                for _ in 0..DELVER_POTIONS {
+                    game.inventory.push(make_item(Item::Heal, 0, 0));
+                }
+            }
+            Unlock::CryptWalker => game.gold += CRYPT_WALKER_GOLD,
+            Unlock::Kingslayer => objects[PLAYER].color = colors::GOLD,
+        }
+    }
+    game.bestiary = meta.bestiary.clone();
+}
+
+// Once a game is over, keep what it achieved for the next one
+fn choose_mode(tcod: &mut Tcod) -> Option<GameMode> {
+    let modes = [GameMode::Permadeath, GameMode::Practice];
+    let names: Vec<&str> = modes.iter().map(|mode| mode.name()).collect();
+    show_list(tcod, "Game mode", &names).map(|i| modes[i])
+}
+
+fn record_meta(tcod: &mut Tcod, game: &Game) {
+    if !game.settings.meta {
+        return;
+    }
+    let mut meta = load_meta();
+    let new_unlocks: Vec<Unlock> = UNLOCKS
+        .iter()
+        .copied()
+        // Nothing is unlocked by a game that couldn't be lost
+        .filter(|_| game.mode == GameMode::Permadeath && !game.cheated)
+        .filter(|unlock| unlock.is_earned(game) && !meta.unlocks.contains(unlock))
+        .collect();
+    meta.unlocks.extend(&new_unlocks);
+    merge_bestiary(&mut meta.bestiary, &game.bestiary);
+    save_meta(&meta);
+
+    if !new_unlocks.is_empty() {
+        let text: Vec<&str> = new_unlocks.iter().map(|unlock| unlock.description()).collect();
+        msgbox(tcod, "Unlocked", &text.join("\n"), DEATH_SCREEN_WIDTH);
+    }
+}
+
+// A game's bestiary started from the meta file's, but a loaded game's can be
+// older than what other games have written there since, so keep the higher
+// count for each monster rather than replacing the lot
+fn merge_bestiary(meta: &mut HashMap<String, u32>, game: &HashMap<String, u32>) {
+    for (name, &kills) in game {
+        let known = meta.entry(name.clone()).or_insert(0);
+        *known = max(*known, kills);
+    }
+}
+
+// The first level of a new game, and the player on it.  Nothing here needs the
+// screen, see new_game for the rest.
+fn start_game(seed: u32, mode: GameMode, settings: Settings) -> (Game, Vec<Object>) {
+    let mut player = Object::new(0, 0, '@', "Player", colors::WHITE);
+    player.fighter = Some(Fighter {
+        max_hp: 30,
+        hp: 30,
+        defense: 2,
+        attack: 5,
+        regen_rate: PLAYER_REGEN_RATE,
+        regen_counter: 0,
+        on_death: DeathCallback::Player,
+    });
+    player.can_swim = true;
+    player.faction = Faction::Player;
 
-        // Arrow Movement Keys
-        (Key { code: Up, .. }, true) => player_move_or_attack(0, -1, game, objects),
-        (Key { code: Down, .. }, true) => player_move_or_attack(0, 1, game, objects),
-        (Key { code: Left, .. }, true) => player_move_or_attack(-1, 0, game, objects),
-        (Key { code: Right, .. }, true) => player_move_or_attack(1, 0, game, objects),
+    let mut objects = vec![player];
 
-        // vi-style cardinal movement keys
-        (Key { printable: 'k', .. }, true) => player_move_or_attack(0, -1, game, objects),
-        (Key { printable: 'j', .. }, true) => player_move_or_attack(0, 1, game, objects),
-        (Key { printable: 'h', .. }, true) => player_move_or_attack(-1, 0, game, objects),
-        (Key { printable: 'l', .. }, true) => player_move_or_attack(1, 0, game, objects),
+    let vaults = load_vaults();
+    let mut game = Game {
+        map: make_map(&mut objects, &vaults, &settings.gen, 1, seed),
+        messages: Messages::new(),
+        inventory: vec![],
+        gold: 0,
+        dungeon_level: 1,
+        deepest_level: 1,
+        seed,
+        rngs: Rngs::new(seed),
+        trace: CombatTrace::new(settings.combat_trace.as_deref(), seed),
+        identified: vec![],
+        turn: 0,
+        recent_path: VecDeque::new(),
+        recorder: MovementRecorder::new(settings.record_moves.as_deref()),
+        ghost: load_ghost(settings.ghost.as_deref()),
+        recent_combat: VecDeque::new(),
+        recent_hp: VecDeque::new(),
+        scent: empty_scent(),
+        levels: HashMap::new(),
+        notes: HashMap::new(),
+        pending_spawns: vec![],
+        killed_by: None,
+        won: false,
+        mana: MAX_MANA,
+        max_mana: MAX_MANA,
+        spells: vec![Spell::Fireball, Spell::Lightning, Spell::Heal],
+        spell_cooldowns: HashMap::new(),
+        sneaking: false,
+        careful_step: false,
+        show_threats: false,
+        mode,
+        deaths: 0,
+        entrance: (0, 0),
+        cleared_levels: HashSet::new(),
+        bestiary: HashMap::new(),
+        auto_action: None,
+        auto_hp: 0,
+        newly_seen: vec![],
+        alerted_groups: vec![],
+        vaults,
+        settings,
+        god_mode: false,
+        one_hit_kills: false,
+        cheated: false,
+        run: rand::random(),
+    };
 
-        // not-really-vi-style diagonal movement keys
-        (Key { printable: 'y', .. }, true) => player_move_or_attack(-1, -1, game, objects),
-        (Key { printable: 'u', .. }, true) => player_move_or_attack(1, -1, game, objects),
-        (Key { printable: 'b', .. }, true) => player_move_or_attack(-1, 1, game, objects),
-        (Key { printable: 'n', .. }, true) => player_move_or_attack(1, 1, game, objects),
+    // Provide items for testing
+    for &item in &[
+        Item::Heal,
+        Item::Acid,
+        Item::Lightning,
+        Item::Confusion,
+        Item::Charm,
+        Item::Spear,
+        Item::Axe,
+        Item::Pick,
+        Item::Digging(DIG_CHARGES),
+    ] {
+        game.inventory.push(make_item(item, 0, 0));
+    }
+    if game.settings.meta {
+        apply_meta(&load_meta(), &mut game, &mut objects);
+    }
+    if let Some(path) = game.settings.load_map.clone() {
+        match load_map_ascii(&path) {
+            Ok((map, loaded)) => {
+                game.map = map;
+                objects.truncate(1);
+                let (x, y) = loaded[PLAYER].pos();
+                objects[PLAYER].set_pos(x, y);
+                objects.extend(loaded.into_iter().skip(1));
+            }
+            Err(err) => println!("Warning: can't load {}: {}", path, err),
+        }
+    }
+    (game, objects)
+}
 
-        (Key { printable: '.', .. }, true) => player_pick_up_here(game, objects),
+fn new_game(tcod: &mut Tcod, seed: u32, mode: GameMode) -> (Game, Vec<Object>) {
+    let (mut game, mut objects) = start_game(seed, mode, Settings::from_args());
+    initialise_fov(tcod, &game.map);
+    let start_level = game.settings.start_level;
+    if start_level > 1 {
+        warp_to_level(tcod, &mut game, &mut objects, start_level);
+    }
+    game.entrance = objects[PLAYER].pos();
+    game.recorder.record(0, game.dungeon_level, objects[PLAYER].pos());
+    tcod.dirty.clear();
+    tcod.held_key = None;
 
-        (Key { printable: 'i', .. }, true) => show_inventory(tcod, game, objects),
+    game.messages.add_cat(
+        "Welcome to the Tombs of the Ancient Kings!",
+        colors::RED,
+        MsgCategory::Lore,
+    );
+    if mode == GameMode::Practice {
+        game.messages.add("Practice mode: dying won't end the game.", colors::LIGHT_GREEN);
+    }
+    (game, objects)
+}
 
-        _ => DidntTakeTurn,
+// Play a game to the end, then as many retries of the same dungeon as the
+// player asks for from the death screen.  Each retry is a new game from the
+// same seed and mode, so the first level comes out just as it did before.
+fn run_game(tcod: &mut Tcod, mut game: Game, mut objects: Vec<Object>) {
+    loop {
+        let retry = play_game(tcod, &mut game, &mut objects);
+        record_meta(tcod, &game);
+        if !retry {
+            break;
+        }
+        let (seed, mode) = (game.seed, game.mode);
+        let (next_game, next_objects) = new_game(tcod, seed, mode);
+        game = next_game;
+        objects = next_objects;
+        game.messages.add(format!("Trying seed {} again.", seed), colors::LIGHT_GREEN);
     }
 }
 
-fn get_names_under_mouse(tcod: &Tcod, objects: &[Object]) -> String {
-    let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
-    if !(0..MAP_WIDTH).contains(&x) || !(0..MAP_HEIGHT).contains(&y) || !tcod.fov.is_in_fov(x, y) {
-        return "".to_string();
+// One round of turns for everything with an AI that the player has seen.  Each
+// gains its speed in energy, then acts once for every ACTION_COST it has, for
+// as long as it lives.  Speed is checked every round, so haste or slowness
+// starting or ending takes effect straight away.  Monsters summoned during the
+// round wait in game.pending_spawns, and only get to act from the next round on.
+fn take_round<F: FnMut(usize, &mut [Object])>(objects: &mut [Object], mut act: F) {
+    for id in 0..objects.len() {
+        let ob = &mut objects[id];
+        if !ob.is_alive || ob.ai.is_none() || !ob.was_seen {
+            continue;
+        }
+        ob.energy += ob.speed();
+        while objects[id].energy >= ACTION_COST && objects[id].is_alive {
+            objects[id].energy -= ACTION_COST;
+            act(id, objects);
+        }
     }
+}
 
-    let names = objects
-        .iter()
-        .filter(|ob| ob.pos() == (x, y))
-        .map(|ob| ob.name.clone())
-        .collect::<Vec<_>>();
+// Pay for the action the player just took, and start counting the turns they
+// lost, which they sit out on top of waiting for energy.  Returns those turns.
+// The player is always ready by the time a key is read, even if the last
+// rounds were cut short by dying.
+fn end_player_turn(player: &mut Object) -> i32 {
+    player.energy = max(player.energy, ACTION_COST) - ACTION_COST;
+    std::mem::take(&mut player.lost_turns)
+}
 
-    names.join(", ")
+// Whether another round goes by before the player acts again, see take_round.
+// Rounds go by until the player has the energy to act, then one for each lost
+// turn.  A hasted player might still have enough to go again straight away,
+// with no round at all.
+fn player_waits(player: &mut Object, lost_rounds: &mut i32) -> bool {
+    if player.energy < ACTION_COST {
+        player.energy += player.speed();
+    } else if *lost_rounds > 0 {
+        *lost_rounds -= 1;
+    } else {
+        return false;
+    }
+    true
 }
 
-fn render_bar(
-    panel: &mut Offscreen,
-    x: i32,
-    y: i32,
-    total_width: i32,
-    _name: &str,
-    value: i32,
-    maximum: i32,
-    bar_color: Color,
-    back_color: Color,
-) {
-    let real_width = value as f32 / maximum as f32 * total_width as f32;
-    let bar_width = real_width.ceil() as i32;
+// Returns true if the player died and asked to retry the same seed
+fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> bool {
+    render_all(tcod, objects, game, true);
+    tcod.root.flush();
+    remember_objects(tcod, game, objects);
 
-    // render the background first
-    panel.set_default_background(back_color);
-    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+    // Each time round is one frame: input is polled without waiting, the game
+    // only moves on when the player does something, and the screen is redrawn
+    // either way.  flush() waits out the rest of the frame, see --fps.
+    while !tcod.root.window_closed() {
+        read_input(tcod, objects, game.settings.key_repeat);
+        let mark = game.messages.mark();
+        let previous_pos = (objects[PLAYER].x, objects[PLAYER].y);
+        let previous_level = game.dungeon_level;
+        let was_alive = objects[PLAYER].is_alive;
+        let player_action = if game.auto_action.is_some() {
+            if tcod.key.code != KeyCode::NoKey {
+                // Any key stops whatever the player was doing
+                game.auto_action = None;
+                game.messages.add("You stop.", colors::WHITE);
+                PlayerAction::DidntTakeTurn
+            } else {
+                auto_step(tcod, game, objects)
+            }
+        } else {
+            handle_keys(tcod, objects, game)
+        };
+        if player_action == PlayerAction::Exit {
+            break;
+        }
+        let (player_x, player_y) = objects[PLAYER].pos();
+        match game.map[player_x as usize][player_y as usize].kind {
+            TileKind::Chasm => {
+                game.messages.add("You fall into the chasm!", colors::RED);
+                fall_to_next_level(tcod, game, objects);
+            }
+            TileKind::TrapDoor { .. } => {
+                // It stays open, for whenever the player comes back
+                let tile = &mut game.map[player_x as usize][player_y as usize];
+                tile.kind = TileKind::TrapDoor { hidden: false };
+                tile.explored = true;
+                game.messages.add("A trap door opens beneath you!", colors::RED);
+                fall_to_next_level(tcod, game, objects);
+            }
+            _ => {}
+        }
 
-    panel.set_default_background(bar_color);
-    if bar_width > 0 {
-        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+        if player_action == PlayerAction::TookTurn || previous_level != game.dungeon_level {
+            game.messages.start_turn(mark);
+            game.turn += 1;
+            if previous_level == game.dungeon_level {
+                update_recent_path(previous_pos, tcod, game, &objects[PLAYER]);
+            } else {
+                game.recent_path.clear();
+                // Groups on the old level don't wake the ones here
+                game.alerted_groups.clear();
+            }
+            game.recorder.record(game.turn, game.dungeon_level, objects[PLAYER].pos());
+            if let Some(ghost) = game.ghost.as_mut() {
+                if !ghost.ended && ghost.position(game.turn).is_none() {
+                    ghost.ended = true;
+                    game.messages.add("Your ghost fades away.", GHOST_COLOR);
+                }
+            }
+        }
+
+        let recompute_fov = previous_pos != (objects[PLAYER].x, objects[PLAYER].y)
+            || previous_level != game.dungeon_level
+            || std::mem::take(&mut tcod.fov_changed);
+        if recompute_fov {
+            objects[PLAYER].clear(&mut tcod.con);
+        }
+
+        // Let monsters take their turn, more than once if the player was slowed down
+        if objects[PLAYER].is_alive
+            && player_action != PlayerAction::DidntTakeTurn
+            && previous_level == game.dungeon_level
+        {
+            update_scent(game, objects);
+            game.recent_hp.push_back(objects[PLAYER].fighter.map_or(0, |f| f.hp));
+            if game.recent_hp.len() > RECAP_TURNS {
+                game.recent_hp.pop_front();
+            }
+
+            let (player_x, player_y) = objects[PLAYER].pos();
+            for ob in objects.iter_mut() {
+                if ob.is_alive && ob.ai.is_some() && ob.faction.is_monster() {
+                    let in_view = tcod.fov.is_in_fov(ob.x, ob.y);
+                    let distance = ob.grid_distance(player_x, player_y, game.settings.movement);
+                    let noticed = ob.alert_state.notices_player(
+                        in_view,
+                        distance,
+                        game.sneaking,
+                        &mut game.rngs.combat,
+                    );
+                    if in_view && game.sneaking && ob.alert_state == AlertState::Unaware {
+                        let name = &ob.name;
+                        game.trace.record(|| format!("roll notice {} {}", name, noticed));
+                    }
+                    ob.alert_state = ob.alert_state.next(noticed);
+                    if let Some(group) = ob.group_id.filter(|_| noticed) {
+                        game.alerted_groups.push(group);
+                    }
+                }
+            }
+            broadcast_alerts(game, objects);
+
+            let mut lost_rounds = end_player_turn(&mut objects[PLAYER]);
+            // Cleared by a key press, which skips the rest of this turn's pauses
+            let mut animate = game.settings.animate_enemy_turns;
+            while player_waits(&mut objects[PLAYER], &mut lost_rounds) {
+                for ob in objects.iter_mut().filter(|ob| !ob.was_seen) {
+                    let (x, y) = ob.pos();
+                    if tcod.fov.is_in_fov(x, y) {
+                        // Monsters the player sneaks up on stay put until they notice
+                        ob.was_seen =
+                            !ob.faction.is_monster() || ob.alert_state != AlertState::Unaware;
+                        if ob.is_alive && is_in_bestiary(&ob.name) {
+                            game.bestiary.entry(ob.name.clone()).or_insert(0);
+                        }
+                    }
+                }
+                take_round(objects, |id, objects| {
+                    let previous_pos = objects[id].pos();
+                    let stamp = game.messages.stamp();
+                    // println!("{} is moving", objects[id].name);
+                    ai_take_turn(id, &tcod.fov, game, objects);
+                    let pos = objects[id].pos();
+                    tcod.dirty.push(previous_pos);
+                    tcod.dirty.push(pos);
+                    let seen = tcod.fov.is_in_fov(previous_pos.0, previous_pos.1)
+                        || tcod.fov.is_in_fov(pos.0, pos.1);
+                    if animate && seen && (pos != previous_pos || game.messages.stamp() != stamp) {
+                        render_all(tcod, objects, game, recompute_fov);
+                        tcod.root.flush();
+                        animate = !key_pressed_within(ENEMY_TURN_DELAY);
+                    }
+                });
+                let cleared = flush_pending(game, objects);
+                tcod.dirty.extend(cleared);
+                apply_terrain_effects(game, objects);
+                spring_traps(tcod, game, objects);
+                rearm_traps(game, objects);
+                tick_status_effects(game, objects);
+                tick_cooldowns(objects);
+                tick_spells(game);
+                regenerate_mana(objects, game);
+                regenerate(game, objects);
+                broadcast_alerts(game, objects);
+                if !objects[PLAYER].is_alive {
+                    break;
+                }
+            }
+            check_level_cleared(tcod, game, objects);
+        }
+
+        render_all(tcod, objects, game, recompute_fov);
+        tcod.root.flush();
+        remember_objects(tcod, game, objects);
+
+        // Once the monsters have moved and the frame is on screen, so the save
+        // catches the whole turn and doesn't hold up drawing it
+        if player_action == PlayerAction::TookTurn || previous_level != game.dungeon_level {
+            auto_save(game, objects, previous_level != game.dungeon_level);
+        }
+
+        if was_alive && !objects[PLAYER].is_alive {
+            match game.mode {
+                GameMode::Permadeath => {
+                    // Death is final, so no save this run made can be used to undo it
+                    discard_run_saves(game.run, all_save_slots());
+                    if show_death_screen(tcod, game, objects) {
+                        return true;
+                    }
+                }
+                GameMode::Practice => respawn_player(tcod, game, objects),
+            }
+        }
+        if game.won {
+            show_victory_screen(tcod, game, objects);
+            return false;
+        }
     }
+    // Left with Escape or by closing the window
+    if let Err(err) = save_if_alive(AUTO_SAVE_SLOT, game, objects) {
+        println!("Warning: couldn't save on the way out: {}", err);
+    }
+    false
 }
 
-fn render_all(tcod: &mut Tcod, objects: &[Object], game: &mut Game, recompute_fov: bool) {
-    let player = &objects[PLAYER];
-    if recompute_fov {
-        tcod.fov.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A first level with nothing read from or written to disk but the vaults
+    fn test_game() -> (Game, Vec<Object>) {
+        let mut settings = Settings::defaults();
+        settings.meta = false;
+        start_game(1, GameMode::Permadeath, settings)
     }
 
-    tcod.con.set_default_foreground(colors::WHITE);
-    for x in 0..MAP_WIDTH {
-        let ux = x as usize;
-        for y in 0..MAP_HEIGHT {
-            let uy = y as usize;
-            let visible = tcod.fov.is_in_fov(x, y);
-            let wall = !game.map[ux][uy].is_transparent;
-            let color = match (visible, wall) {
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                (true, true) => light_blend(
-                    player.x,
-                    player.y,
-                    x,
-                    y,
-                    COLOR_LIGHT_WALL,
-                    COLOR_DARK_WALL,
-                    TORCH_RADIUS as f32,
-                ),
-                (true, false) => light_blend(
-                    player.x,
-                    player.y,
-                    x,
-                    y,
-                    COLOR_LIGHT_GROUND,
-                    COLOR_DARK_GROUND,
-                    TORCH_RADIUS as f32,
-                ),
-            };
-            let explored = &mut game.map[ux][uy].explored;
-            if visible {
-                *explored = true;
-            }
-            if *explored {
-                tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
-                tcod.con.put_char(x, y, if wall { '#' } else { '.' }, BackgroundFlag::None);
-            }
+    // Solid rock but for the floor tiles given
+    fn map_with_floor(floor: &[(i32, i32)]) -> Map {
+        let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for &(x, y) in floor {
+            map[x as usize][y as usize] = Tile::empty();
         }
+        map
     }
 
-    // Draw "background" objects first
-    for object in objects {
-        if game.map[object.x as usize][object.y as usize].explored && object.is_walkable {
-            object.draw(&mut tcod.con);
+    // Every tile of a rectangle, corners included
+    fn area(x1: i32, y1: i32, x2: i32, y2: i32) -> Vec<(i32, i32)> {
+        (x1..=x2).flat_map(|x| (y1..=y2).map(move |y| (x, y))).collect()
+    }
+
+    #[test]
+    fn dijkstra_map_from_one_source() {
+        let map = map_with_floor(&area(1, 1, 5, 5));
+        let distances = dijkstra_map(&map, &[(1, 1)]);
+        assert_eq!(distances[1][1], 0);
+        assert_eq!(distances[2][2], 1);
+        // Diagonal steps count the same as straight ones
+        assert_eq!(distances[4][2], 3);
+        assert_eq!(distances[5][5], 4);
+        assert_eq!(distances[6][6], u32::MAX);
+    }
+
+    #[test]
+    fn dijkstra_map_from_several_sources() {
+        let map = map_with_floor(&area(1, 1, 5, 5));
+        let distances = dijkstra_map(&map, &[(1, 1), (5, 5)]);
+        assert_eq!(distances[1][1], 0);
+        assert_eq!(distances[5][5], 0);
+        assert_eq!(distances[5][4], 1);
+        assert_eq!(distances[3][3], 2);
+        assert_eq!(distances[1][5], 4);
+    }
+
+    #[test]
+    fn dijkstra_map_leaves_pockets_unreachable() {
+        let mut floor = area(1, 1, 5, 5);
+        floor.push((10, 10));
+        let map = map_with_floor(&floor);
+        let distances = dijkstra_map(&map, &[(1, 1)]);
+        assert_eq!(distances[10][10], u32::MAX);
+        assert_eq!(distances[0][0], u32::MAX);
+        assert_eq!(dijkstra_map(&map, &[(10, 10)])[1][1], u32::MAX);
+    }
+
+    #[test]
+    fn metrics_compared() {
+        use Metric::*;
+        // Straight along an axis they all agree
+        for metric in [Chebyshev, Manhattan, Euclidean] {
+            assert_eq!(metric.distance(0, 5), 5.0);
+            assert_eq!(metric.distance(-5, 0), 5.0);
         }
+        // On the diagonal they don't
+        assert_eq!(Chebyshev.distance(3, 3), 3.0);
+        assert_eq!(Manhattan.distance(3, 3), 6.0);
+        assert!((Euclidean.distance(3, 3) - 18f32.sqrt()).abs() < 1e-6);
+        assert_eq!(Chebyshev.distance(3, -4), 4.0);
+        assert_eq!(Manhattan.distance(3, -4), 7.0);
+        assert_eq!(Euclidean.distance(3, -4), 5.0);
+        // So (4, 4) is in a square range 5 but not a diamond or circle of 5
+        assert!(Chebyshev.distance(4, 4) <= 5.0);
+        assert!(Manhattan.distance(4, 4) > 5.0);
+        assert!(Euclidean.distance(4, 4) > 5.0);
     }
-    // Then "foreground" objects
-    for object in objects {
-        if tcod.fov.is_in_fov(object.x, object.y) && !object.is_walkable {
-            object.draw(&mut tcod.con);
+
+    #[test]
+    fn version_1_save_loads_through_migration() {
+        // Written by the game as it was when saving was added: seed 1, turn 42,
+        // 17 gold, the player down to 23 HP and a patch of the map explored
+        let text = include_str!("../tests/fixtures/save-v1.txt");
+        let (loaded, objects) = parse_save(text).unwrap();
+        assert_eq!((loaded.seed, loaded.turn, loaded.gold), (1, 42, 17));
+        assert_eq!(loaded.mode, GameMode::Permadeath);
+        assert_eq!(loaded.dungeon_level, 1);
+        assert!(objects[PLAYER].is_player());
+        assert_eq!(objects[PLAYER].fighter.unwrap().hp, 23);
+        for x in 0..MAP_WIDTH as usize {
+            for y in 0..MAP_HEIGHT as usize {
+                let patch = (10..20).contains(&x) && (5..9).contains(&y);
+                assert_eq!(loaded.map[x][y].explored, patch, "({}, {})", x, y);
+            }
         }
+        // Fields added since version 1 get their defaults
+        assert!(loaded.notes.is_empty());
+        assert!(objects.iter().all(|ob| ob.group_id.is_none() && ob.energy == 0));
+        assert!(loaded.map.iter().flatten().all(|tile| tile.light == LightLevel::Normal));
     }
 
-    blit(&tcod.con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), &mut tcod.root, (0, 0), 1.0, 1.0);
-    // show the player's stats graphically
-    tcod.panel.set_default_background(colors::BLACK);
-    tcod.panel.clear();
+    #[test]
+    fn dying_removes_every_save_of_the_run() {
+        let (mut game, objects) = test_game();
+        let (mut other, other_objects) = test_game();
+        game.run = 1;
+        other.run = 2;
+        let slots: Vec<String> =
+            (1..=4).map(|i| format!("test-run-{}-{}", std::process::id(), i)).collect();
+        // Two saves of the run, one of another game, and an empty slot
+        save_to_slot(&slots[0], &game, &objects).unwrap();
+        game.turn += 10;
+        save_to_slot(&slots[1], &game, &objects).unwrap();
+        save_to_slot(&slots[2], &other, &other_objects).unwrap();
+        let (loaded, _) = parse_save(&read_save_text(&save_path(&slots[0])).unwrap()).unwrap();
+        assert_eq!(loaded.run, 1);
+        assert_eq!(read_save_info(&slots[1]).unwrap().run, Some(1));
 
-    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
-    let max_hp = objects[PLAYER].fighter.map_or(0, |f| f.max_hp);
+        discard_run_saves(1, slots.iter().map(String::as_str));
+        let kept: Vec<bool> =
+            slots.iter().map(|slot| Path::new(&save_path(slot)).exists()).collect();
+        discard_save(&slots[2]);
+        assert_eq!(kept, [false, false, true, false]);
+    }
 
-    render_bar(
-        &mut tcod.panel,
-        1,
-        1,
-        BAR_WIDTH,
-        "HP",
-        hp,
-        max_hp,
-        colors::LIGHT_RED,
-        colors::DARKER_RED,
-    );
+    #[test]
+    fn save_errors_tell_newer_versions_from_damage() {
+        let (game, objects) = test_game();
+        let mut out = SaveWriter::new(SAVE_VERSION);
+        save_game_state(&game, &objects, &mut out);
+        let text = format!("{} {}\nstate\n{}", SAVE_MAGIC, SAVE_VERSION, out.text);
+        assert!(parse_save(&text).is_ok());
 
-    tcod.panel.set_default_background(colors::LIGHT_GREY);
-    tcod.panel.print_ex(
-        1,
-        0,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        get_names_under_mouse(tcod, objects),
-    );
+        let newer = format!("{} {}\nstate\n{}", SAVE_MAGIC, SAVE_VERSION + 1, out.text);
+        assert_eq!(parse_save(&newer).err(), Some(SaveError::Incompatible(SAVE_VERSION + 1)));
+        let cut_short = &text[..text.len() / 2];
+        assert!(matches!(parse_save(cut_short), Err(SaveError::Corrupt(_))));
+        assert!(matches!(parse_save("hello\nstate\n1 2 3"), Err(SaveError::Corrupt(_))));
+    }
 
-    let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in game.messages.iter().rev() {
-        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
-        y -= msg_height;
-        if y < 0 {
-            break;
+    #[test]
+    fn packed_saves_unpack_to_the_same_text() {
+        let (game, objects) = test_game();
+        let mut out = SaveWriter::new(SAVE_VERSION);
+        save_game_state(&game, &objects, &mut out);
+        let packed = pack(out.text.as_bytes());
+        assert!(packed.starts_with(PACKED_SAVE_MAGIC));
+        assert!(packed.len() < out.text.len() / 4);
+        assert_eq!(unpack(&packed[PACKED_SAVE_MAGIC.len()..]).unwrap(), out.text.as_bytes());
+
+        for text in ["", "a", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "abcabcabcabcabx"] {
+            let packed = pack(text.as_bytes());
+            assert_eq!(unpack(&packed[PACKED_SAVE_MAGIC.len()..]).unwrap(), text.as_bytes());
         }
-        tcod.panel.set_default_foreground(color);
-        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+        assert_eq!(unpack(&[3, b'a']), None);
+        assert_eq!(unpack(&[1, b'a', 4, 2]), None);
+        // A copy far bigger than any save, as a damaged file might ask for
+        let mut huge = vec![1, b'a'];
+        push_number(&mut huge, 1 << 60);
+        push_number(&mut huge, 1);
+        assert_eq!(unpack(&huge), None);
     }
 
-    // blit the contents of `panel` to the root console
-    blit(&tcod.panel, (0, 0), (SCREEN_WIDTH, PANEL_HEIGHT), &mut tcod.root, (0, PANEL_Y), 1.0, 1.0);
-    // show the player's stats
-    /*
-    if let Some(fighter) = objects[PLAYER].fighter {
-        tcod.root.print_ex(
-            1,
-            SCREEN_HEIGHT - 2,
-            BackgroundFlag::None,
-            TextAlignment::Left,
-            format!("HP: {}/{} ", fighter.hp, fighter.max_hp),
-        );
+    #[test]
+    fn ties_for_nearest_go_to_the_lowest_index() {
+        // Orcs three steps from the player at (10, 10), and one further off
+        let with_orcs_at = |spots: &[(i32, i32)]| {
+            let mut player = Object::new(10, 10, '@', "player", colors::WHITE);
+            player.faction = Faction::Player;
+            player.fighter = make_monster(&ORC, 0, 0).fighter;
+            let mut objects = vec![player];
+            objects.extend(spots.iter().map(|&(x, y)| make_monster(&ORC, x, y)));
+            objects
+        };
+        let objects = with_orcs_at(&[(10, 15), (13, 12), (7, 10)]);
+        assert_eq!(nearest_enemy(PLAYER, MovementMode::EightWay, &objects), Some(2));
+        assert_eq!(closest_monster(5, Metric::Chebyshev, &objects), Some(2));
+        // Whichever way round they are, the first one listed wins
+        let objects = with_orcs_at(&[(10, 15), (7, 10), (13, 12)]);
+        assert_eq!(nearest_enemy(PLAYER, MovementMode::EightWay, &objects), Some(2));
+        assert_eq!(closest_monster(5, Metric::Chebyshev, &objects), Some(2));
+        assert_eq!(objects[2].pos(), (7, 10));
     }
-    */
-}
 
-struct Tcod {
-    root: Root,
-    con: Offscreen,
-    panel: Offscreen,
-    fov: FovMap,
-    key: Key,
-    mouse: Mouse,
-}
+    #[test]
+    fn off_the_map_is_out_of_bounds_and_blocked() {
+        let map = map_with_floor(&area(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1));
+        let corners =
+            [(0, 0), (MAP_WIDTH - 1, 0), (0, MAP_HEIGHT - 1), (MAP_WIDTH - 1, MAP_HEIGHT - 1)];
+        for &(x, y) in &corners {
+            assert!(in_map(x, y), "({}, {})", x, y);
+            assert!(!is_blocked(&map, x, y, &[]), "({}, {})", x, y);
+        }
+        let outside = [
+            (-1, 0),
+            (0, -1),
+            (-1, -1),
+            (MAP_WIDTH, 0),
+            (0, MAP_HEIGHT),
+            (MAP_WIDTH, MAP_HEIGHT),
+            (i32::MIN, 5),
+            (5, i32::MAX),
+        ];
+        for &(x, y) in &outside {
+            assert!(!in_map(x, y), "({}, {})", x, y);
+            assert!(is_blocked(&map, x, y, &[]), "({}, {})", x, y);
+            assert!(is_blocked_by_object(x, y, &[]), "({}, {})", x, y);
+        }
+    }
 
-fn main() {
-    let root = Root::initializer()
-        .font("arial10x10.png", FontLayout::Tcod)
-        .font_type(FontType::Greyscale)
-        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-        .title("Rust/libtcod tutorial")
-        .init();
+    #[test]
+    fn fallback_start_room_joins_the_rest_of_the_level() {
+        let (_, mut objects) = test_game();
+        objects.truncate(1);
+        // The player is stuck in rock, with an orc waiting in the middle of
+        // the map where the fallback room goes
+        objects[PLAYER].set_pos(0, 0);
+        let room = Rect::new(
+            (MAP_WIDTH - MIN_ROOM_WIDTH) / 2,
+            (MAP_HEIGHT - MIN_ROOM_HEIGHT) / 2,
+            MIN_ROOM_WIDTH,
+            MIN_ROOM_HEIGHT,
+        );
+        let (cx, cy) = room.center();
+        objects.push(make_monster(&ORC, cx, cy));
+        let mut map = map_with_floor(&area(2, 2, 6, 6));
+        let stairs = ensure_start(&mut map, &mut objects, 1, (4, 4));
 
-    let mut tcod = Tcod {
-        root,
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
-        panel: Offscreen::new(SCREEN_WIDTH, SCREEN_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
-        key: Default::default(),
-        mouse: Default::default(),
-    };
+        let start = objects[PLAYER].pos();
+        assert!(map[start.0 as usize][start.1 as usize].is_walkable);
+        assert_ne!(start, (cx, cy));
+        assert_eq!(stairs, (4, 4));
+        assert_ne!(connectivity_map(&map, &[start])[4][4], u32::MAX);
 
-    let mut player = Object::new(0, 0, '@', "Player", colors::WHITE);
-    player.fighter = Some(Fighter {
-        max_hp: 30,
-        hp: 30,
-        defense: 2,
-        attack: 5,
-        on_death: DeathCallback::Player,
-    });
+        // With nothing open to join up with, the stairs go where the player is
+        objects[PLAYER].set_pos(0, 0);
+        let mut map = map_with_floor(&[]);
+        assert_eq!(ensure_start(&mut map, &mut objects, 1, (4, 4)), objects[PLAYER].pos());
+    }
 
-    let mut objects = vec![player];
+    #[test]
+    fn crowded_levels_still_have_a_way_down() {
+        let gen = GenConfig { monster_density: 50.0, item_density: 50.0 };
+        let vaults = load_vaults();
+        for seed in 1..4 {
+            for level in 1..=deepest_level() {
+                let (_, mut objects) = test_game();
+                objects.truncate(1);
+                let map = make_map(&mut objects, &vaults, &gen, level, seed);
+                let start = objects[PLAYER].pos();
+                assert!(map[start.0 as usize][start.1 as usize].is_walkable);
+                assert!(
+                    !objects[1..].iter().any(|ob| !ob.is_walkable && ob.pos() == start),
+                    "seed {} level {}: something is on the player",
+                    seed,
+                    level
+                );
+                let distances = connectivity_map(&map, &[start]);
+                for stairs in objects.iter().filter(|ob| ob.stairs == Some(Stairs::Down)) {
+                    let (x, y) = stairs.pos();
+                    assert_ne!(
+                        distances[x as usize][y as usize],
+                        u32::MAX,
+                        "seed {} level {}",
+                        seed,
+                        level
+                    );
+                }
+            }
+        }
+    }
 
-    let mut game =
-        Game { map: make_map(&mut objects), messages: Messages::new(), inventory: vec![] };
+    #[test]
+    fn combat_rolls_leave_the_next_level_alone() {
+        let layout = |game: &Game, objects: &[Object]| {
+            let tiles: Vec<TileKind> = game.map.iter().flatten().map(|tile| tile.kind).collect();
+            let things: Vec<(i32, i32, String)> =
+                objects.iter().map(|ob| (ob.x, ob.y, ob.name.clone())).collect();
+            (tiles, things)
+        };
+        let (mut quiet, mut quiet_objects) = test_game();
+        let (mut busy, mut busy_objects) = test_game();
+        for _ in 0..1000 {
+            busy.rngs.combat.gen::<f32>();
+            busy.rngs.loot.gen::<u32>();
+        }
+        enter_level(&mut quiet, &mut quiet_objects, 2);
+        enter_level(&mut busy, &mut busy_objects, 2);
+        assert!(layout(&quiet, &quiet_objects) == layout(&busy, &busy_objects));
+    }
 
-    // Provide items for testing
-    let mut potion = Object::new(0, 0, '!', "health potion", colors::ORANGE);
-    potion.is_walkable = true;
-    potion.item = Some(Item::Heal);
-    game.inventory.push(potion);
+    #[test]
+    fn certain_block_stops_every_blow() {
+        let (mut game, mut objects) = test_game();
+        let mut shield = make_shield(0, 0);
+        if let Some(equipment) = shield.equipment.as_mut() {
+            equipment.equipped = true;
+            equipment.block_chance = 1.0;
+        }
+        game.inventory.push(shield);
+        let (x, y) = objects[PLAYER].pos();
+        let mut orc = make_monster(&ORC, x + 1, y);
+        if let Some(fighter) = orc.fighter.as_mut() {
+            fighter.attack = 100;
+        }
+        let hp = objects[PLAYER].fighter.unwrap().hp;
+        for _ in 0..50 {
+            orc.attack(&mut objects[PLAYER], &mut game);
+        }
+        assert_eq!(objects[PLAYER].fighter.unwrap().hp, hp);
+        assert!(objects[PLAYER].is_alive);
 
-    let mut scroll = Object::new(0, 0, '#', "scroll of lightning bolt", colors::LIGHT_YELLOW);
-    scroll.is_walkable = true;
-    scroll.item = Some(Item::Lightning);
-    game.inventory.push(scroll);
+        // Without the shield the same blow gets through
+        game.inventory.clear();
+        orc.attack(&mut objects[PLAYER], &mut game);
+        assert!(objects[PLAYER].fighter.unwrap().hp < hp);
+    }
 
-    let mut scroll = Object::new(0, 0, '#', "scroll of confusion", colors::LIGHT_BLUE);
-    scroll.is_walkable = true;
-    scroll.item = Some(Item::Confusion);
-    game.inventory.push(scroll);
+    #[test]
+    fn regeneration_heals_one_hp_per_regen_rate_turns() {
+        let (game, mut objects) = test_game();
+        objects.truncate(1);
+        objects.push(make_monster(&TROLL, 1, 1));
+        objects.push(make_monster(&ORC, 2, 2));
+        let hp = |objects: &[Object], id: usize| objects[id].fighter.unwrap().hp;
+        for object in objects.iter_mut() {
+            if let Some(fighter) = object.fighter.as_mut() {
+                fighter.hp -= 3;
+            }
+        }
+        let start: Vec<i32> = (0..3).map(|id| hp(&objects, id)).collect();
 
-    for x in 0..MAP_WIDTH {
-        for y in 0..MAP_HEIGHT {
-            tcod.fov.set(
-                x,
-                y,
-                game.map[x as usize][y as usize].is_transparent,
-                game.map[x as usize][y as usize].is_walkable,
+        for turn in 1..=PLAYER_REGEN_RATE * 2 {
+            regenerate(&game, &mut objects);
+            assert_eq!(hp(&objects, PLAYER), start[PLAYER] + (turn / PLAYER_REGEN_RATE) as i32);
+            assert_eq!(
+                hp(&objects, 1),
+                min(start[1] + (turn / TROLL.regen_rate) as i32, TROLL.max_hp)
             );
+            // Orcs don't heal on their own
+            assert_eq!(hp(&objects, 2), start[2]);
         }
     }
 
-    tcod::system::set_fps(LIMIT_FPS);
+    #[test]
+    fn atomic_writes_replace_the_whole_file() {
+        let path = std::env::temp_dir().join(format!("roguelike-test-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_atomically(path, "old and rather longer text").unwrap();
+        write_atomically(path, "new").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "new");
+        assert!(!Path::new(&partial_path(path)).exists());
+        fs::remove_file(path).unwrap();
+    }
 
-    game.messages.add("Welcome to the Tombs of the Ancient Kings!", colors::RED);
-    render_all(&mut tcod, &objects, &mut game, true);
-    tcod.root.flush();
+    #[test]
+    fn explored_tiles_survive_saving_and_loading() {
+        let (mut game, mut objects) = test_game();
+        let explored = area(3, 3, 20, 9);
+        for &(x, y) in &explored {
+            game.map[x as usize][y as usize].explored = true;
+        }
+        // And on a level left behind
+        enter_level(&mut game, &mut objects, 2);
+        game.map[5][5].explored = true;
+        let mut out = SaveWriter::new(SAVE_VERSION);
+        save_game_state(&game, &objects, &mut out);
+        let text = format!("{} {}\nstate\n{}", SAVE_MAGIC, SAVE_VERSION, out.text);
 
-    while !tcod.root.window_closed() {
-        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => tcod.mouse = m,
-            Some((_, Event::Key(k))) => tcod.key = k,
-            _ => tcod.key = Default::default(),
+        let (loaded, _) = parse_save(&text).unwrap();
+        let was_explored =
+            |map: &Map| -> Vec<bool> { map.iter().flatten().map(|tile| tile.explored).collect() };
+        assert!(was_explored(&loaded.map) == was_explored(&game.map));
+        assert!(loaded.map[5][5].explored);
+        let level_1 = &loaded.levels[&1].map;
+        assert!(was_explored(level_1) == was_explored(&game.levels[&1].map));
+        for &(x, y) in &explored {
+            assert!(level_1[x as usize][y as usize].explored);
         }
-        let previous_pos = (objects[PLAYER].x, objects[PLAYER].y);
-        let player_action = handle_keys(&mut tcod, &mut objects, &mut game);
-        if player_action == PlayerAction::Exit {
-            break;
+    }
+
+    #[test]
+    fn haste_doubles_turns_and_slowness_halves_them() {
+        let (_, mut objects) = test_game();
+        objects.truncate(1);
+        for x in 1..=3 {
+            let mut orc = make_monster(&ORC, x, 1);
+            orc.was_seen = true;
+            objects.push(orc);
         }
-        let recompute_fov = previous_pos != (objects[PLAYER].x, objects[PLAYER].y);
-        if recompute_fov {
-            objects[PLAYER].clear(&mut tcod.con);
+        objects[2].set_speed_effect(StatusEffect::Hasted { turns: 100 });
+        objects[3].set_speed_effect(StatusEffect::Slowed { turns: 100 });
+        // Out of sight monsters don't get turns at all
+        objects.push(make_monster(&ORC, 4, 1));
+
+        let mut turns = [0; 5];
+        for _ in 0..20 {
+            take_round(&mut objects, |id, _| turns[id] += 1);
         }
+        assert_eq!(turns, [0, 20, 40, 10, 0]);
+    }
 
-        // Let monsters take their turn
-        if objects[PLAYER].is_alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                let ob = &mut objects[id];
-                if !ob.was_seen {
-                    let (x, y) = ob.pos();
-                    if tcod.fov.is_in_fov(x, y) {
-                        ob.was_seen = true;
-                    }
-                }
-                if ob.is_alive && ob.ai.is_some() && ob.was_seen {
-                    ob.clear(&mut tcod.con);
-                    // println!("{} is moving", ob.name);
-                    ai_take_turn(id, &mut game, &mut objects);
+    #[test]
+    fn hasted_player_acts_twice_per_monster_turn() {
+        // Monster turns while the player takes twenty actions, at each speed
+        let monster_turns = |effect: Option<StatusEffect>, lost_turns: i32| {
+            let (_, mut objects) = test_game();
+            objects.truncate(1);
+            let mut orc = make_monster(&ORC, 1, 1);
+            orc.was_seen = true;
+            objects.push(orc);
+            if let Some(effect) = effect {
+                objects[PLAYER].set_speed_effect(effect);
+            }
+            objects[PLAYER].lost_turns = lost_turns;
+            let mut turns = 0;
+            for _ in 0..20 {
+                let mut lost_rounds = end_player_turn(&mut objects[PLAYER]);
+                while player_waits(&mut objects[PLAYER], &mut lost_rounds) {
+                    take_round(&mut objects, |_, _| turns += 1);
                 }
+                assert!(objects[PLAYER].energy >= ACTION_COST);
             }
+            turns
+        };
+        assert_eq!(monster_turns(None, 0), 20);
+        assert_eq!(monster_turns(Some(StatusEffect::Hasted { turns: 100 }), 0), 10);
+        assert_eq!(monster_turns(Some(StatusEffect::Slowed { turns: 100 }), 0), 40);
+        // Lost turns are rounds the player sits out on top
+        assert_eq!(monster_turns(None, 3), 23);
+    }
+
+    #[test]
+    fn leaving_saves_a_living_player_only() {
+        let (mut game, mut objects) = test_game();
+        game.turn = 17;
+        let slot = format!("test-exit-{}", std::process::id());
+        save_if_alive(&slot, &game, &objects).unwrap();
+        let text = read_save_text(&save_path(&slot)).unwrap();
+        discard_save(&slot);
+        let (loaded, _) = parse_save(&text).unwrap();
+        assert_eq!((loaded.turn, loaded.mode), (17, GameMode::Permadeath));
+
+        objects[PLAYER].is_alive = false;
+        save_if_alive(&slot, &game, &objects).unwrap();
+        assert!(!Path::new(&save_path(&slot)).exists());
+    }
+
+    #[test]
+    fn merging_a_stale_bestiary_keeps_newer_kills() {
+        let counts = |entries: &[(&str, u32)]| -> HashMap<String, u32> {
+            entries.iter().map(|&(name, kills)| (name.to_string(), kills)).collect()
+        };
+        let mut meta = counts(&[("orc", 12), ("troll", 3), ("bat", 1)]);
+        let game = counts(&[("orc", 7), ("troll", 5), ("goblin", 2)]);
+        merge_bestiary(&mut meta, &game);
+        assert_eq!(meta, counts(&[("orc", 12), ("troll", 5), ("bat", 1), ("goblin", 2)]));
+    }
+
+    #[test]
+    fn loading_shows_what_the_saved_game_showed() {
+        let (mut game, objects) = test_game();
+        let (px, py) = objects[PLAYER].pos();
+        // Remembered from earlier, and out of sight now
+        game.map[0][0].explored = true;
+        // Saved before its turn was drawn, so what's in view isn't explored yet
+        let mut out = SaveWriter::new(SAVE_VERSION);
+        save_game_state(&game, &objects, &mut out);
+        let text = format!("{} {}\nstate\n{}", SAVE_MAGIC, SAVE_VERSION, out.text);
+        // Drawing that turn showed the view from the player, and render_tile
+        // marked all of it explored
+        let mut shown = Vision::new();
+        shown.reset(&game.map);
+        shown.compute(px, py, torch_radius(&game), &game.map);
+        let tiles = || (0..MAP_WIDTH).flat_map(|x| (0..MAP_HEIGHT).map(move |y| (x, y)));
+        for (x, y) in tiles().filter(|&(x, y)| shown.is_in_fov(x, y)) {
+            game.map[x as usize][y as usize].explored = true;
         }
+        assert!(tiles().filter(|&(x, y)| shown.is_in_fov(x, y)).count() > 9);
 
-        render_all(&mut tcod, &objects, &mut game, recompute_fov);
-        tcod.root.flush();
+        let (mut loaded, loaded_objects) = parse_save(&text).unwrap();
+        let mut fov = Vision::new();
+        restore_vision(&mut fov, &mut loaded, &loaded_objects[PLAYER]);
+        for (x, y) in tiles() {
+            assert_eq!(fov.is_in_fov(x, y), shown.is_in_fov(x, y), "({}, {})", x, y);
+            let (before, after) =
+                (&game.map[x as usize][y as usize], &loaded.map[x as usize][y as usize]);
+            assert_eq!(after.explored, before.explored, "({}, {})", x, y);
+        }
+        assert!(fov.is_in_fov(px, py));
+        assert!(loaded.map[0][0].explored && !fov.is_in_fov(0, 0));
+    }
+
+    #[test]
+    fn splash_hits_a_monster_next_to_the_impact() {
+        let (mut game, mut objects) = test_game();
+        objects.truncate(1);
+        objects[PLAYER].set_pos(1, 1);
+        objects.push(make_monster(&ORC, 11, 10));
+        objects.push(make_monster(&ORC, 13, 10));
+        let splashed = splashed_by(10, 10, &game, &objects);
+        assert_eq!(splashed, vec![1]);
+
+        let hp = objects[1].fighter.unwrap().hp;
+        splash_acid(1, &mut game, &mut objects);
+        assert_eq!(objects[1].fighter.unwrap().hp, hp - ACID_DAMAGE);
+        assert_eq!(objects[2].fighter.unwrap().hp, hp);
+    }
+
+    #[test]
+    fn wounded_monster_keeps_its_hp_between_visits() {
+        let (mut game, mut objects) = test_game();
+        enter_level(&mut game, &mut objects, 2);
+        let mut orc = make_monster(&ORC, 5, 5);
+        orc.name = "wounded orc".into();
+        orc.fighter.as_mut().unwrap().hp = 4;
+        objects.push(orc);
+        objects.push(Object::new(6, 5, '%', "remains of an orc", colors::DARK_RED));
+
+        enter_level(&mut game, &mut objects, 3);
+        assert!(objects.iter().all(|ob| ob.name != "wounded orc"));
+        enter_level(&mut game, &mut objects, 2);
+        let orc = objects.iter().find(|ob| ob.name == "wounded orc").expect("the orc is gone");
+        assert_eq!(orc.fighter.unwrap().hp, 4);
+        assert_eq!(orc.pos(), (5, 5));
+        assert!(objects.iter().any(|ob| ob.name == "remains of an orc" && ob.pos() == (6, 5)));
     }
 }